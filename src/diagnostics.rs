@@ -0,0 +1,308 @@
+use crate::grid::SpatialGrid;
+use crate::{Body, Gravity, MIN_SEPARATION_SQ};
+use crate::parallel::prelude::*;
+use glam::Vec2;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Potential energy of a pair, matching the softened force law in `calculate_forces`
+/// (before the coupling between their species, which just scales it).
+/// For the default inverse-square law the force falls off as `1 / (r^2 + eps^2)`, which
+/// integrates to an arctangent; other exponents are integrated numerically. The softening is
+/// always the round kernel's: the elliptical one of `softening_anisotropy` depends on where
+/// the pair sits, so it isn't the gradient of any potential and has none to match.
+pub fn pair_potential(m1: f32, m2: f32, dist: f32, gravity: Gravity) -> f32 {
+    let eps = gravity.softening;
+    let p = gravity.exponent;
+    if eps == 0.0 {
+        return unsoftened_potential(m1, m2, dist, gravity);
+    }
+    if p == 2.0 {
+        return -gravity.g * m1 * m2 * (std::f32::consts::FRAC_PI_2 - (dist / eps).atan()) / eps;
+    }
+
+    // Writing r = eps * sinh(w) turns the integral of the force into eps^(1-p) * cosh(w)^(1-p) dw
+    let w = (dist / eps).asinh();
+    let strength = gravity.g * m1 * m2 * eps.powf(1.0 - p);
+    if p > 1.0 {
+        // Relative to infinite separation. With y = e^(-(p-1) w) the remaining integrand is smooth:
+        // integral of cosh^(1-p) from w to infinity = 2^(p-1) / (p-1) * integral of
+        // (1 + y^(2/(p-1)))^(1-p) dy from 0 to e^(-(p-1) w)
+        let upper = (-(p - 1.0) * w).exp();
+        let integral = simpson(|y| (1.0 + y.powf(2.0 / (p - 1.0))).powf(1.0 - p), 0.0, upper);
+        -strength * 2f32.powf(p - 1.0) / (p - 1.0) * integral
+    } else {
+        // The force falls off too slowly for the energy at infinity to be finite,
+        // so measure it from zero separation instead
+        strength * simpson(|w| w.cosh().powf(1.0 - p), 0.0, w)
+    }
+}
+
+/// `pair_potential` with the softening switched off, where the force is a plain power law.
+/// Like the softened potential it vanishes at infinity for exponents above 1, and otherwise
+/// is measured from zero separation (from a separation of 1 for an exponent of exactly 1,
+/// where both ends diverge).
+fn unsoftened_potential(m1: f32, m2: f32, dist: f32, gravity: Gravity) -> f32 {
+    let p = gravity.exponent;
+    let strength = gravity.g * m1 * m2;
+    let dist = dist.max(MIN_SEPARATION_SQ.sqrt());
+    if p == 1.0 {
+        strength * dist.ln()
+    } else {
+        strength * dist.powf(1.0 - p) / (1.0 - p)
+    }
+}
+
+/// Integrate `f` over `[a, b]` with Simpson's rule
+fn simpson(f: impl Fn(f32) -> f32, a: f32, b: f32) -> f32 {
+    const INTERVALS: usize = 32;
+    let h = (b - a) / INTERVALS as f32;
+    let inner: f32 = (1..INTERVALS)
+        .map(|i| f(a + i as f32 * h) * if i % 2 == 1 { 4.0 } else { 2.0 })
+        .sum();
+    (f(a) + inner + f(b)) * h / 3.0
+}
+
+pub fn center_of_mass(bodies: &[Body]) -> Vec2 {
+    let total_mass: f32 = bodies.iter().map(|body| body.mass).sum();
+    if total_mass == 0.0 {
+        return Vec2::ZERO;
+    }
+    bodies.iter().map(|body| body.pos * body.mass).sum::<Vec2>() / total_mass
+}
+
+/// Surface density in `bins` equally wide rings around `center`, out to `radius`: bodies per
+/// unit area, or mass per unit area with `by_mass`. Central bodies are left out, since a
+/// single heavy one would swamp the innermost ring.
+pub fn radial_profile(bodies: &[Body], center: Vec2, radius: f32, bins: usize, by_mass: bool) -> Vec<f32> {
+    let mut totals = vec![0.0; bins];
+    let width = radius / bins as f32;
+    for body in bodies.iter().filter(|body| !body.central) {
+        let bin = (body.pos.distance(center) / width) as usize;
+        if let Some(total) = totals.get_mut(bin) {
+            *total += if by_mass { body.mass } else { 1.0 };
+        }
+    }
+    for (i, total) in totals.iter_mut().enumerate() {
+        let (inner, outer) = (i as f32 * width, (i + 1) as f32 * width);
+        *total /= std::f32::consts::PI * (outer * outer - inner * inner);
+    }
+    totals
+}
+
+/// Kinetic energy of `bodies[index]` plus its potential energy with every other body.
+/// Positive values mean the body is unbound from the rest of the system.
+pub fn body_energy(bodies: &[Body], index: usize, gravity: Gravity) -> f32 {
+    let body = &bodies[index];
+    let kinetic = 0.5 * body.mass * body.vel.length_squared();
+    let potential: f32 = bodies
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != index)
+        .map(|(_, other)| {
+            let coupling = gravity.coupling(body.species, other.species);
+            coupling * pair_potential(body.mass, other.mass, body.pos.distance(other.pos), gravity.between(body, other))
+        })
+        .sum();
+    kinetic + potential
+}
+
+pub fn kinetic_energy(bodies: &[Body]) -> f32 {
+    bodies.iter().map(|body| 0.5 * body.mass * body.vel.length_squared()).sum()
+}
+
+/// Kinetic plus potential energy. Since `pair_potential` is the exact integral of the force
+/// kernel (same softening, same exponent), this is conserved by the dynamics up to integrator
+/// error, apart from the speed limit and damped wall bounces, which aren't conservative.
+/// With a `softening_anisotropy` other than 1 neither are the forces, and this is only the
+/// energy of the round kernel.
+pub fn total_energy(bodies: &[Body], gravity: Gravity) -> f32 {
+    kinetic_energy(bodies) + potential_energy(bodies, gravity)
+}
+
+/// `2 KE / |PE|`, which is about 1 for a system in virial equilibrium: below 1 it's
+/// collapsing, above 1 it's expanding (or flying apart). Only meaningful when the potential
+/// vanishes at infinity, i.e. for force exponents above 1.
+pub fn virial_ratio(bodies: &[Body], gravity: Gravity) -> f32 {
+    virial(kinetic_energy(bodies), potential_energy(bodies, gravity))
+}
+
+/// The virial ratio from already computed kinetic and potential energies
+pub fn virial(kinetic: f32, potential: f32) -> f32 {
+    2.0 * kinetic / potential.abs().max(f32::MIN_POSITIVE)
+}
+
+/// Total potential energy over all pairs, plus that of every body in the tidal field
+pub fn potential_energy(bodies: &[Body], gravity: Gravity) -> f32 {
+    let pairs: f32 = (0..bodies.len())
+        .into_par_iter()
+        .map(|i| {
+            bodies[i + 1..]
+                .iter()
+                .map(|other| {
+                    let dist = bodies[i].pos.distance(other.pos);
+                    let coupling = gravity.coupling(bodies[i].species, other.species);
+                    coupling * pair_potential(bodies[i].mass, other.mass, dist, gravity.between(&bodies[i], other))
+                })
+                .sum::<f32>()
+        })
+        .sum();
+    let tidal: f32 = bodies.iter().map(|body| -0.5 * body.pos.dot(gravity.tidal_force(body.pos, body.mass))).sum();
+    pairs + tidal
+}
+
+/// Total angular momentum about the origin (positive is counter-clockwise)
+pub fn angular_momentum(bodies: &[Body]) -> f32 {
+    bodies.iter().map(|body| body.mass * body.pos.perp_dot(body.vel)).sum()
+}
+
+pub fn momentum(bodies: &[Body]) -> Vec2 {
+    bodies.iter().map(|body| body.vel * body.mass).sum()
+}
+
+/// The quantities the dynamics should conserve, at one moment of a run
+#[derive(Clone, Copy)]
+pub struct Conserved {
+    pub energy: f32,
+    pub momentum: Vec2,
+    /// Sum of every body's `|m v|`, to measure momentum changes against, since the net
+    /// momentum itself is often close to zero
+    pub momentum_scale: f32,
+    pub angular_momentum: f32,
+}
+
+impl Conserved {
+    pub fn measure(bodies: &[Body], gravity: Gravity) -> Self {
+        Conserved {
+            energy: total_energy(bodies, gravity),
+            momentum: momentum(bodies),
+            momentum_scale: bodies.iter().map(|body| body.mass * body.vel.length()).sum(),
+            angular_momentum: angular_momentum(bodies),
+        }
+    }
+}
+
+/// Writes conserved quantities to a CSV file every `stride` steps
+pub struct DiagnosticsLog {
+    writer: BufWriter<File>,
+    stride: usize,
+}
+
+impl DiagnosticsLog {
+    pub fn create(path: &Path, stride: usize) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "step,time,total_energy,kinetic,potential,angular_momentum,com_x,com_y")?;
+        Ok(DiagnosticsLog { writer, stride })
+    }
+
+    pub fn record(&mut self, step: u64, time: f32, bodies: &[Body], gravity: Gravity) -> io::Result<()> {
+        if !step.is_multiple_of(self.stride as u64) {
+            return Ok(());
+        }
+
+        let kinetic = kinetic_energy(bodies);
+        let potential = potential_energy(bodies, gravity);
+        let com = center_of_mass(bodies);
+        writeln!(
+            self.writer,
+            "{},{},{},{},{},{},{},{}",
+            step,
+            time,
+            kinetic + potential,
+            kinetic,
+            potential,
+            angular_momentum(bodies),
+            com.x,
+            com.y
+        )
+    }
+}
+
+/// Pairs farther apart than this never count as close approaches
+const APPROACH_RADIUS: f32 = 50.0;
+
+/// Two bodies at the smallest separation seen between them
+#[derive(Clone, Copy)]
+pub struct Approach {
+    pub separation: f32,
+    pub step: u64,
+    /// Body ids, lower first
+    pub ids: (u64, u64),
+}
+
+// Ordered by separation, so the heap's top is the widest of the recorded approaches
+impl Ord for Approach {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.separation.total_cmp(&other.separation)
+    }
+}
+
+impl PartialOrd for Approach {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Approach {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Approach {}
+
+/// The `count` closest approaches over a run, each pair of bodies counted once at its closest
+pub struct ClosestApproaches {
+    count: usize,
+    heap: BinaryHeap<Approach>,
+}
+
+impl ClosestApproaches {
+    pub fn new(count: usize) -> Self {
+        ClosestApproaches { count, heap: BinaryHeap::with_capacity(count + 1) }
+    }
+
+    /// Check the current separations after `step`
+    pub fn record(&mut self, step: u64, bodies: &[Body]) {
+        // Once the list is full, only pairs closer than its widest entry can make it in
+        let radius = match self.heap.peek() {
+            Some(widest) if self.heap.len() == self.count => widest.separation.max(1e-3),
+            _ => APPROACH_RADIUS,
+        };
+        let grid = SpatialGrid::new(bodies.iter().map(|body| body.pos), radius);
+        for (i, body) in bodies.iter().enumerate() {
+            for j in grid.neighbors_within(body.pos, radius).into_iter().filter(|&j| j > i) {
+                let other = &bodies[j];
+                self.offer(Approach {
+                    separation: body.pos.distance(other.pos),
+                    step,
+                    ids: (body.id.min(other.id), body.id.max(other.id)),
+                });
+            }
+        }
+    }
+
+    fn offer(&mut self, approach: Approach) {
+        if self.heap.len() == self.count && self.heap.peek().is_some_and(|widest| approach >= *widest) {
+            return;
+        }
+        if let Some(previous) = self.heap.iter().find(|previous| previous.ids == approach.ids) {
+            if previous.separation <= approach.separation {
+                return;
+            }
+            self.heap.retain(|previous| previous.ids != approach.ids);
+        }
+        self.heap.push(approach);
+        if self.heap.len() > self.count {
+            self.heap.pop();
+        }
+    }
+
+    /// The recorded approaches, closest first
+    pub fn sorted(&self) -> Vec<Approach> {
+        self.heap.clone().into_sorted_vec()
+    }
+}
@@ -0,0 +1,50 @@
+use glam::Vec2;
+use std::collections::HashMap;
+
+/// Uniform grid bucketing points by cell, for fast neighborhood queries
+pub struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<(usize, Vec2)>>,
+}
+
+impl SpatialGrid {
+    /// Build a grid over `positions`, remembering each point's index
+    pub fn new(positions: impl Iterator<Item = Vec2>, cell_size: f32) -> Self {
+        let mut grid = SpatialGrid {
+            cell_size,
+            cells: HashMap::new(),
+        };
+        for (i, pos) in positions.enumerate() {
+            grid.cells.entry(grid.cell_of(pos)).or_default().push((i, pos));
+        }
+        grid
+    }
+
+    fn cell_of(&self, pos: Vec2) -> (i32, i32) {
+        (
+            (pos.x / self.cell_size).floor() as i32,
+            (pos.y / self.cell_size).floor() as i32,
+        )
+    }
+
+    /// Indices of all points within `radius` of `pos` (including a point at `pos` itself)
+    pub fn neighbors_within(&self, pos: Vec2, radius: f32) -> Vec<usize> {
+        let reach = (radius / self.cell_size).ceil() as i32;
+        let (cx, cy) = self.cell_of(pos);
+        let radius_sq = radius * radius;
+
+        let mut found = Vec::new();
+        for y in cy - reach..=cy + reach {
+            for x in cx - reach..=cx + reach {
+                if let Some(cell) = self.cells.get(&(x, y)) {
+                    found.extend(
+                        cell.iter()
+                            .filter(|(_, other)| other.distance_squared(pos) <= radius_sq)
+                            .map(|&(i, _)| i),
+                    );
+                }
+            }
+        }
+        found
+    }
+}
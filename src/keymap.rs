@@ -0,0 +1,263 @@
+use minifb::{Key, KeyRepeat, Window};
+
+/// Something the keyboard does in the interactive mode
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Help,
+    Pause,
+    ScrubBack,
+    ScrubForward,
+    SpeedUp,
+    SlowDown,
+    GravityDown,
+    GravityUp,
+    CycleColors,
+    PrintTable,
+    PrintBinding,
+    CycleIntegrator,
+    ToggleMinimap,
+    ToggleProfile,
+    ToggleLogTime,
+    ToggleCom,
+    ToggleSnap,
+    ToggleWorldTrails,
+    ToggleToneMap,
+    ToggleStrobe,
+    ResetStrobe,
+    ToggleSoftening,
+    SaveSettings,
+    SaveSvg,
+    ThetaDown,
+    ThetaUp,
+    Explode,
+    TakeSnapshot,
+    RestoreSnapshot,
+    DropSnapshot,
+    PanLeft,
+    PanRight,
+    PanUp,
+    PanDown,
+    ResetCamera,
+    FitView,
+    MassUp,
+    MassDown,
+}
+
+/// When an action fires while its key is down
+#[derive(Clone, Copy)]
+enum Trigger {
+    /// Every frame the key is held
+    Held,
+    /// Once per press
+    Pressed,
+    /// Once per press, then at the keyboard's repeat rate
+    Repeated,
+}
+
+struct ActionInfo {
+    action: Action,
+    /// Used to bind the action in config files and with `--bind`
+    name: &'static str,
+    trigger: Trigger,
+    default_keys: &'static [Key],
+    description: &'static str,
+}
+
+/// Every action, in the order the help lists them
+const ACTIONS: &[ActionInfo] = &[
+    ActionInfo { action: Action::Help, name: "help", trigger: Trigger::Pressed, default_keys: &[Key::H], description: "Show or hide this help" },
+    ActionInfo { action: Action::Pause, name: "pause", trigger: Trigger::Pressed, default_keys: &[Key::Space], description: "Pause or resume" },
+    ActionInfo { action: Action::ScrubBack, name: "scrub-back", trigger: Trigger::Repeated, default_keys: &[Key::Comma], description: "While paused, step back through the history" },
+    ActionInfo { action: Action::ScrubForward, name: "scrub-forward", trigger: Trigger::Repeated, default_keys: &[Key::Period], description: "While paused, step forward through the history" },
+    ActionInfo { action: Action::SpeedUp, name: "speed-up", trigger: Trigger::Held, default_keys: &[Key::Equal, Key::NumPadPlus], description: "Speed up time" },
+    ActionInfo { action: Action::SlowDown, name: "slow-down", trigger: Trigger::Held, default_keys: &[Key::Minus, Key::NumPadMinus], description: "Slow down time" },
+    ActionInfo { action: Action::GravityDown, name: "gravity-down", trigger: Trigger::Held, default_keys: &[Key::Key1], description: "Weaken gravity" },
+    ActionInfo { action: Action::GravityUp, name: "gravity-up", trigger: Trigger::Held, default_keys: &[Key::Key2], description: "Strengthen gravity" },
+    ActionInfo { action: Action::CycleColors, name: "cycle-colors", trigger: Trigger::Pressed, default_keys: &[Key::C], description: "Cycle the color mode" },
+    ActionInfo { action: Action::CycleIntegrator, name: "cycle-integrator", trigger: Trigger::Pressed, default_keys: &[Key::I], description: "Cycle the integrator" },
+    ActionInfo { action: Action::ThetaDown, name: "theta-down", trigger: Trigger::Repeated, default_keys: &[Key::LeftBracket], description: "Lower the Barnes-Hut opening angle" },
+    ActionInfo { action: Action::ThetaUp, name: "theta-up", trigger: Trigger::Repeated, default_keys: &[Key::RightBracket], description: "Raise the Barnes-Hut opening angle" },
+    ActionInfo { action: Action::ToggleSoftening, name: "toggle-softening", trigger: Trigger::Pressed, default_keys: &[Key::O], description: "Softening off or on" },
+    ActionInfo { action: Action::Explode, name: "explode", trigger: Trigger::Pressed, default_keys: &[Key::E], description: "Explosion at the mouse pointer" },
+    ActionInfo { action: Action::TakeSnapshot, name: "take-snapshot", trigger: Trigger::Pressed, default_keys: &[Key::K], description: "Keep a snapshot of the bodies in memory" },
+    ActionInfo { action: Action::RestoreSnapshot, name: "restore-snapshot", trigger: Trigger::Pressed, default_keys: &[Key::R], description: "Go back to the latest snapshot" },
+    ActionInfo { action: Action::DropSnapshot, name: "drop-snapshot", trigger: Trigger::Pressed, default_keys: &[Key::Delete], description: "Drop the latest snapshot for the one before" },
+    ActionInfo { action: Action::MassUp, name: "mass-up", trigger: Trigger::Repeated, default_keys: &[Key::PageUp], description: "Make the selected body heavier" },
+    ActionInfo { action: Action::MassDown, name: "mass-down", trigger: Trigger::Repeated, default_keys: &[Key::PageDown], description: "Make the selected body lighter" },
+    ActionInfo { action: Action::ToggleSnap, name: "toggle-snap", trigger: Trigger::Pressed, default_keys: &[Key::G], description: "Snap thrown bodies to a grid" },
+    ActionInfo { action: Action::PanLeft, name: "pan-left", trigger: Trigger::Held, default_keys: &[Key::Left], description: "Pan left" },
+    ActionInfo { action: Action::PanRight, name: "pan-right", trigger: Trigger::Held, default_keys: &[Key::Right], description: "Pan right" },
+    ActionInfo { action: Action::PanUp, name: "pan-up", trigger: Trigger::Held, default_keys: &[Key::Up], description: "Pan up" },
+    ActionInfo { action: Action::PanDown, name: "pan-down", trigger: Trigger::Held, default_keys: &[Key::Down], description: "Pan down" },
+    ActionInfo { action: Action::ResetCamera, name: "reset-camera", trigger: Trigger::Pressed, default_keys: &[Key::Home], description: "Reset the camera" },
+    ActionInfo { action: Action::FitView, name: "fit-view", trigger: Trigger::Pressed, default_keys: &[Key::F], description: "Fit the bodies into view" },
+    ActionInfo { action: Action::ToggleMinimap, name: "toggle-minimap", trigger: Trigger::Pressed, default_keys: &[Key::M], description: "Show or hide the minimap" },
+    ActionInfo { action: Action::ToggleProfile, name: "toggle-density-profile", trigger: Trigger::Pressed, default_keys: &[Key::D], description: "Show or hide the radial density profile" },
+    ActionInfo { action: Action::ToggleLogTime, name: "toggle-log-time", trigger: Trigger::Pressed, default_keys: &[Key::L], description: "Show the elapsed time on a linear or log scale" },
+    ActionInfo { action: Action::ToggleCom, name: "toggle-com", trigger: Trigger::Pressed, default_keys: &[Key::X], description: "Show or hide the center of mass" },
+    ActionInfo { action: Action::ToggleWorldTrails, name: "toggle-world-trails", trigger: Trigger::Pressed, default_keys: &[Key::W], description: "Pin trails to the world or the screen" },
+    ActionInfo { action: Action::ToggleToneMap, name: "toggle-tone-map", trigger: Trigger::Pressed, default_keys: &[Key::B], description: "Tone mapping on or off" },
+    ActionInfo { action: Action::ToggleStrobe, name: "toggle-strobe", trigger: Trigger::Pressed, default_keys: &[Key::A], description: "Stroboscopic view on or off" },
+    ActionInfo { action: Action::ResetStrobe, name: "reset-strobe", trigger: Trigger::Pressed, default_keys: &[Key::Z], description: "Start the stroboscopic view over" },
+    ActionInfo { action: Action::PrintTable, name: "print-table", trigger: Trigger::Pressed, default_keys: &[Key::T], description: "Print the heaviest bodies" },
+    ActionInfo { action: Action::PrintBinding, name: "print-binding", trigger: Trigger::Pressed, default_keys: &[Key::U], description: "Print the most and least bound bodies" },
+    ActionInfo { action: Action::SaveSettings, name: "save-settings", trigger: Trigger::Pressed, default_keys: &[Key::P], description: "Save the settings to a TOML file" },
+    ActionInfo { action: Action::SaveSvg, name: "save-svg", trigger: Trigger::Pressed, default_keys: &[Key::S], description: "Save the view as SVG" },
+];
+
+/// Keys that can't be bound, since the console and quitting rely on them
+const RESERVED_KEYS: &[Key] = &[Key::Escape, Key::Backquote, Key::Enter, Key::Backspace];
+
+/// Every key that can be bound, by its `minifb` name
+const KEYS: &[Key] = &[
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+    Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12,
+    Key::Down, Key::Left, Key::Right, Key::Up, Key::Apostrophe, Key::Backslash, Key::Comma, Key::Equal,
+    Key::LeftBracket, Key::Minus, Key::Period, Key::RightBracket, Key::Semicolon, Key::Slash, Key::Delete,
+    Key::End, Key::Home, Key::Insert, Key::PageDown, Key::PageUp, Key::Space, Key::Tab, Key::NumPad0,
+    Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4, Key::NumPad5, Key::NumPad6, Key::NumPad7,
+    Key::NumPad8, Key::NumPad9, Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk, Key::NumPadMinus,
+    Key::NumPadPlus, Key::NumPadEnter,
+];
+
+/// The keys bound to each action, indexed like `ACTIONS`
+#[derive(Clone)]
+pub struct Keymap {
+    keys: Vec<Vec<Key>>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap { keys: ACTIONS.iter().map(|info| info.default_keys.to_vec()).collect() }
+    }
+}
+
+impl Keymap {
+    /// Rebind from an `ACTION=KEY[,KEY...]` binding, replacing the action's default keys
+    pub fn bind(&mut self, binding: &str) -> Result<(), String> {
+        let (name, keys) = binding.split_once('=').ok_or("--bind expects ACTION=KEY[,KEY...]")?;
+        let index = ACTIONS.iter().position(|info| info.name == name.trim()).ok_or_else(|| {
+            let names: Vec<&str> = ACTIONS.iter().map(|info| info.name).collect();
+            format!("unknown action '{}' (expected one of {})", name.trim(), names.join(", "))
+        })?;
+        // Nothing after the = leaves the action unbound
+        self.keys[index] = if keys.trim().is_empty() {
+            Vec::new()
+        } else {
+            keys.split(',').map(|key| parse_key(key.trim())).collect::<Result<_, _>>()?
+        };
+        Ok(())
+    }
+
+    /// Check that no key does two things at once
+    pub fn check(&self) -> Result<(), String> {
+        for (i, keys) in self.keys.iter().enumerate() {
+            for key in keys {
+                if let Some(j) = self.keys[i + 1..].iter().position(|other| other.contains(key)) {
+                    return Err(format!("{:?} is bound to both {} and {}", key, ACTIONS[i].name, ACTIONS[i + 1 + j].name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The keys bound to `action`, for showing next to what it controls
+    pub fn label(&self, action: Action) -> String {
+        let (_, keys) = self.lookup(action);
+        if keys.is_empty() {
+            return "unbound".to_string();
+        }
+        keys.iter().map(|key| format!("{:?}", key)).collect::<Vec<_>>().join("/")
+    }
+
+    /// One line per action: the keys bound to it, its name to rebind it by, and what it does
+    pub fn help(&self) -> Vec<String> {
+        let mut lines: Vec<String> = ACTIONS
+            .iter()
+            .map(|info| format!("{:<20} {:<20} {}", self.label(info.action), info.name, info.description))
+            .collect();
+        lines.push(format!("{:<20} {:<20} {}", "Backquote", "", "Open the command console"));
+        lines.push(format!("{:<20} {:<20} {}", "Escape", "", "Exit"));
+        lines
+    }
+
+    /// Bindings that differ from the defaults, as `ACTION = [KEY, ...]`
+    pub fn changed(&self) -> Vec<(&'static str, Vec<String>)> {
+        ACTIONS
+            .iter()
+            .zip(&self.keys)
+            .filter(|(info, keys)| info.default_keys != keys.as_slice())
+            .map(|(info, keys)| (info.name, keys.iter().map(|key| format!("{:?}", key)).collect()))
+            .collect()
+    }
+
+    fn lookup(&self, action: Action) -> (&ActionInfo, &[Key]) {
+        let index = ACTIONS.iter().position(|info| info.action == action).expect("every action is listed");
+        (&ACTIONS[index], &self.keys[index])
+    }
+}
+
+/// What the keyboard asks for in one frame, read once at its start so every control sees the
+/// same input, with the right edge or level semantics for each action
+pub struct InputState {
+    /// Pressed (or repeating) actions, to run once this frame
+    triggered: Vec<Action>,
+    /// Actions whose keys are held down
+    held: Vec<Action>,
+    /// Time since the previous frame's input, which held actions act over
+    seconds: f32,
+}
+
+impl InputState {
+    /// Read the keys bound in `keymap`, `seconds` after the last read
+    pub fn read(window: &Window, keymap: &Keymap, seconds: f32) -> Self {
+        let mut input = InputState::none();
+        input.seconds = seconds;
+        for (info, keys) in ACTIONS.iter().zip(&keymap.keys) {
+            let down = |key: &Key| match info.trigger {
+                Trigger::Held => window.is_key_down(*key),
+                Trigger::Pressed => window.is_key_pressed(*key, KeyRepeat::No),
+                Trigger::Repeated => window.is_key_pressed(*key, KeyRepeat::Yes),
+            };
+            if keys.iter().any(down) {
+                match info.trigger {
+                    Trigger::Held => input.held.push(info.action),
+                    Trigger::Pressed | Trigger::Repeated => input.triggered.push(info.action),
+                }
+            }
+        }
+        input
+    }
+
+    /// No input at all, for when something else (like the console) has the keyboard
+    pub fn none() -> Self {
+        InputState { triggered: Vec::new(), held: Vec::new(), seconds: 0.0 }
+    }
+
+    /// Whether a pressed or repeating action fires this frame
+    pub fn triggered(&self, action: Action) -> bool {
+        self.triggered.contains(&action)
+    }
+
+    /// How long a held action was held for this frame, in seconds (0 if it wasn't), so that
+    /// what it does doesn't depend on the frame rate
+    pub fn held(&self, action: Action) -> f32 {
+        if self.held.contains(&action) {
+            self.seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+fn parse_key(name: &str) -> Result<Key, String> {
+    if let Some(key) = RESERVED_KEYS.iter().find(|key| format!("{:?}", key) == name) {
+        return Err(format!("{:?} is reserved for the console and quitting", key));
+    }
+    KEYS.iter()
+        .copied()
+        .find(|key| format!("{:?}", key) == name)
+        .ok_or(format!("unknown key '{}' (use minifb key names, like A, Key1, Space or PageUp)", name))
+}
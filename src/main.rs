@@ -1,8 +1,31 @@
-use glam::Vec2;
-use minifb::{Key, Window, WindowOptions};
-use rand::Rng;
-use rayon::prelude::*;
-use std::time::Instant;
+mod diagnostics;
+mod font;
+mod grid;
+mod keymap;
+mod parallel;
+mod particles;
+mod picture;
+mod quadtree;
+mod settings;
+
+use glam::{Vec2, Vec3};
+use grid::SpatialGrid;
+use keymap::{Action, InputState, Keymap};
+use diagnostics::Conserved;
+use quadtree::{Interactions, QuadTree, TreeLimits, TreeStats};
+use minifb::{Key, KeyRepeat, MouseButton, MouseMode, Window, WindowOptions};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use parallel::prelude::*;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::ops::Add;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 // Base simulation constants
 const BASE_G: f32 = 100.0;        // base gravitational constant
@@ -13,236 +36,5409 @@ const WIDTH: usize = 3840;        // 4K resolution
 const HEIGHT: usize = 2160;
 const MIN_MASS: f32 = 1.0;
 const MAX_MASS: f32 = 80.0;
-const MAX_VELOCITY: f32 = 800.0;
+const MAX_VELOCITY: f32 = 800.0; // default speed limit
 const SPACE_SCALE: f32 = 1.0;
 const CENTRAL_MASS: f32 = 2000.0;
+const FOCUSING_SPEED: f32 = 300.0; // speed of the stream in the focusing scenario
+const FRAME_SMOOTHING: f32 = 0.1; // weight of the newest frame in the smoothed frame time
+const MAX_FRAME_LAG: f32 = 0.1;   // seconds the smoothed clock may drift from real time
+const RING_SPEED_RANGE: (f32, f32) = (0.98, 1.02);  // ring orbits are kept near-circular
+const RING_ECCENTRICITY_RANGE: (f32, f32) = (-0.02, 0.02);
+const MIN_SEPARATION_SQ: f32 = 1e-12; // pairs closer than this have no defined direction
+const ACCELERATION_DECADES: f32 = 3.0; // orders of magnitude below the largest acceleration the color scale spans
+const SPAWN_ATTEMPTS: usize = 50;  // tries to find a position clear of other bodies before giving up
+const GALAXY_DISK: (f32, f32) = (50.0, 250.0); // inner and outer radius of each disk in the collision scenario
+const IMAGE_WIDTH: f32 = 1600.0;  // default width the image scenario's picture is stretched to
+const TABLE_LIMIT: usize = 50;       // max rows printed by the body table dump
+const BINDING_LIMIT: usize = 5;      // bodies printed at each end of the binding energy table
+const TEXT_SCALE: usize = 4;        // screen pixels per font pixel in the overlay
+const OVERLAY_COLOR: u32 = 0xC0C0C0;
+const THROW_MASS: f32 = 10.0;       // mass of bodies thrown in with the mouse
+const THROW_SPEED_SCALE: f32 = 0.25; // thrown speed per screen pixel dragged
+const SELECT_RADIUS: f32 = 20.0;    // screen pixels around a body that still select it
+const PAN_SPEED: f32 = 1200.0;      // screen pixels per second the camera moves while a pan key is held
+const ADJUST_RATE: f32 = 3.0;       // factor per second the speed and gravity change by while their key is held
+const MAX_INPUT_STEP: f32 = 0.1;    // longest frame held keys act over, so a stalled frame doesn't jump
+const ZOOM_STEP: f32 = 1.1;         // zoom factor per scroll wheel notch
+const MIN_ZOOM: f32 = 0.05;
+const MAX_ZOOM: f32 = 100.0;
+const MAX_THETA: f32 = 2.0;
+const GRID_COLOR: u32 = 0x203040;
+const MIN_GRID_SPACING: f32 = 8.0;  // screen pixels below which the snap grid isn't drawn
+const THETA_STEP: f32 = 0.05;       // change in the Barnes-Hut opening angle per key press
+const FRAME_MARGIN: f32 = 0.05;     // fraction of the screen left free on each side when framing the bodies
+const MIN_FRAME_SIZE: f32 = 50.0;   // smallest area (in world units) framing zooms in on
+const MASS_STEP: f32 = 1.1;         // mass factor per Page Up/Down press on the selected body
+const MAX_SNAPSHOTS: usize = 8;     // in-memory snapshots kept before the oldest is dropped
+const MAX_SPAWN: usize = 10_000;     // most bodies a single console spawn command adds
+const MINIMAP_MARGIN: usize = 40;   // screen pixels between the minimap and the edges
+const MAX_MINIMAP_WIDTH: usize = (HEIGHT - 2 * MINIMAP_MARGIN) * WIDTH / HEIGHT; // widest minimap whose height still fits between the margins
+const MAX_PROFILE_BINS: usize = 200; // most rings the density profile can be binned into
+const PROFILE_DECADES: f32 = 4.0;   // orders of magnitude below the densest ring the profile plot spans
+const PERIOD_PASSAGES: usize = 6;   // pericenter passages the orbital period is averaged over
+const PERIOD_TOLERANCE: f32 = 0.1;  // largest relative spread of the times between passages for an orbit to count as periodic
+const MAX_SUPERSAMPLE: usize = 4;   // largest supersampling factor for the final screenshot (divides the screen size)
+const SVG_VELOCITY_TIME: f32 = 0.1; // velocity lines in SVG exports show the distance covered in this time
+const BLOOM_DOWNSCALE: usize = 4;   // the bloom is blurred at this fraction of the screen resolution
+const BLOOM_RADIUS: usize = 6;      // box blur radius of the bloom, in downscaled pixels
 
-#[derive(Clone)]
-struct Body {
-    pos: Vec2,
-    vel: Vec2,
-    mass: f32,
+static NEXT_BODY_ID: AtomicU64 = AtomicU64::new(0);
+
+/// How bodies behave when they hit the edge of the simulation area
+#[derive(Clone, Copy, PartialEq)]
+enum WallBounce {
+    /// Keep half of the perpendicular velocity and snap to the wall
+    Damped,
+    /// Perfect reflection: keep the full speed and mirror the overshoot back inside
+    Elastic,
+}
+
+/// How runaway bodies are kept in check during close encounters. This is purely a stabilizer
+/// for the integration: any active limit changes the dynamics, so energy and momentum are no
+/// longer conserved whenever it kicks in.
+#[derive(Clone, Copy, PartialEq)]
+enum SpeedLimit {
+    /// Leave the dynamics alone, relying on softening (or the walls) to keep things bounded
+    Off,
+    /// Rescale velocities above this speed, which abruptly throws away momentum
+    Velocity(f32),
+    /// Cap the acceleration instead, so velocities never jump but change at most this fast
+    Acceleration(f32),
+}
+
+impl SpeedLimit {
+    /// Acceleration of a body of `mass` under `force`, after the limit
+    fn acceleration(self, force: Vec2, mass: f32) -> Vec2 {
+        match self {
+            SpeedLimit::Off | SpeedLimit::Velocity(_) => force / mass,
+            SpeedLimit::Acceleration(max) => (force / mass).clamp_length_max(max),
+        }
+    }
+
+    /// Velocity after the limit
+    fn velocity(self, vel: Vec2) -> Vec2 {
+        match self {
+            SpeedLimit::Velocity(max) => vel.clamp_length_max(max),
+            SpeedLimit::Off | SpeedLimit::Acceleration(_) => vel,
+        }
+    }
+}
+
+/// Layer along the walls where velocities are damped, soaking up the energy of bodies that
+/// drift out there instead of leaving the walls to bounce them back in
+#[derive(Clone, Copy)]
+struct Sponge {
+    /// Thickness of the layer (0 disables it)
+    width: f32,
+    /// Damping rate per unit time right at the wall; it ramps up from 0 across the layer
+    strength: f32,
+}
+
+impl Sponge {
+    /// Factor to scale a velocity at `pos` by over a step of `dt`
+    fn damping(self, pos: Vec2, dt: f32) -> f32 {
+        if self.width <= 0.0 {
+            return 1.0;
+        }
+        let bounds = Vec2::new(WIDTH as f32, HEIGHT as f32) / 2.0 * SPACE_SCALE;
+        let depth = ((pos.abs() - (bounds - self.width)) / self.width).max_element().clamp(0.0, 1.0);
+        // Quadratic ramp, so bodies entering the layer don't feel an abrupt edge
+        (-self.strength * depth * depth * dt).exp()
+    }
+}
+
+/// A second population of satellites, e.g. gas next to stars, with its own masses and color
+#[derive(Clone, Copy)]
+struct Species {
+    /// Share of the generated satellites that belong to it (0 disables it)
+    fraction: f64,
+    mass_range: (f32, f32),
     color: u32,
 }
 
-impl Body {
-    fn new(pos: Vec2, vel: Vec2, mass: f32) -> Self {
-        // Color based on mass (blue for small, red for large)
-        let t = (mass - MIN_MASS) / (MAX_MASS - MIN_MASS);
-        let r = (t * 255.0) as u32;
-        let g = ((1.0 - t * t) * 200.0) as u32;
-        let b = ((1.0 - t) * 255.0) as u32;
-        let color = (r << 16) | (g << 8) | b;
-        
-        Body { pos, vel, mass, color }
+impl Species {
+    /// The species color, dimmer for the lighter bodies in its mass range
+    fn shade(&self, mass: f32) -> u32 {
+        let (min, max) = self.mass_range;
+        let brightness = 0.5 + 0.5 * ((mass - min) / (max - min).max(f32::MIN_POSITIVE)).clamp(0.0, 1.0);
+        let channel = |shift: u32| ((((self.color >> shift) & 0xFF) as f32 * brightness) as u32) << shift;
+        channel(16) | channel(8) | channel(0)
     }
+}
 
-    fn central() -> Self {
-        Body::new(
-            Vec2::ZERO,
-            Vec2::ZERO,
-            CENTRAL_MASS,
-        )
+/// Scheme used to advance the bodies by one timestep
+#[derive(Clone, Copy, PartialEq)]
+enum Integrator {
+    /// Semi-implicit Euler: one force evaluation per step
+    Euler,
+    /// Velocity Verlet: 2nd order and symplectic, so energy errors stay bounded
+    Verlet,
+    /// 4th-order Hermite predictor-corrector using acceleration and jerk
+    Hermite,
+}
+
+impl Integrator {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "euler" => Some(Integrator::Euler),
+            "verlet" => Some(Integrator::Verlet),
+            "hermite" => Some(Integrator::Hermite),
+            _ => None,
+        }
     }
 
-    fn random(g: f32) -> Self {
-        let mut rng = rand::thread_rng();
-        
-        // Create denser orbital shells
-        let shell = rng.gen_range(0..6);
-        let base_distance = 150.0 * (shell + 1) as f32;
-        let distance = base_distance + rng.gen_range(-30.0..30.0);
-        
-        let angle = rng.gen_range(0.0..std::f32::consts::TAU);
-        let pos = Vec2::new(
-            distance * angle.cos(),
-            distance * angle.sin(),
-        );
-        
-        // Calculate orbital velocity with some randomized eccentricity
-        let orbit_speed = (g * CENTRAL_MASS / distance).sqrt() * rng.gen_range(0.7..1.4);
-        let tangent = Vec2::new(-pos.y, pos.x).normalize();
-        let outward = pos.normalize();
-        let vel = (tangent + outward * rng.gen_range(-0.2..0.2)) * orbit_speed;
+    fn next(self) -> Self {
+        match self {
+            Integrator::Euler => Integrator::Verlet,
+            Integrator::Verlet => Integrator::Hermite,
+            Integrator::Hermite => Integrator::Euler,
+        }
+    }
 
-        Body::new(
-            pos,
-            vel,
-            rng.gen_range(MIN_MASS..MAX_MASS),
-        )
+    fn name(self) -> &'static str {
+        match self {
+            Integrator::Euler => "euler",
+            Integrator::Verlet => "verlet",
+            Integrator::Hermite => "hermite",
+        }
     }
+}
 
-    fn update(&mut self, force: Vec2, dt: f32) {
-        let acc = force / self.mass;
-        self.vel += acc * dt;
-        
-        // Dampen velocity if it exceeds maximum
-        if self.vel.length() > MAX_VELOCITY {
-            self.vel = self.vel.normalize() * MAX_VELOCITY;
+/// Initial arrangement of the bodies
+#[derive(Clone, Copy, PartialEq)]
+enum InitialCondition {
+    /// Satellites on randomized orbits in shells around the central mass
+    Shells,
+    /// A stream of light bodies flying past the central mass on unbound paths
+    Focusing,
+    /// Near-circular orbits within an annulus, optionally with a cleared gap
+    Ring {
+        inner: f32,
+        outer: f32,
+        gap_center: f32,
+        gap_width: f32,
+    },
+    /// Only the central mass, for throwing in satellites by hand
+    Sandbox,
+    /// Two disk galaxies, each a central mass with a ring of light bodies, approaching each
+    /// other along x from `separation` apart at a relative `speed`, offset in y by the
+    /// `impact_parameter`
+    Collision {
+        separation: f32,
+        speed: f32,
+        impact_parameter: f32,
+    },
+    /// Bodies scattered over the `--image` picture, more of them where it's brighter
+    Image,
+}
+
+impl InitialCondition {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "shells" => Some(InitialCondition::Shells),
+            "focusing" => Some(InitialCondition::Focusing),
+            "ring" => Some(InitialCondition::Ring {
+                inner: 300.0,
+                outer: 900.0,
+                gap_center: 600.0,
+                gap_width: 80.0,
+            }),
+            "sandbox" => Some(InitialCondition::Sandbox),
+            "collision" => Some(InitialCondition::Collision {
+                separation: 1000.0,
+                speed: 40.0,
+                impact_parameter: 250.0,
+            }),
+            _ => None,
         }
-        
-        self.pos += self.vel * dt;
+    }
 
-        // Bounce off walls
-        let bounds_x = (WIDTH as f32 / 2.0) * SPACE_SCALE;
-        let bounds_y = (HEIGHT as f32 / 2.0) * SPACE_SCALE;
-        
-        if self.pos.x.abs() > bounds_x {
-            self.vel.x *= -0.5;
-            self.pos.x = self.pos.x.signum() * bounds_x;
+    /// Parse `INNER,OUTER[,GAP_CENTER,GAP_WIDTH]` into a ring
+    fn parse_ring(value: &str) -> Result<Self, String> {
+        let usage = "--ring expects INNER,OUTER[,GAP_CENTER,GAP_WIDTH]";
+        let numbers = value
+            .split(',')
+            .map(|part| part.trim().parse::<f32>().map_err(|_| usage.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (inner, outer, gap_center, gap_width) = match numbers[..] {
+            [inner, outer] => (inner, outer, 0.0, 0.0),
+            [inner, outer, gap_center, gap_width] => (inner, outer, gap_center, gap_width),
+            _ => return Err(usage.to_string()),
+        };
+
+        if inner <= 0.0 || outer <= inner {
+            return Err("--ring needs 0 < INNER < OUTER".to_string());
         }
-        if self.pos.y.abs() > bounds_y {
-            self.vel.y *= -0.5;
-            self.pos.y = self.pos.y.signum() * bounds_y;
+        if gap_width < 0.0 {
+            return Err("--ring gap width can't be negative".to_string());
         }
+        if gap_center - gap_width / 2.0 <= inner && gap_center + gap_width / 2.0 >= outer {
+            return Err("--ring gap covers the whole annulus".to_string());
+        }
+        Ok(InitialCondition::Ring { inner, outer, gap_center, gap_width })
     }
 
-    fn radius(&self) -> f32 {
-        if self.mass == CENTRAL_MASS {
-            25.0 // Fixed size for central body
-        } else {
-            (self.mass / MIN_MASS).sqrt() * 3.0
+    /// Parse `SEPARATION,SPEED,IMPACT_PARAMETER` into a galaxy collision
+    fn parse_collision(value: &str) -> Result<Self, String> {
+        let usage = "--collision expects SEPARATION,SPEED,IMPACT_PARAMETER";
+        let numbers = value
+            .split(',')
+            .map(|part| part.trim().parse::<f32>().map_err(|_| usage.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [separation, speed, impact_parameter] = numbers[..] else {
+            return Err(usage.to_string());
+        };
+
+        if separation <= 0.0 {
+            return Err("--collision separation must be positive".to_string());
         }
+        Ok(InitialCondition::Collision { separation, speed, impact_parameter })
     }
 }
 
-fn calculate_forces(bodies: &[Body], g: f32) -> Vec<Vec2> {
-    bodies
-        .par_iter()
-        .map(|body1| {
-            let mut force = Vec2::ZERO;
+/// How the masses of generated satellites are spread over `MIN_MASS..MAX_MASS`
+#[derive(Clone, Copy, PartialEq)]
+enum MassDistribution {
+    /// Every mass equally likely
+    Uniform,
+    /// Density falling off as `m^-slope`, like a stellar initial mass function: many light
+    /// bodies and a few heavy ones
+    PowerLaw { slope: f32 },
+    /// Uniform in the logarithm of the mass, so each doubling is equally common
+    LogUniform,
+}
 
-            for body2 in bodies {
-                if std::ptr::eq(body1, body2) {
-                    continue;
-                }
+/// Slope of Salpeter's initial mass function
+const SALPETER_SLOPE: f32 = 2.35;
 
-                let r = body2.pos - body1.pos;
-                let dist_sq = r.length_squared() + SOFTENING * SOFTENING;
-                force += g * body1.mass * body2.mass * r.normalize() / dist_sq;
+impl MassDistribution {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "uniform" => Some(MassDistribution::Uniform),
+            "power-law" => Some(MassDistribution::PowerLaw { slope: SALPETER_SLOPE }),
+            "log-uniform" => Some(MassDistribution::LogUniform),
+            _ => None,
+        }
+    }
+
+    /// Draw a mass in `min..max` by inverting the distribution's cumulative distribution
+    fn sample(self, rng: &mut impl Rng, min: f32, max: f32) -> f32 {
+        let u: f32 = rng.gen_range(0.0..1.0);
+        let mass = match self {
+            MassDistribution::Uniform => min + u * (max - min),
+            MassDistribution::LogUniform => min * (max / min).powf(u),
+            // At a slope of 1 the power law's integral becomes a logarithm: the log-uniform distribution
+            MassDistribution::PowerLaw { slope } if (slope - 1.0).abs() < 1e-4 => min * (max / min).powf(u),
+            MassDistribution::PowerLaw { slope } => {
+                let exponent = 1.0 - slope;
+                let (low, high) = (min.powf(exponent), max.powf(exponent));
+                (low + u * (high - low)).powf(1.0 / exponent)
             }
+        };
+        mass.clamp(min, max)
+    }
+}
 
-            force
-        })
-        .collect()
+/// What the satellites' colors represent
+#[derive(Clone, Copy, PartialEq)]
+enum ColorMode {
+    /// Blue for light bodies, red for heavy ones
+    Mass,
+    /// Number of neighbors within `density_radius`, highlighting cores and filaments
+    Density,
+    /// Mass colors brightened where the density is above the mean and dimmed below it, to make
+    /// spiral arms and other waves in a disk stand out
+    DensityWave,
+    /// A fixed hue per body id, for following individual bodies around
+    Identity,
+    /// Acceleration in the latest step on a log scale, to spot bodies about to be flung off
+    Acceleration,
 }
 
-fn draw_circle(buffer: &mut Vec<u32>, center: Vec2, radius: f32, color: u32, is_central: bool) {
-    let x_center = (center.x / SPACE_SCALE + WIDTH as f32/2.0) as i32;
-    let y_center = (center.y / SPACE_SCALE + HEIGHT as f32/2.0) as i32;
-    let r = radius as i32;
+impl ColorMode {
+    fn next(self) -> Self {
+        match self {
+            ColorMode::Mass => ColorMode::Density,
+            ColorMode::Density => ColorMode::DensityWave,
+            ColorMode::DensityWave => ColorMode::Identity,
+            ColorMode::Identity => ColorMode::Acceleration,
+            ColorMode::Acceleration => ColorMode::Mass,
+        }
+    }
 
-    // Add glow effect for central body
-    let glow_radius = if is_central { r * 2 } else { r + 2 };
-    let r_squared = r * r;
-    let glow_squared = glow_radius * glow_radius;
+    fn name(self) -> &'static str {
+        match self {
+            ColorMode::Mass => "mass",
+            ColorMode::Density => "density",
+            ColorMode::DensityWave => "density wave",
+            ColorMode::Identity => "identity",
+            ColorMode::Acceleration => "acceleration",
+        }
+    }
+}
 
-    for y in -glow_radius..=glow_radius {
-        let y_offset = (y_center + y) as usize * WIDTH;
-        let y_sq = y * y;
-        
-        for x in -glow_radius..=glow_radius {
-            let dist_sq = x*x + y_sq;
-            if dist_sq <= glow_squared {
-                let px = x_center + x;
-                
-                if px >= 0 && px < WIDTH as i32 && (y_center + y) >= 0 && (y_center + y) < HEIGHT as i32 {
-                    let idx = y_offset + px as usize;
-                    if dist_sq <= r_squared {
-                        buffer[idx] = color;
-                    } else {
-                        // Glow effect
-                        let glow_intensity = 1.0 - (dist_sq as f32 / glow_squared as f32);
-                        let r = ((color >> 16) & 0xFF) as f32 * glow_intensity;
-                        let g = ((color >> 8) & 0xFF) as f32 * glow_intensity;
-                        let b = (color & 0xFF) as f32 * glow_intensity;
-                        buffer[idx] = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
-                    }
-                }
-            }
+/// How the simulation's length, mass and time units are presented to the user.
+/// The physics always runs in simulation units; this only converts what gets shown.
+#[derive(Clone, Copy)]
+struct Units {
+    /// Size of one simulation unit of length, mass and time in the displayed units
+    length: f32,
+    mass: f32,
+    time: f32,
+    length_label: &'static str,
+    mass_label: &'static str,
+    time_label: &'static str,
+    physical: bool,
+}
+
+impl Units {
+    /// Raw simulation units: one length unit is one pixel at the default scale
+    fn simulation() -> Self {
+        Units {
+            length: 1.0,
+            mass: 1.0,
+            time: 1.0,
+            length_label: "units",
+            mass_label: "mass units",
+            time_label: "time units",
+            physical: false,
+        }
+    }
+
+    /// AU, solar masses and years. One length unit is 0.01 AU and the central body is one
+    /// solar mass; the time unit (about 26 days) follows from requiring that `BASE_G`
+    /// equals the real G = 4 pi^2 AU^3 / (Msun yr^2).
+    fn physical() -> Self {
+        let length = 0.01;
+        let mass = 1.0 / CENTRAL_MASS;
+        let g_physical = 4.0 * std::f32::consts::PI * std::f32::consts::PI;
+        Units {
+            length,
+            mass,
+            time: (BASE_G * length.powi(3) / (g_physical * mass)).sqrt(),
+            length_label: "AU",
+            mass_label: "Msun",
+            time_label: "yr",
+            physical: true,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "simulation" => Some(Units::simulation()),
+            "physical" => Some(Units::physical()),
+            _ => None,
+        }
+    }
+
+    /// The same units with time shown in `label`, `per_unit` of which make one of the current time unit
+    fn with_time_unit(self, label: &'static str, per_unit: f32) -> Self {
+        Units { time: self.time * per_unit, time_label: label, ..self }
+    }
+
+    fn velocity(&self) -> f32 {
+        self.length / self.time
+    }
+
+    fn energy(&self) -> f32 {
+        self.mass * self.velocity() * self.velocity()
+    }
+
+    /// The gravitational constant `g` (in simulation units) as shown to the user
+    fn describe_g(&self, g: f32) -> String {
+        let converted = g * self.length.powi(3) / (self.mass * self.time * self.time);
+        if self.physical {
+            format!("{:.2} {}^3/{}/{}^2", converted, self.length_label, self.mass_label, self.time_label)
+        } else {
+            format!("{:.2}", converted)
         }
     }
 }
 
-fn main() {
-    let mut window = Window::new(
-        "N-Body Simulation (4K)",
-        WIDTH,
-        HEIGHT,
-        WindowOptions {
-            resize: true,
-            scale: minifb::Scale::X1,
-            ..WindowOptions::default()
-        },
-    )
-    .unwrap();
+/// Parameters of the pairwise force law
+#[derive(Clone, Copy, PartialEq)]
+struct Gravity {
+    g: f32,
+    softening: f32,
+    /// Softening of the pairs involving a central body
+    central_softening: f32,
+    /// Power of the (softened) distance the force falls off with; 2 is Newtonian gravity
+    exponent: f32,
+    /// Softening across the radial direction relative to along it (1 is the usual round kernel)
+    anisotropy: f32,
+    /// Strength of the pull within the first species, between the two, and within the second
+    coupling: [f32; 3],
+    /// Components `xx, xy, yy` of the tidal tensor of a host galaxy: every body feels an extra
+    /// acceleration of the tensor times its position, on top of the pairwise force law
+    tide: [f32; 3],
+}
 
-    let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
-    
-    // Create bodies with central mass
-    let mut bodies = Vec::with_capacity(NUM_BODIES + 1);
-    bodies.push(Body::central());
-    bodies.extend((0..NUM_BODIES).map(|_| Body::random(BASE_G)));
-    
-    let mut last_update = Instant::now();
-    let mut time_multiplier = 1.0;  // Controls simulation speed
-    let mut gravity_multiplier = 1.0;  // Controls gravity strength
+impl Gravity {
+    /// Multiplier of the pull between bodies of `species1` and `species2`
+    fn coupling(&self, species1: u8, species2: u8) -> f32 {
+        self.coupling[(species1 + species2) as usize]
+    }
 
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        // Handle controls
-        if window.is_key_down(Key::Equal) || window.is_key_down(Key::NumPadPlus) {
-            time_multiplier *= 1.1;
+    /// Whether every pair pulls equally, so the species make no difference to the forces
+    fn uniform_coupling(&self) -> bool {
+        self.coupling.iter().all(|&coupling| coupling == 1.0)
+    }
+
+    /// Whether pairs with a central body are softened like every other pair
+    fn uniform_softening(&self) -> bool {
+        self.central_softening == self.softening
+    }
+
+    /// The force law between two bodies, with the central softening if either is a central body
+    fn between(&self, body1: &Body, body2: &Body) -> Gravity {
+        if body1.central || body2.central {
+            Gravity { softening: self.central_softening, ..*self }
+        } else {
+            *self
         }
-        if window.is_key_down(Key::Minus) || window.is_key_down(Key::NumPadMinus) {
-            time_multiplier *= 0.9;
+    }
+
+    /// Pull of the tidal field on a mass at `pos`. The tensor is symmetric, so the field has
+    /// the potential `-m x.T.x / 2`.
+    fn tidal_force(&self, pos: Vec2, mass: f32) -> Vec2 {
+        let [xx, xy, yy] = self.tide;
+        mass * Vec2::new(xx * pos.x + xy * pos.y, xy * pos.x + yy * pos.y)
+    }
+
+    /// Force that a mass `mass2` at `pos2` exerts on a mass `mass1` at `pos1`
+    fn force(&self, pos1: Vec2, mass1: f32, pos2: Vec2, mass2: f32) -> Vec2 {
+        // Coincident bodies have no direction to pull in (normalize would give NaN)
+        let r = pos2 - pos1;
+        if r.length_squared() < MIN_SEPARATION_SQ {
+            return Vec2::ZERO;
         }
-        if window.is_key_down(Key::Key1) {
-            gravity_multiplier *= 0.9;
+
+        let dist_sq = r.length_squared() + self.softening_sq(r, (pos1 + pos2) / 2.0);
+        self.g * mass1 * mass2 * r.normalize() / self.falloff(dist_sq)
+    }
+
+    /// Squared softening length for the separation `r` of a pair centered on `midpoint`.
+    /// With anisotropy the softening is elliptical, `softening` along the radial direction
+    /// from the origin and `softening * anisotropy` tangentially. Using the pair's midpoint
+    /// keeps the forces on both bodies equal and opposite.
+    fn softening_sq(&self, r: Vec2, midpoint: Vec2) -> f32 {
+        let eps_sq = self.softening * self.softening;
+        if self.anisotropy == 1.0 {
+            return eps_sq;
         }
-        if window.is_key_down(Key::Key2) {
-            gravity_multiplier *= 1.1;
+        let radial = midpoint.normalize_or_zero();
+        if radial == Vec2::ZERO {
+            return eps_sq;
         }
+        let cos_sq = r.dot(radial).powi(2) / r.length_squared();
+        eps_sq * (cos_sq + (1.0 - cos_sq) * self.anisotropy * self.anisotropy)
+    }
 
-        // Clear buffer with very dark blue background
-        buffer.fill(0x000008);
+    /// Denominator of the force law, `(r^2 + eps^2)^(p/2)`, for a softened squared distance
+    fn falloff(&self, softened_dist_sq: f32) -> f32 {
+        if self.exponent == 2.0 {
+            softened_dist_sq
+        } else {
+            softened_dist_sq.powf(self.exponent / 2.0)
+        }
+    }
+}
 
-        // Update physics with fixed timestep and substeps for smoothness
-        let now = Instant::now();
-        let elapsed = now.duration_since(last_update).as_secs_f32();
-        
-        let g = BASE_G * gravity_multiplier;
-        let dt = BASE_DT;  // Keep base timestep constant
-        
-        // Calculate number of physics steps needed
-        let steps_needed = ((elapsed * time_multiplier) / dt) as usize;
-        let substeps = (steps_needed as f32 / 4.0).ceil() as usize;  // Limit max steps per frame
-        
-        if substeps > 0 {
-            let adjusted_dt = (elapsed * time_multiplier) / substeps as f32;
-            
-            for _ in 0..substeps {
-                let forces = calculate_forces(&bodies, g);
-                for (body, force) in bodies.iter_mut().zip(forces) {
-                    body.update(force, adjusted_dt);
-                }
+/// Screen corner to place an inset in
+#[derive(Clone, Copy)]
+enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Corner {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "top-left" => Some(Corner::TopLeft),
+            "top-right" => Some(Corner::TopRight),
+            "bottom-left" => Some(Corner::BottomLeft),
+            "bottom-right" => Some(Corner::BottomRight),
+            _ => None,
+        }
+    }
+
+    /// Top-left pixel of a `width` x `height` inset kept `margin` pixels from the edges
+    fn origin(self, width: usize, height: usize, margin: usize) -> (usize, usize) {
+        let right = WIDTH.saturating_sub(width + margin);
+        let bottom = HEIGHT.saturating_sub(height + margin);
+        match self {
+            Corner::TopLeft => (margin, margin),
+            Corner::TopRight => (right, margin),
+            Corner::BottomLeft => (margin, bottom),
+            Corner::BottomRight => (right, bottom),
+        }
+    }
+}
+
+/// Window size relative to the 4K render buffer, which minifb stretches to fill the window
+#[derive(Clone, Copy)]
+enum WindowScale {
+    Half,
+    X1,
+    X2,
+    /// As large as fits on the screen, starting from half size
+    Fit,
+}
+
+impl WindowScale {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "half" => Some(WindowScale::Half),
+            "x1" => Some(WindowScale::X1),
+            "x2" => Some(WindowScale::X2),
+            "fit" => Some(WindowScale::Fit),
+            _ => None,
+        }
+    }
+
+    /// Size to open the window at, and the minifb scale applied on top of that
+    fn window_size(self) -> (usize, usize, minifb::Scale) {
+        match self {
+            WindowScale::Half => (WIDTH / 2, HEIGHT / 2, minifb::Scale::X1),
+            WindowScale::X1 => (WIDTH, HEIGHT, minifb::Scale::X1),
+            WindowScale::X2 => (WIDTH, HEIGHT, minifb::Scale::X2),
+            WindowScale::Fit => (WIDTH / 2, HEIGHT / 2, minifb::Scale::FitScreen),
+        }
+    }
+}
+
+/// How trails, predicted paths and other overlay lines are drawn
+#[derive(Clone, Copy)]
+struct LineStyle {
+    /// Line width in screen pixels
+    width: f32,
+    /// Blend partially covered edge pixels instead of snapping to whole pixels
+    antialias: bool,
+}
+
+/// How a satellite's radius grows with its mass, `base * (mass / MIN_MASS)^exponent`. The
+/// same radius is drawn and decides which bodies touch in merges, so merged bodies grow as
+/// big as they look. An exponent of 1/2 keeps the mass per area constant, as for flat disks;
+/// 1/3 keeps the density of a sphere constant.
+#[derive(Clone, Copy, PartialEq)]
+struct RadiusLaw {
+    /// Radius of the lightest body
+    base: f32,
+    exponent: f32,
+}
+
+/// One ring of light around a body, fading from `intensity` at the body's edge to nothing
+/// at `radius`
+#[derive(Clone, Copy)]
+struct GlowLayer {
+    /// Outer radius, in pixels when drawing, or as a multiple of the body's radius in the config
+    radius: f32,
+    intensity: f32,
+}
+
+/// Runtime-configurable simulation options (set from the command line)
+#[derive(Clone)]
+struct Config {
+    wall_bounce: WallBounce,
+    speed_limit: SpeedLimit,
+    sponge: Sponge,
+    /// Rate at which radial velocities are damped, pushing orbits toward circles (0 disables it)
+    cooling: f32,
+    integrator: Integrator,
+    /// Bodies smaller than this on screen (in pixels) are drawn as a single faint pixel
+    lod_radius: f32,
+    /// Angular velocity of a solid-body rotation added to the initial orbits (negative spins clockwise)
+    spin: f32,
+    scenario: InitialCondition,
+    /// Picture the image scenario spawns bodies on (PGM or PPM)
+    image: Option<PathBuf>,
+    /// Number of bodies spawned on the image
+    image_bodies: usize,
+    /// Width of the image in world units, centered on the origin
+    image_width: f32,
+    /// Start the image's bodies on orbits instead of at rest
+    image_orbits: bool,
+    /// Size of the worker thread pool (defaults to one per CPU core)
+    threads: Option<usize>,
+    /// Merge bodies that touch
+    merge: bool,
+    /// Take the net momentum back out after every step, so the system can't drift away
+    zero_momentum: bool,
+    /// Multiple of the drawn radius within which bodies merge
+    accretion_scale: f32,
+    /// Radius of the satellites for a given mass, both drawn and in merges
+    radius_law: RadiusLaw,
+    /// Neighborhood radius used by the density color modes
+    density_radius: f32,
+    /// How sharply the density wave color mode separates over- from underdense regions
+    wave_contrast: f32,
+    /// Start with the dominant central mass
+    central_body: bool,
+    /// Hold the central mass fixed at the origin
+    pin_central: bool,
+    /// Give bodies random velocities instead of orbits even around a central body, for a
+    /// cluster held up by random motion rather than rotation
+    hot: bool,
+    /// Standard deviation of each velocity component when bodies don't start on orbits
+    velocity_dispersion: f32,
+    /// Smooth frame times before using them to advance the simulation
+    smooth_frames: bool,
+    /// Draw bodies between their last two physics steps, by the share of a step left over
+    interpolate: bool,
+    /// Frames of body positions kept to scrub back through while paused (0 keeps none)
+    history: usize,
+    /// Distance from the center of mass beyond which a body counts as escaping
+    escape_radius: f32,
+    /// Drop escaping bodies once they are unbound from the rest of the system
+    remove_escapers: bool,
+    /// Merge the closest pairs whenever there are more bodies than this
+    max_bodies: Option<usize>,
+    /// Most pairs merged per step while over `max_bodies`, so the count comes down gradually
+    cap_merge_rate: usize,
+    /// Range of the initial speed as a multiple of the circular orbital speed
+    orbit_speed_range: (f32, f32),
+    /// Range of the outward velocity component (relative to the tangent) that makes orbits eccentric
+    eccentricity_range: (f32, f32),
+    /// How the generated satellites' masses are drawn
+    mass_distribution: MassDistribution,
+    /// Gravitational constant in simulation units
+    g: f32,
+    /// Steps over which gravity ramps up linearly from nothing to full strength (0 starts at full)
+    gravity_ramp: u64,
+    /// Second population of satellites
+    species: Species,
+    /// Pull within the first species, between the species, and within the second, relative to gravity
+    coupling: [f32; 3],
+    /// Tidal tensor of the host galaxy as `xx, xy, yy` (all 0, the default, leaves it out)
+    tide: [f32; 3],
+    /// Units used when displaying quantities
+    units: Units,
+    /// Base physics timestep
+    dt: f32,
+    softening: f32,
+    /// Softening of the pairs involving a central body, if different from `softening`
+    central_softening: Option<f32>,
+    /// Exponent of the force law (2 for inverse-square gravity)
+    force_exponent: f32,
+    /// Tangential over radial softening length, for the experimental elliptical kernel
+    softening_anisotropy: f32,
+    /// Barnes-Hut opening angle (0 sums over all pairs directly)
+    theta: f32,
+    /// Report the shape of the Barnes-Hut tree in the overlay and the headless summary
+    tree_stats: bool,
+    /// Depth and leaf bucket size the Barnes-Hut tree is split to
+    tree_limits: TreeLimits,
+    /// Start each Verlet step from the forces the previous one ended with, when still valid
+    reuse_forces: bool,
+    /// Have the bench measure the Barnes-Hut force error at several opening angles
+    theta_sweep: bool,
+    /// Have the bench compare the integrators' energy error against their cost on a test orbit
+    integrator_sweep: bool,
+    /// Have the bench time the Barnes-Hut tree build on one thread against several
+    tree_build: bool,
+    /// Print the headless summary and conservation report as JSON
+    json: bool,
+    /// Write the bodies to stdout as a line of JSON every this many headless steps
+    stream: Option<u64>,
+    /// CSV file to write energy, momentum and center of mass to
+    diagnostics_log: Option<PathBuf>,
+    /// Only log diagnostics every this many steps
+    diagnostics_stride: usize,
+    /// Report this many of the closest pairwise approaches at the end of the run
+    closest_approaches: usize,
+    /// Number of steps to run in headless and bench mode
+    steps: u64,
+    /// Close the window once this many physics steps have run
+    max_steps: Option<u64>,
+    /// Save the last frame as a PPM image when the window closes after `max_steps`
+    final_screenshot: Option<PathBuf>,
+    /// Render the final screenshot at this many times the resolution and filter it back down
+    supersample: usize,
+    /// Seed for the initial conditions (random when not given)
+    seed: Option<u64>,
+    /// JSON file to load the bodies from instead of generating a scenario
+    bodies_json: Option<PathBuf>,
+    /// Save file to resume from
+    load: Option<PathBuf>,
+    /// Where to save the state when the run ends
+    save: Option<PathBuf>,
+    /// Resample new bodies that would start closer than this to another body (never less than the softening)
+    min_separation: Option<f32>,
+    /// Number of past positions kept per body for its trail (0 disables trails)
+    trail_length: usize,
+    /// Only keep trails for this many of the heaviest bodies (all bodies when not given)
+    trail_count: Option<usize>,
+    /// Range the mass of the selected body can be edited within
+    mass_edit_range: (f32, f32),
+    /// Keys bound to the interactive controls
+    keymap: Keymap,
+    /// Number of steps the predicted paths of thrown and selected bodies, and the forecast approach of two bodies, look ahead
+    prediction_steps: usize,
+    /// Color of the predicted paths
+    prediction_color: u32,
+    line_style: LineStyle,
+    /// Print how long force calculation, integration and rendering take
+    profile: bool,
+    window_scale: WindowScale,
+    /// Wall-clock milliseconds per frame the physics may use before the remaining time is dropped
+    frame_budget: f32,
+    /// Brightness of the screen-edge flash on close encounters (0 disables it)
+    encounter_flash: f32,
+    /// Glow layers around the central body, added on top of each other
+    glow: Vec<GlowLayer>,
+    /// Strength of the Reinhard tone curve applied to each frame (0 disables it, B toggles it)
+    tone_map: f32,
+    /// How strongly the stroboscopic view lifts rarely visited pixels against the busiest one
+    strobe_brightness: f32,
+    /// Draw velocity vectors in SVG exports
+    svg_velocities: bool,
+    /// Strength of the light bleeding out of bright pixels (0 disables the bloom)
+    bloom: f32,
+    /// Luminance (0 to 1) above which pixels bleed light into their surroundings
+    bloom_threshold: f32,
+    /// Start with a crosshair marking the center of mass (X toggles it)
+    com_marker: bool,
+    com_color: u32,
+    /// Start with the minimap shown (M toggles it)
+    minimap: bool,
+    /// Minimap width in screen pixels (the height follows the screen's aspect ratio)
+    minimap_width: usize,
+    minimap_corner: Corner,
+    /// Start with the radial density profile shown (D toggles it)
+    density_profile: bool,
+    /// Number of rings the density profile is binned into, out to `escape_radius`
+    profile_bins: usize,
+    /// Frames between updates of the density profile
+    profile_interval: usize,
+    /// Plot the mass density instead of the number density
+    profile_mass: bool,
+    /// Show the elapsed time and step count as powers of ten (L toggles it)
+    log_time: bool,
+    /// Run one simulation per integrator side by side (2 or 4 of them) instead of a single one
+    compare: Vec<Integrator>,
+    /// Spacing of the grid that thrown bodies snap to while snapping is on (G toggles it)
+    snap_increment: f32,
+    /// Fraction of the bodies closest to the center of mass that F fits into view
+    frame_fraction: f32,
+    /// Reach of the explosion set off with E
+    explosion_radius: f32,
+    /// Kinetic energy of the explosion's kicks
+    explosion_energy: f32,
+}
+
+/// What to do, chosen by the first command-line argument
+enum Command {
+    /// Open a window (the default)
+    Run(Config),
+    Headless(Config),
+    /// Time a fixed number of steps and report the throughput
+    Bench(Config),
+    /// Convert a body file between the JSON and CSV formats
+    Convert { input: PathBuf, output: PathBuf },
+    /// Print the usage of a subcommand
+    Help(String),
+}
+
+/// Options that only matter with a window. `--bind` only matters with one too, but isn't
+/// listed, so config files with rebound keys still work for headless runs.
+const WINDOW_OPTIONS: &[&str] = &[
+    "--max-steps", "--final-screenshot", "--supersample", "--lod-radius", "--density-radius", "--wave-contrast", "--smooth-frames", "--interpolate", "--history", "--trails",
+    "--trail-count", "--prediction-steps", "--prediction-color", "--line-width", "--antialias", "--scale",
+    "--encounter-flash", "--glow", "--tone-map", "--strobe-brightness", "--svg-velocities", "--bloom", "--bloom-threshold", "--com-marker", "--com-color", "--minimap", "--minimap-size", "--minimap-corner",
+    "--density-profile", "--profile-bins", "--profile-interval", "--profile-mass", "--log-time",
+    "--compare", "--snap-grid", "--frame-fraction", "--explosion-radius", "--explosion-energy", "--mass-range",
+    "--frame-budget",
+];
+
+const SIMULATION_USAGE: &str = "[--config FILE] [--seed N] [--bodies-json FILE] [--load FILE] [--save FILE] [--min-separation D] [--scenario shells|focusing|ring|sandbox|collision] [--ring INNER,OUTER[,GAP_CENTER,GAP_WIDTH]] [--collision SEPARATION,SPEED,IMPACT_PARAMETER] [--image FILE] [--image-bodies N] [--image-width W] [--image-orbits] [--no-central] [--pin-central] [--hot] [--velocity-dispersion V] [--spin RATE] [--species FRACTION] [--species-mass MIN,MAX] [--species-color RRGGBB] [--coupling AA,AB,BB] [--tide XX,XY,YY] [--orbit-speed MIN,MAX] [--eccentricity MIN,MAX] [--mass-distribution uniform|power-law|log-uniform] [--mass-slope ALPHA] [--integrator euler|verlet|hermite] [--g G] [--gravity-ramp STEPS] [--dt STEP] [--softening EPS] [--central-softening EPS] [--force-exponent P] [--softening-anisotropy RATIO] [--theta T] [--tree-depth N] [--tree-bucket N] [--tree-stats] [--reuse-forces] [--units simulation|physical] [--time-unit LABEL[,PER_UNIT]] [--elastic-walls] [--sponge-width W] [--sponge-strength K] [--cooling RATE] [--max-speed V] [--no-speed-limit] [--max-acceleration A] [--merge] [--zero-momentum] [--accretion-scale S] [--body-radius R] [--radius-exponent E] [--escape-radius R] [--remove-escapers] [--max-bodies N] [--cap-merge-rate N] [--diagnostics-log FILE] [--diagnostics-stride N] [--closest-approaches N] [--threads N] [--profile]";
+const WINDOW_USAGE: &str = "[--max-steps N] [--final-screenshot FILE] [--supersample N] [--scale half|x1|x2|fit] [--frame-budget MS] [--smooth-frames] [--interpolate] [--history FRAMES] [--lod-radius PX] [--density-radius R] [--wave-contrast K] [--trails K] [--trail-count N] [--prediction-steps N] [--prediction-color RRGGBB] [--line-width PX] [--antialias] [--encounter-flash S] [--glow RADIUS:INTENSITY[,...]] [--tone-map S] [--strobe-brightness S] [--svg-velocities] [--bloom I] [--bloom-threshold T] [--com-marker] [--com-color RRGGBB] [--minimap] [--minimap-size W] [--minimap-corner top-left|top-right|bottom-left|bottom-right] [--density-profile] [--profile-bins N] [--profile-interval FRAMES] [--profile-mass] [--log-time] [--compare INTEGRATOR,INTEGRATOR[,...]] [--snap-grid D] [--frame-fraction F] [--explosion-radius R] [--explosion-energy E] [--mass-range MIN,MAX] [--bind ACTION=KEY[,KEY...]]";
+
+fn usage(command: &str) -> String {
+    match command {
+        "headless" => format!(
+            "Usage: nbody-sim headless [--steps N] [--json] [--stream N] {}\nStep without a window, exactly one timestep per step, and print a summary and how well energy and momentum were conserved",
+            SIMULATION_USAGE
+        ),
+        "bench" => format!(
+            "Usage: nbody-sim bench [--steps N] [--theta-sweep] [--integrator-sweep] [--tree-build] {}\nTime N steps (default 1000, seed 0) and report steps per second",
+            SIMULATION_USAGE
+        ),
+        "convert" => "Usage: nbody-sim convert INPUT OUTPUT\nConvert a body file between .json and .csv (x,y,vx,vy,mass)".to_string(),
+        _ => format!(
+            "Usage: nbody-sim [run|headless|bench|convert] [OPTIONS]\nnbody-sim [run] {} {}\nUse 'nbody-sim COMMAND --help' for the options of the other commands",
+            SIMULATION_USAGE, WINDOW_USAGE
+        ),
+    }
+}
+
+impl Command {
+    fn from_args() -> Result<Self, String> {
+        let mut args: Vec<String> = std::env::args().skip(1).collect();
+        let command = match args.first().map(String::as_str) {
+            Some(name @ ("run" | "headless" | "bench" | "convert")) => {
+                let name = name.to_string();
+                args.remove(0);
+                name
             }
+            // `--headless` predates the subcommands
+            _ if args.iter().any(|arg| arg == "--headless") => {
+                args.retain(|arg| arg != "--headless");
+                "headless".to_string()
+            }
+            _ => "run".to_string(),
+        };
+        if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+            return Ok(Command::Help(usage(&command)));
         }
-        
-        last_update = now;
 
-        // Draw bodies (central body last to overlay its glow)
-        for body in &bodies[1..] {
-            draw_circle(&mut buffer, body.pos, body.radius(), body.color, false);
+        // Splice in the options from config files where they appear, so later flags override them
+        let mut expanded = Vec::with_capacity(args.len());
+        let mut rest = args.into_iter();
+        while let Some(arg) = rest.next() {
+            if arg == "--config" {
+                let path = rest.next().ok_or("missing value for --config")?;
+                expanded.extend(settings::read_settings(Path::new(&path))?);
+            } else {
+                expanded.push(arg);
+            }
+        }
+        let args = expanded;
+
+        match command.as_str() {
+            "convert" => match <[String; 2]>::try_from(args) {
+                Ok([input, output]) => Ok(Command::Convert { input: input.into(), output: output.into() }),
+                Err(_) => Err("convert expects INPUT and OUTPUT files".to_string()),
+            },
+            "headless" => Ok(Command::Headless(Config::from_args(&command, args.into_iter())?)),
+            "bench" => Ok(Command::Bench(Config::from_args(&command, args.into_iter())?)),
+            _ => Ok(Command::Run(Config::from_args(&command, args.into_iter())?)),
         }
-        // Draw central body with glow
-        draw_circle(&mut buffer, bodies[0].pos, bodies[0].radius(), 0xFFAA33, true);
+    }
+}
 
-        // Update window title with controls and current multipliers
-        window.set_title(&format!(
-            "N-Body Simulation (4K) - Speed: {:.1}x (±) - Gravity: {:.1}x (1/2) - ESC to exit",
-            time_multiplier, gravity_multiplier
-        ));
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            wall_bounce: WallBounce::Damped,
+            speed_limit: SpeedLimit::Velocity(MAX_VELOCITY),
+            sponge: Sponge { width: 0.0, strength: 5.0 },
+            cooling: 0.0,
+            mass_edit_range: (MIN_MASS, CENTRAL_MASS),
+            keymap: Keymap::default(),
+            species: Species { fraction: 0.0, mass_range: (MIN_MASS, 5.0), color: 0xFFD27F },
+            coupling: [1.0; 3],
+            tide: [0.0; 3],
+            integrator: Integrator::Euler,
+            lod_radius: 0.0,
+            spin: 0.0,
+            scenario: InitialCondition::Shells,
+            image: None,
+            image_bodies: NUM_BODIES,
+            image_width: IMAGE_WIDTH,
+            image_orbits: false,
+            threads: None,
+            merge: false,
+            zero_momentum: false,
+            accretion_scale: 1.0,
+            radius_law: RadiusLaw { base: 3.0, exponent: 0.5 },
+            density_radius: 50.0,
+            wave_contrast: 2.0,
+            central_body: true,
+            pin_central: false,
+            hot: false,
+            velocity_dispersion: 10.0,
+            smooth_frames: false,
+            interpolate: false,
+            history: 0,
+            escape_radius: 1000.0,
+            remove_escapers: false,
+            max_bodies: None,
+            cap_merge_rate: 5,
+            orbit_speed_range: (0.7, 1.4),
+            eccentricity_range: (-0.2, 0.2),
+            mass_distribution: MassDistribution::Uniform,
+            g: BASE_G,
+            units: Units::simulation(),
+            dt: BASE_DT,
+            gravity_ramp: 0,
+            softening: SOFTENING,
+            central_softening: None,
+            softening_anisotropy: 1.0,
+            theta: 0.0,
+            reuse_forces: false,
+            tree_stats: false,
+            tree_limits: TreeLimits::default(),
+            theta_sweep: false,
+            integrator_sweep: false,
+            tree_build: false,
+            json: false,
+            stream: None,
+            force_exponent: 2.0,
+            diagnostics_log: None,
+            diagnostics_stride: 1,
+            closest_approaches: 0,
+            steps: 1000,
+            max_steps: None,
+            final_screenshot: None,
+            supersample: 1,
+            seed: None,
+            bodies_json: None,
+            load: None,
+            save: None,
+            min_separation: None,
+            trail_length: 0,
+            trail_count: None,
+            prediction_steps: 3000,
+            prediction_color: 0x80FF80,
+            line_style: LineStyle { width: 1.0, antialias: false },
+            profile: false,
+            window_scale: WindowScale::X1,
+            frame_budget: 12.0,
+            encounter_flash: 0.0,
+            glow: vec![GlowLayer { radius: 2.0, intensity: 1.0 }],
+            tone_map: 0.0,
+            strobe_brightness: 20.0,
+            svg_velocities: false,
+            bloom: 0.0,
+            bloom_threshold: 0.5,
+            com_marker: false,
+            com_color: 0xFF40FF,
+            compare: Vec::new(),
+            snap_increment: 50.0,
+            frame_fraction: 0.95,
+            explosion_radius: 150.0,
+            explosion_energy: 1e7,
+            minimap: false,
+            minimap_width: 640,
+            minimap_corner: Corner::BottomRight,
+            density_profile: false,
+            profile_bins: 24,
+            profile_interval: 30,
+            profile_mass: false,
+            log_time: false,
+        }
+    }
+}
+
+impl Config {
+    /// Whether stdout carries machine-readable output, so messages must go to stderr
+    fn data_on_stdout(&self) -> bool {
+        self.json || self.stream.is_some()
+    }
+
+    fn gravity(&self, gravity_multiplier: f32) -> Gravity {
+        Gravity {
+            g: self.g * gravity_multiplier,
+            softening: self.softening,
+            central_softening: self.central_softening.unwrap_or(self.softening),
+            exponent: self.force_exponent,
+            anisotropy: self.softening_anisotropy,
+            coupling: self.coupling,
+            tide: self.tide,
+        }
+    }
+
+    /// Parse the options of the `command` subcommand
+    fn from_args(command: &str, args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut args = args;
+        // Applied once all the arguments are in, so it works on either side of --units
+        let mut time_unit = None;
+        while let Some(arg) = args.next() {
+            if command != "run" && WINDOW_OPTIONS.contains(&arg.as_str()) {
+                return Err(format!("{} only applies to the run command", arg));
+            }
+            if command != "bench" && (arg == "--theta-sweep" || arg == "--integrator-sweep" || arg == "--tree-build") {
+                return Err(format!("{} only applies to bench", arg));
+            }
+            if command != "headless" && (arg == "--json" || arg == "--stream") {
+                return Err(format!("{} only applies to headless", arg));
+            }
+            if command == "run" && arg == "--steps" {
+                return Err("--steps only applies to headless and bench (use --max-steps with a window)".to_string());
+            }
 
-        window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
+            let mut value = || args.next().ok_or(format!("missing value for {}", arg));
+            match arg.as_str() {
+                "--elastic-walls" => config.wall_bounce = WallBounce::Elastic,
+                "--sponge-width" => {
+                    config.sponge.width = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&width| width >= 0.0)
+                        .ok_or("--sponge-width expects a non-negative distance")?;
+                }
+                "--sponge-strength" => {
+                    config.sponge.strength = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&strength| strength >= 0.0)
+                        .ok_or("--sponge-strength expects a non-negative damping rate")?;
+                }
+                "--cooling" => {
+                    config.cooling = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&rate: &f32| rate >= 0.0)
+                        .ok_or("--cooling expects a non-negative damping rate")?;
+                }
+                "--max-speed" => {
+                    let max: f32 = value()?
+                        .parse()
+                        .map_err(|_| "--max-speed expects a number".to_string())?;
+                    if max <= 0.0 {
+                        return Err("--max-speed must be positive".to_string());
+                    }
+                    config.speed_limit = SpeedLimit::Velocity(max);
+                }
+                "--no-speed-limit" => config.speed_limit = SpeedLimit::Off,
+                "--max-acceleration" => {
+                    let max: f32 = value()?
+                        .parse()
+                        .map_err(|_| "--max-acceleration expects a number".to_string())?;
+                    if max <= 0.0 {
+                        return Err("--max-acceleration must be positive".to_string());
+                    }
+                    config.speed_limit = SpeedLimit::Acceleration(max);
+                }
+                "--integrator" => {
+                    let name = value()?;
+                    config.integrator = Integrator::from_name(&name)
+                        .ok_or(format!("unknown integrator: {}", name))?;
+                }
+                "--lod-radius" => {
+                    config.lod_radius = value()?
+                        .parse()
+                        .map_err(|_| "--lod-radius expects a number of pixels".to_string())?;
+                }
+                "--spin" => {
+                    config.spin = value()?
+                        .parse()
+                        .ok()
+                        .filter(|spin: &f32| spin.is_finite())
+                        .ok_or("--spin expects an angular velocity")?;
+                }
+                "--scenario" => {
+                    let name = value()?;
+                    config.scenario = InitialCondition::from_name(&name)
+                        .ok_or(format!("unknown scenario: {}", name))?;
+                }
+                "--threads" => {
+                    let threads: usize = value()?
+                        .parse()
+                        .map_err(|_| "--threads expects a whole number".to_string())?;
+                    if threads == 0 {
+                        return Err("--threads must be at least 1".to_string());
+                    }
+                    config.threads = Some(threads);
+                }
+                "--merge" => config.merge = true,
+                "--zero-momentum" => config.zero_momentum = true,
+                "--accretion-scale" => {
+                    config.accretion_scale = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&scale: &f32| scale > 0.0 && scale.is_finite())
+                        .ok_or("--accretion-scale expects a positive multiple of the radius")?;
+                }
+                "--body-radius" => {
+                    config.radius_law.base = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&radius: &f32| radius > 0.0)
+                        .ok_or("--body-radius expects a positive radius")?;
+                }
+                "--radius-exponent" => {
+                    config.radius_law.exponent = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&exponent: &f32| (0.0..=1.0).contains(&exponent))
+                        .ok_or("--radius-exponent expects an exponent between 0 and 1")?;
+                }
+                "--density-radius" => {
+                    config.density_radius = value()?
+                        .parse()
+                        .map_err(|_| "--density-radius expects a distance".to_string())?;
+                    if config.density_radius <= 0.0 {
+                        return Err("--density-radius must be positive".to_string());
+                    }
+                }
+                "--wave-contrast" => {
+                    config.wave_contrast = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&contrast: &f32| contrast > 0.0)
+                        .ok_or("--wave-contrast expects a positive number")?;
+                }
+                "--no-central" => config.central_body = false,
+                "--pin-central" => config.pin_central = true,
+                "--hot" => config.hot = true,
+                "--velocity-dispersion" => {
+                    config.velocity_dispersion = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&speed: &f32| speed >= 0.0 && speed.is_finite())
+                        .ok_or("--velocity-dispersion expects a non-negative speed")?;
+                }
+                "--smooth-frames" => config.smooth_frames = true,
+                "--interpolate" => config.interpolate = true,
+                "--history" => {
+                    config.history = value()?
+                        .parse()
+                        .map_err(|_| "--history expects a whole number of frames".to_string())?;
+                }
+                "--escape-radius" => {
+                    config.escape_radius = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&radius: &f32| radius > 0.0 && radius.is_finite())
+                        .ok_or("--escape-radius expects a positive distance")?;
+                }
+                "--remove-escapers" => config.remove_escapers = true,
+                "--max-bodies" => {
+                    config.max_bodies = Some(
+                        value()?
+                            .parse()
+                            .ok()
+                            .filter(|&bodies| bodies > 0)
+                            .ok_or("--max-bodies expects a positive number of bodies")?,
+                    );
+                }
+                "--cap-merge-rate" => {
+                    config.cap_merge_rate = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&rate| rate > 0)
+                        .ok_or("--cap-merge-rate expects a positive number of pairs")?;
+                }
+                "--mass-range" => {
+                    let (min, max) = parse_range(&value()?, &arg)?;
+                    if min <= 0.0 {
+                        return Err("--mass-range must be positive".to_string());
+                    }
+                    config.mass_edit_range = (min, max);
+                }
+                "--bind" => config.keymap.bind(&value()?)?,
+                "--species" => {
+                    config.species.fraction = value()?
+                        .parse()
+                        .ok()
+                        .filter(|fraction| (0.0..=1.0).contains(fraction))
+                        .ok_or("--species expects a fraction between 0 and 1")?;
+                }
+                "--species-mass" => {
+                    let (min, max) = parse_range(&value()?, &arg)?;
+                    if min <= 0.0 {
+                        return Err("--species-mass must be positive".to_string());
+                    }
+                    config.species.mass_range = (min, max);
+                }
+                "--species-color" => {
+                    config.species.color = parse_color(&value()?).ok_or("--species-color expects a hex color like ffd27f")?;
+                }
+                "--coupling" => {
+                    let usage = "--coupling expects three strengths: WITHIN_FIRST,BETWEEN,WITHIN_SECOND";
+                    let strengths = value()?
+                        .split(',')
+                        .map(|part| part.trim().parse::<f32>().map_err(|_| usage.to_string()))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    config.coupling = strengths.try_into().map_err(|_| usage.to_string())?;
+                }
+                "--tide" => {
+                    let usage = "--tide expects three tidal tensor components: XX,XY,YY";
+                    let components = value()?
+                        .split(',')
+                        .map(|part| part.trim().parse::<f32>().map_err(|_| usage.to_string()))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    config.tide = components.try_into().map_err(|_| usage.to_string())?;
+                }
+                "--orbit-speed" => {
+                    let (min, max) = parse_range(&value()?, &arg)?;
+                    if min <= 0.0 {
+                        return Err("--orbit-speed factors must be positive".to_string());
+                    }
+                    config.orbit_speed_range = (min, max);
+                }
+                "--mass-distribution" => {
+                    let name = value()?;
+                    config.mass_distribution = MassDistribution::from_name(&name)
+                        .ok_or(format!("unknown mass distribution: {}", name))?;
+                }
+                "--mass-slope" => {
+                    let slope = value()?
+                        .parse()
+                        .ok()
+                        .filter(|slope: &f32| slope.is_finite())
+                        .ok_or("--mass-slope expects a number")?;
+                    config.mass_distribution = MassDistribution::PowerLaw { slope };
+                }
+                "--eccentricity" => {
+                    let (min, max) = parse_range(&value()?, &arg)?;
+                    if min < -1.0 || max > 1.0 {
+                        return Err("--eccentricity values must be within -1..1".to_string());
+                    }
+                    config.eccentricity_range = (min, max);
+                }
+                "--diagnostics-log" => config.diagnostics_log = Some(PathBuf::from(value()?)),
+                "--diagnostics-stride" => {
+                    config.diagnostics_stride = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&stride| stride > 0)
+                        .ok_or("--diagnostics-stride expects a positive whole number")?;
+                }
+                "--closest-approaches" => {
+                    config.closest_approaches = value()?
+                        .parse()
+                        .map_err(|_| "--closest-approaches expects a whole number".to_string())?;
+                }
+                "--steps" => {
+                    config.steps = value()?
+                        .parse()
+                        .map_err(|_| "--steps expects a whole number".to_string())?;
+                }
+                "--max-steps" => {
+                    config.max_steps = Some(
+                        value()?
+                            .parse()
+                            .map_err(|_| "--max-steps expects a whole number".to_string())?,
+                    );
+                }
+                "--final-screenshot" => config.final_screenshot = Some(PathBuf::from(value()?)),
+                "--supersample" => {
+                    config.supersample = value()?
+                        .parse()
+                        .ok()
+                        .filter(|factor| (1..=MAX_SUPERSAMPLE).contains(factor))
+                        .ok_or(format!("--supersample expects a factor from 1 to {}", MAX_SUPERSAMPLE))?;
+                }
+                "--seed" => {
+                    config.seed = Some(
+                        value()?
+                            .parse()
+                            .map_err(|_| "--seed expects a whole number".to_string())?,
+                    );
+                }
+                "--bodies-json" => config.bodies_json = Some(PathBuf::from(value()?)),
+                "--image" => {
+                    config.image = Some(PathBuf::from(value()?));
+                    config.scenario = InitialCondition::Image;
+                }
+                "--image-bodies" => {
+                    config.image_bodies = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&bodies| bodies > 0)
+                        .ok_or("--image-bodies expects a positive number of bodies")?;
+                }
+                "--image-width" => {
+                    config.image_width = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&width: &f32| width > 0.0)
+                        .ok_or("--image-width expects a positive width")?;
+                }
+                "--image-orbits" => config.image_orbits = true,
+                "--load" => config.load = Some(PathBuf::from(value()?)),
+                "--save" => config.save = Some(PathBuf::from(value()?)),
+                "--min-separation" => {
+                    config.min_separation = Some(
+                        value()?
+                            .parse()
+                            .map_err(|_| "--min-separation expects a distance".to_string())?,
+                    );
+                }
+                "--ring" => config.scenario = InitialCondition::parse_ring(&value()?)?,
+                "--collision" => config.scenario = InitialCondition::parse_collision(&value()?)?,
+                "--trails" => {
+                    config.trail_length = value()?
+                        .parse()
+                        .map_err(|_| "--trails expects a number of positions".to_string())?;
+                }
+                "--trail-count" => {
+                    config.trail_count = Some(
+                        value()?
+                            .parse()
+                            .map_err(|_| "--trail-count expects a number of bodies".to_string())?,
+                    );
+                }
+                "--g" => {
+                    config.g = value()?
+                        .parse()
+                        .ok()
+                        .filter(|g: &f32| g.is_finite() && *g >= 0.0)
+                        .ok_or("--g expects a non-negative gravitational constant")?;
+                }
+                "--gravity-ramp" => {
+                    config.gravity_ramp = value()?
+                        .parse()
+                        .map_err(|_| "--gravity-ramp expects a whole number of steps".to_string())?;
+                }
+                "--dt" => {
+                    config.dt = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&dt| dt > 0.0)
+                        .ok_or("--dt expects a positive timestep")?;
+                }
+                "--softening" => {
+                    config.softening = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&softening| softening >= 0.0)
+                        .ok_or("--softening expects a length (0 turns it off)")?;
+                }
+                "--central-softening" => {
+                    config.central_softening = Some(
+                        value()?
+                            .parse()
+                            .ok()
+                            .filter(|&softening: &f32| softening >= 0.0)
+                            .ok_or("--central-softening expects a length (0 turns it off)")?,
+                    );
+                }
+                "--prediction-steps" => {
+                    config.prediction_steps = value()?
+                        .parse()
+                        .map_err(|_| "--prediction-steps expects a number of steps".to_string())?;
+                }
+                "--prediction-color" => {
+                    config.prediction_color = parse_color(&value()?)
+                        .ok_or("--prediction-color expects a hex color like 80ff80")?;
+                }
+                "--line-width" => {
+                    config.line_style.width = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&width| width > 0.0)
+                        .ok_or("--line-width expects a positive number of pixels")?;
+                }
+                "--antialias" => config.line_style.antialias = true,
+                "--profile" => config.profile = true,
+                "--frame-budget" => {
+                    config.frame_budget = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&budget| budget > 0.0)
+                        .ok_or("--frame-budget expects a positive number of milliseconds")?;
+                }
+                "--glow" => {
+                    let usage = "--glow expects RADIUS:INTENSITY pairs, like 1.5:1,4:0.2, or none";
+                    let layers = value()?;
+                    config.glow = if layers == "none" {
+                        Vec::new()
+                    } else {
+                        layers
+                            .split(',')
+                            .map(|layer| {
+                                let (radius, intensity) = layer.split_once(':')?;
+                                let radius: f32 = radius.trim().parse().ok().filter(|&radius| radius > 1.0)?;
+                                let intensity: f32 = intensity.trim().parse().ok().filter(|&intensity| intensity >= 0.0)?;
+                                Some(GlowLayer { radius, intensity })
+                            })
+                            .collect::<Option<_>>()
+                            .ok_or(usage)?
+                    };
+                }
+                "--encounter-flash" => {
+                    config.encounter_flash = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&strength: &f32| strength >= 0.0 && strength.is_finite())
+                        .ok_or("--encounter-flash expects a non-negative strength")?;
+                }
+                "--tone-map" => {
+                    config.tone_map = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&strength| strength >= 0.0)
+                        .ok_or("--tone-map expects a non-negative strength")?;
+                }
+                "--strobe-brightness" => {
+                    config.strobe_brightness = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&brightness| brightness > 0.0)
+                        .ok_or("--strobe-brightness expects a positive scale")?;
+                }
+                "--svg-velocities" => config.svg_velocities = true,
+                "--bloom" => {
+                    config.bloom = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&bloom| bloom >= 0.0)
+                        .ok_or("--bloom expects a non-negative intensity")?;
+                }
+                "--bloom-threshold" => {
+                    config.bloom_threshold = value()?
+                        .parse()
+                        .ok()
+                        .filter(|threshold| (0.0..1.0).contains(threshold))
+                        .ok_or("--bloom-threshold expects a luminance from 0 up to 1")?;
+                }
+                "--com-marker" => config.com_marker = true,
+                "--com-color" => {
+                    config.com_color = parse_color(&value()?).ok_or("--com-color expects a hex color like ff40ff")?;
+                }
+                "--compare" => {
+                    let names = value()?;
+                    config.compare = names
+                        .split(',')
+                        .map(|name| Integrator::from_name(name.trim()).ok_or(format!("unknown integrator: {}", name)))
+                        .collect::<Result<_, _>>()?;
+                    if ![2, 4].contains(&config.compare.len()) {
+                        return Err("--compare expects 2 or 4 integrators, like euler,verlet".to_string());
+                    }
+                }
+                "--snap-grid" => {
+                    config.snap_increment = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&increment: &f32| increment > 0.0)
+                        .ok_or("--snap-grid expects a positive spacing")?;
+                }
+                "--frame-fraction" => {
+                    config.frame_fraction = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&fraction: &f32| fraction > 0.0 && fraction <= 1.0)
+                        .ok_or("--frame-fraction expects a fraction in (0, 1]")?;
+                }
+                "--explosion-radius" => {
+                    config.explosion_radius = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&radius: &f32| radius > 0.0)
+                        .ok_or("--explosion-radius expects a positive distance")?;
+                }
+                "--explosion-energy" => {
+                    config.explosion_energy = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&energy: &f32| energy >= 0.0)
+                        .ok_or("--explosion-energy expects a non-negative energy")?;
+                }
+                "--minimap" => config.minimap = true,
+                "--minimap-size" => {
+                    config.minimap_width = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&width| (16..=MAX_MINIMAP_WIDTH).contains(&width))
+                        .ok_or(format!("--minimap-size expects a width between 16 and {} pixels", MAX_MINIMAP_WIDTH))?;
+                }
+                "--minimap-corner" => {
+                    let name = value()?;
+                    config.minimap_corner = Corner::from_name(&name).ok_or(format!("unknown corner: {}", name))?;
+                }
+                "--density-profile" => config.density_profile = true,
+                "--profile-bins" => {
+                    config.profile_bins = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&bins| (2..=MAX_PROFILE_BINS).contains(&bins))
+                        .ok_or(format!("--profile-bins expects between 2 and {} bins", MAX_PROFILE_BINS))?;
+                }
+                "--profile-interval" => {
+                    config.profile_interval = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&frames| frames > 0)
+                        .ok_or("--profile-interval expects a positive number of frames")?;
+                }
+                "--profile-mass" => config.profile_mass = true,
+                "--log-time" => config.log_time = true,
+                "--scale" => {
+                    let name = value()?;
+                    config.window_scale = WindowScale::from_name(&name).ok_or(format!("unknown scale: {}", name))?;
+                }
+                "--softening-anisotropy" => {
+                    config.softening_anisotropy = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&ratio: &f32| ratio > 0.0)
+                        .ok_or("--softening-anisotropy expects a positive number")?;
+                }
+                "--theta" => {
+                    config.theta = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&theta: &f32| (0.0..=MAX_THETA).contains(&theta))
+                        .ok_or(format!("--theta expects an opening angle between 0 and {}", MAX_THETA))?;
+                }
+                "--tree-depth" => {
+                    config.tree_limits.max_depth = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&depth| (1..=quadtree::DEPTH_LIMIT).contains(&depth))
+                        .ok_or(format!("--tree-depth expects a depth between 1 and {}", quadtree::DEPTH_LIMIT))?;
+                }
+                "--tree-bucket" => {
+                    config.tree_limits.bucket_size = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&size| size > 0)
+                        .ok_or("--tree-bucket expects a positive number of bodies")?;
+                }
+                "--theta-sweep" => config.theta_sweep = true,
+                "--integrator-sweep" => config.integrator_sweep = true,
+                "--tree-build" => config.tree_build = true,
+                "--reuse-forces" => config.reuse_forces = true,
+                "--tree-stats" => config.tree_stats = true,
+                "--json" => config.json = true,
+                "--stream" => {
+                    config.stream = Some(
+                        value()?
+                            .parse()
+                            .ok()
+                            .filter(|&stride| stride > 0)
+                            .ok_or("--stream expects a positive number of steps between frames")?,
+                    );
+                }
+                "--force-exponent" => {
+                    config.force_exponent = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&exponent: &f32| exponent > 0.0 && exponent.is_finite())
+                        .ok_or("--force-exponent expects a positive exponent")?;
+                }
+                "--units" => {
+                    let name = value()?;
+                    config.units = Units::from_name(&name).ok_or(format!("unknown units: {}", name))?;
+                }
+                "--time-unit" => {
+                    let spec = value()?;
+                    let (label, per_unit) = spec.split_once(',').unwrap_or((&spec, "1"));
+                    let per_unit: f32 = per_unit
+                        .parse()
+                        .ok()
+                        .filter(|&per_unit: &f32| per_unit > 0.0 && per_unit.is_finite())
+                        .ok_or("--time-unit expects LABEL[,PER_UNIT] with a positive PER_UNIT")?;
+                    if label.is_empty() {
+                        return Err("--time-unit expects a non-empty label".to_string());
+                    }
+                    // Labels are static so the units stay Copy; this only runs once per argument
+                    time_unit = Some((&*Box::leak(label.to_string().into_boxed_str()), per_unit));
+                }
+                _ => return Err(format!("unknown argument: {}", arg)),
+            }
+        }
+        if config.load.is_some() && config.bodies_json.is_some() {
+            return Err("--load and --bodies-json both set the bodies; pick one".to_string());
+        }
+        if config.json && config.stream.is_some() {
+            return Err("--json and --stream both write to stdout; pick one".to_string());
+        }
+        if let Some((label, per_unit)) = time_unit {
+            config.units = config.units.with_time_unit(label, per_unit);
+        }
+        config.keymap.check()?;
+        Ok(config)
+    }
+}
+
+#[derive(Clone)]
+struct Body {
+    id: u64,
+    pos: Vec2,
+    vel: Vec2,
+    mass: f32,
+    color: u32,
+    /// Drawn as the glowing star at a fixed size
+    central: bool,
+    /// Pulls on the other bodies but is never moved, e.g. for fixed structures
+    is_static: bool,
+    /// 0 for the main population, 1 for the second species
+    species: u8,
+    /// Magnitude of the acceleration from the latest force calculation (before the speed limit)
+    acceleration: f32,
+    /// Colored by its body file, so the color stays put instead of following the mass
+    custom_color: bool,
+}
+
+impl Body {
+    fn new(pos: Vec2, vel: Vec2, mass: f32) -> Self {
+        let id = NEXT_BODY_ID.fetch_add(1, Ordering::Relaxed);
+        Body { id, pos, vel, mass, color: mass_color(mass), central: false, is_static: false, species: 0, acceleration: 0.0, custom_color: false }
+    }
+
+    fn central() -> Self {
+        Body {
+            central: true,
+            ..Body::new(Vec2::ZERO, Vec2::ZERO, CENTRAL_MASS)
+        }
+    }
+
+    fn random(rng: &mut impl Rng, g: f32, config: &Config) -> Self {
+        // Create denser orbital shells
+        let shell = rng.gen_range(0..6);
+        let base_distance = 150.0 * (shell + 1) as f32;
+        let distance = base_distance + rng.gen_range(-30.0..30.0);
+        
+        let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+        let pos = Vec2::new(
+            distance * angle.cos(),
+            distance * angle.sin(),
+        );
+        
+        let vel = initial_velocity(rng, pos, g, config, config.orbit_speed_range, config.eccentricity_range);
+
+        Body::new(
+            pos,
+            vel,
+            config.mass_distribution.sample(rng, MIN_MASS, MAX_MASS),
+        )
+    }
+
+    /// A body on a near-circular orbit between `inner` and `outer`, outside the gap
+    fn ring(rng: &mut impl Rng, g: f32, config: &Config, inner: f32, outer: f32, gap_center: f32, gap_width: f32) -> Self {
+        // Sample uniformly by area, rejecting radii inside the gap
+        let distance = loop {
+            let distance = rng.gen_range(inner * inner..outer * outer).sqrt();
+            if (distance - gap_center).abs() >= gap_width / 2.0 {
+                break distance;
+            }
+        };
+
+        let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+        let pos = Vec2::new(distance * angle.cos(), distance * angle.sin());
+        let vel = initial_velocity(rng, pos, g, config, RING_SPEED_RANGE, RING_ECCENTRICITY_RANGE);
+
+        Body::new(pos, vel, config.mass_distribution.sample(rng, MIN_MASS, MAX_MASS))
+    }
+
+    /// A light body entering from the left wall, heading right to pass the central mass
+    fn focusing(impact_parameter: f32, start_x: f32) -> Self {
+        Body::new(
+            Vec2::new(start_x, impact_parameter),
+            Vec2::new(FOCUSING_SPEED, 0.0),
+            MIN_MASS,
+        )
+    }
+
+    fn update(&mut self, force: Vec2, dt: f32, config: &Config) {
+        if self.is_static {
+            return;
+        }
+        // The speed limit is the one clamp on the physics, and an optional one (see `SpeedLimit`)
+        let acc = config.speed_limit.acceleration(force, self.mass);
+        self.vel += acc * dt;
+        self.limit_speed(config.speed_limit);
+        self.pos += self.vel * dt;
+        self.bounce_off_walls(config.wall_bounce);
+        self.vel *= config.sponge.damping(self.pos, dt);
+        self.cool(config.cooling, dt);
+    }
+
+    /// Damp the radial part of the velocity about the origin (where the central mass sits)
+    /// by `rate` per unit time, leaving the tangential part alone, so eccentric orbits settle
+    /// into circles instead of decaying. Not physical: it stands in for the dissipation that
+    /// flattens a gas disk, and removes energy without touching angular momentum.
+    fn cool(&mut self, rate: f32, dt: f32) {
+        if rate == 0.0 || self.central || self.is_static {
+            return;
+        }
+        let outward = self.pos.normalize_or_zero();
+        let radial = self.vel.dot(outward);
+        self.vel -= outward * radial * (1.0 - (-rate * dt).exp());
+    }
+
+    /// Change the mass, recoloring the body to match
+    fn set_mass(&mut self, mass: f32, species: &Species) {
+        self.mass = mass;
+        if !self.central && !self.custom_color {
+            self.color = if self.species == 0 { mass_color(mass) } else { species.shade(mass) };
+        }
+    }
+
+    fn limit_speed(&mut self, speed_limit: SpeedLimit) {
+        self.vel = speed_limit.velocity(self.vel);
+    }
+
+    fn bounce_off_walls(&mut self, wall_bounce: WallBounce) {
+        let bounds_x = (WIDTH as f32 / 2.0) * SPACE_SCALE;
+        let bounds_y = (HEIGHT as f32 / 2.0) * SPACE_SCALE;
+        
+        bounce(&mut self.pos.x, &mut self.vel.x, bounds_x, wall_bounce);
+        bounce(&mut self.pos.y, &mut self.vel.y, bounds_y, wall_bounce);
+    }
+
+    /// Merge `other` into this body, conserving mass and momentum. A static body
+    /// swallows whatever it merges with and stays where it is, and the merged body
+    /// belongs to the species of the heavier one. Swallowing a central body makes it central.
+    fn absorb(&mut self, other: &Body) {
+        let mass = self.mass + other.mass;
+        let heavier = if other.mass > self.mass { other } else { &*self };
+        let (species, color, custom_color) = (heavier.species, heavier.color, heavier.custom_color);
+        if self.is_static || other.is_static {
+            if !self.is_static {
+                self.pos = other.pos;
+            }
+            self.vel = Vec2::ZERO;
+            self.is_static = true;
+        } else {
+            self.pos = (self.pos * self.mass + other.pos * other.mass) / mass;
+            self.vel = (self.vel * self.mass + other.vel * other.mass) / mass;
+        }
+        self.mass = mass;
+        self.central |= other.central;
+        self.species = species;
+        self.custom_color = custom_color;
+        // The second species and colors from body files are kept
+        self.color = if species == 0 && !custom_color { mass_color(mass) } else { color };
+    }
+
+    fn radius(&self, law: RadiusLaw) -> f32 {
+        if self.central {
+            25.0 // Fixed size for central body
+        } else {
+            law.base * (self.mass / MIN_MASS).powf(law.exponent)
+        }
+    }
+}
+
+/// Orbital velocity around the central mass at `pos`, with the speed factor and outward
+/// component drawn from the given ranges. Without a central body there's nothing to orbit,
+/// so bodies get a random thermal velocity instead, as they do for a hot start. The
+/// configured spin is added on top.
+fn initial_velocity(
+    rng: &mut impl Rng,
+    pos: Vec2,
+    g: f32,
+    config: &Config,
+    speed_range: (f32, f32),
+    eccentricity_range: (f32, f32),
+) -> Vec2 {
+    let vel = if config.central_body && !config.hot {
+        // Calculate orbital velocity with some randomized eccentricity
+        let orbit_speed = (g * CENTRAL_MASS / pos.length()).sqrt() * rng.gen_range(speed_range.0..=speed_range.1);
+        let tangent = Vec2::new(-pos.y, pos.x).normalize();
+        let outward = pos.normalize();
+        (tangent + outward * rng.gen_range(eccentricity_range.0..=eccentricity_range.1)) * orbit_speed
+    } else {
+        Vec2::new(random_gaussian(rng), random_gaussian(rng)) * config.velocity_dispersion
+    };
+
+    // Solid-body rotation on top of the orbits gives the cluster a net spin
+    vel + Vec2::new(-pos.y, pos.x) * config.spin
+}
+
+/// Standard normal sample via the Box-Muller transform
+fn random_gaussian(rng: &mut impl Rng) -> f32 {
+    let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+    let u2: f32 = rng.gen_range(0.0..1.0);
+    (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos()
+}
+
+fn mass_color(mass: f32) -> u32 {
+    // Color based on mass (blue for small, red for large)
+    let t = ((mass - MIN_MASS) / (MAX_MASS - MIN_MASS)).clamp(0.0, 1.0);
+    let r = (t * 255.0) as u32;
+    let g = ((1.0 - t * t) * 200.0) as u32;
+    let b = ((1.0 - t) * 255.0) as u32;
+    (r << 16) | (g << 8) | b
+}
+
+/// Map `t` in [0, 1] onto a blue -> cyan -> yellow -> red heat ramp
+fn heat_color(t: f32) -> u32 {
+    const STOPS: [(f32, f32, f32); 4] = [
+        (0.1, 0.2, 1.0),
+        (0.0, 1.0, 1.0),
+        (1.0, 1.0, 0.0),
+        (1.0, 0.1, 0.0),
+    ];
+    let scaled = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let i = (scaled as usize).min(STOPS.len() - 2);
+    let f = scaled - i as f32;
+    let (a, b) = (STOPS[i], STOPS[i + 1]);
+    let channel = |x: f32, y: f32| ((x + (y - x) * f) * 255.0) as u32;
+    (channel(a.0, b.0) << 16) | (channel(a.1, b.1) << 8) | channel(a.2, b.2)
+}
+
+/// Saturated color for `id`. Stepping the hue by the golden ratio keeps any run of
+/// consecutive ids far apart on the color wheel.
+fn identity_color(id: u64) -> u32 {
+    const GOLDEN_RATIO_CONJUGATE: f64 = 0.618_033_988_749_895;
+    let hue = (id as f64 * GOLDEN_RATIO_CONJUGATE).fract() as f32 * 6.0;
+    let sector = hue as u32;
+    let f = hue - sector as f32;
+    let (lo, hi) = (0.25, 1.0);
+    let (r, g, b) = match sector {
+        0 => (hi, lo + (hi - lo) * f, lo),
+        1 => (hi - (hi - lo) * f, hi, lo),
+        2 => (lo, hi, lo + (hi - lo) * f),
+        3 => (lo, hi - (hi - lo) * f, hi),
+        4 => (lo + (hi - lo) * f, lo, hi),
+        _ => (hi, lo, hi - (hi - lo) * f),
+    };
+    let channel = |x: f32| (x * 255.0) as u32;
+    (channel(r) << 16) | (channel(g) << 8) | channel(b)
+}
+
+/// Colors to draw each body with under the given color mode
+fn body_colors(bodies: &[Body], mode: ColorMode, config: &Config) -> Vec<u32> {
+    match mode {
+        ColorMode::Mass => bodies.iter().map(|body| body.color).collect(),
+        ColorMode::Density => {
+            let counts = neighbor_counts(bodies, config.density_radius);
+            let max = counts.iter().copied().max().unwrap_or(1).max(1) as f32;
+            counts.iter().map(|&count| heat_color(count as f32 / max)).collect()
+        }
+        ColorMode::DensityWave => {
+            // Contrast against the mean rather than the peak, so the arms show up next to a
+            // much denser core. A logistic curve of the log contrast keeps both ends in range.
+            let counts = neighbor_counts(bodies, config.density_radius);
+            let mean = counts.iter().sum::<usize>() as f32 / counts.len().max(1) as f32;
+            bodies
+                .iter()
+                .zip(counts)
+                .map(|(body, count)| {
+                    let contrast = (count as f32 / mean).ln();
+                    let brightness = 1.0 / (1.0 + (-config.wave_contrast * contrast).exp());
+                    scale_color(body.color, 0.1 + 0.9 * brightness)
+                })
+                .collect()
+        }
+        ColorMode::Identity => bodies.iter().map(|body| identity_color(body.id)).collect(),
+        ColorMode::Acceleration => {
+            // The scale ends at the largest acceleration, so the outlier is the red one
+            let max = max_acceleration(bodies).max(f32::MIN_POSITIVE);
+            bodies
+                .iter()
+                .map(|body| heat_color(1.0 + (body.acceleration / max).log10() / ACCELERATION_DECADES))
+                .collect()
+        }
+    }
+}
+
+/// Number of bodies within `radius` of each body, itself included
+fn neighbor_counts(bodies: &[Body], radius: f32) -> Vec<usize> {
+    let grid = SpatialGrid::new(bodies.iter().map(|body| body.pos), radius);
+    bodies.iter().map(|body| grid.neighbors_within(body.pos, radius).len()).collect()
+}
+
+/// `color` with every channel scaled by `factor`
+fn scale_color(color: u32, factor: f32) -> u32 {
+    let channel = |shift: u32| ((((color >> shift) & 0xFF) as f32 * factor) as u32).min(255) << shift;
+    channel(16) | channel(8) | channel(0)
+}
+
+fn max_acceleration(bodies: &[Body]) -> f32 {
+    bodies.iter().map(|body| body.acceleration).fold(0.0, f32::max)
+}
+
+/// Create the central body (unless disabled) followed by the satellites for the configured scenario
+fn generate_bodies(config: &Config) -> Result<Vec<Body>, String> {
+    let mut rng = match config.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let mut bodies = Vec::with_capacity(NUM_BODIES + 1);
+    // The collision brings its own two central masses
+    let collision = matches!(config.scenario, InitialCondition::Collision { .. });
+    if config.central_body && !collision {
+        bodies.push(Body { is_static: config.pin_central, ..Body::central() });
+    }
+
+    let min_separation = config.min_separation.map(|separation| separation.max(config.softening));
+    let crowded = match config.scenario {
+        InitialCondition::Shells => {
+            add_bodies(&mut bodies, &mut rng, NUM_BODIES, min_separation, |rng| Body::random(rng, config.g, config))
+        }
+        InitialCondition::Focusing => {
+            // Spread the stream out along x so it keeps arriving for a while
+            let bounds_x = (WIDTH as f32 / 2.0) * SPACE_SCALE;
+            let bounds_y = (HEIGHT as f32 / 2.0) * SPACE_SCALE;
+            add_bodies(&mut bodies, &mut rng, NUM_BODIES, min_separation, |rng| {
+                Body::focusing(
+                    rng.gen_range(-bounds_y * 0.6..bounds_y * 0.6),
+                    rng.gen_range(-bounds_x..-bounds_x * 0.5),
+                )
+            })
+        }
+        InitialCondition::Ring { inner, outer, gap_center, gap_width } => {
+            add_bodies(&mut bodies, &mut rng, NUM_BODIES, min_separation, |rng| {
+                Body::ring(rng, config.g, config, inner, outer, gap_center, gap_width)
+            })
+        }
+        InitialCondition::Sandbox => 0,
+        InitialCondition::Image => {
+            let path = config.image.as_deref().ok_or("the image scenario needs --image FILE")?;
+            let picture = picture::read_picture(path)?;
+            if picture.brightness.iter().all(|&brightness| brightness == 0.0) {
+                return Err(format!("{} is completely black, so there's nowhere to put bodies", path.display()));
+            }
+            let size = Vec2::new(picture.width as f32, picture.height as f32) * (config.image_width / picture.width as f32);
+            add_bodies(&mut bodies, &mut rng, config.image_bodies, min_separation, |rng| {
+                // Rejection sampling: a spot is kept with a probability equal to its brightness.
+                // Spots right on a central body are skipped, where no orbit is defined.
+                let pos = loop {
+                    let (x, y) = (rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0));
+                    let pos = (Vec2::new(x, y) - 0.5) * size;
+                    if rng.gen_range(0.0..1.0) < picture.sample(x, y) && (!config.central_body || pos.length() > 1.0) {
+                        break pos;
+                    }
+                };
+                let vel = if config.image_orbits {
+                    initial_velocity(rng, pos, config.g, config, config.orbit_speed_range, config.eccentricity_range)
+                } else {
+                    Vec2::new(-pos.y, pos.x) * config.spin
+                };
+                Body::new(pos, vel, config.mass_distribution.sample(rng, MIN_MASS, MAX_MASS))
+            })
+        }
+        InitialCondition::Collision { separation, speed, impact_parameter } => {
+            // Each disk orbits its own center, so build it around the origin and then move it
+            // into place, with opposite bulk velocities to keep the center of mass at rest
+            let disk_config = Config { central_body: true, ..config.clone() };
+            let mut crowded = 0;
+            for side in [-1.0, 1.0] {
+                let center = Vec2::new(side * separation, side * impact_parameter) / 2.0;
+                let bulk = Vec2::new(-side * speed / 2.0, 0.0);
+                bodies.push(Body { pos: center, vel: bulk, ..Body::central() });
+                crowded += add_bodies(&mut bodies, &mut rng, NUM_BODIES / 2, min_separation, |rng| {
+                    let disk = Body::ring(rng, config.g, &disk_config, GALAXY_DISK.0, GALAXY_DISK.1, 0.0, 0.0);
+                    // Light bodies, so the disks trace the tides instead of dominating the cores
+                    Body { pos: disk.pos + center, vel: disk.vel + bulk, mass: MIN_MASS, color: mass_color(MIN_MASS), ..disk }
+                });
+            }
+            crowded
+        }
+    };
+    if crowded > 0 {
+        eprintln!("Warning: {} bodies start closer than the minimum separation (no room left)", crowded);
+    }
+
+    // The second species only differs in mass and color, so any scenario can be split up
+    let species = config.species;
+    if species.fraction > 0.0 {
+        for body in bodies.iter_mut().filter(|body| !body.central && !body.is_static) {
+            if rng.gen_bool(species.fraction) {
+                let mass = rng.gen_range(species.mass_range.0..=species.mass_range.1);
+                *body = Body { mass, color: species.shade(mass), species: 1, ..body.clone() };
+            }
+        }
+    }
+
+    Ok(bodies)
+}
+
+/// Add `count` bodies from `make`. With a minimum separation, candidates that land too
+/// close to an existing body are redrawn, up to `SPAWN_ATTEMPTS` times; the number of bodies
+/// that had to be placed anyway is returned.
+fn add_bodies(
+    bodies: &mut Vec<Body>,
+    rng: &mut StdRng,
+    count: usize,
+    min_separation: Option<f32>,
+    mut make: impl FnMut(&mut StdRng) -> Body,
+) -> usize {
+    let mut crowded = 0;
+    for _ in 0..count {
+        let mut body = make(rng);
+        if let Some(separation) = min_separation {
+            let separation_sq = separation * separation;
+            let too_close = |body: &Body, bodies: &[Body]| {
+                bodies.iter().any(|other| other.pos.distance_squared(body.pos) < separation_sq)
+            };
+            let mut attempts = 1;
+            while too_close(&body, bodies) && attempts < SPAWN_ATTEMPTS {
+                body = make(rng);
+                attempts += 1;
+            }
+            if too_close(&body, bodies) {
+                crowded += 1;
+            }
+        }
+        bodies.push(body);
+    }
+    crowded
+}
+
+/// Merge every pair of bodies closer than their combined radius times `accretion_scale`.
+/// The lower-index body absorbs the other, so the central body stays at index 0. Pairs are
+/// visited in index order, and since a body grows with every merge, that order is part of
+/// the result: it only depends on the order of `bodies`, so reruns merge identically.
+///
+/// A grid with cells as wide as the largest possible contact finds the candidates, so only
+/// nearby pairs get the exact overlap test. Only bodies that absorb others change, and they
+/// come before the ones still to be checked against them, so the grid never goes stale for
+/// the bodies it's asked about.
+fn merge_collisions(bodies: &mut Vec<Body>, accretion_scale: f32, law: RadiusLaw) {
+    let max_radius = bodies.iter().map(|body| body.radius(law)).fold(0.0, f32::max);
+    let cell_size = 2.0 * max_radius * accretion_scale;
+    if cell_size <= 0.0 {
+        return;
+    }
+    let grid = SpatialGrid::new(bodies.iter().map(|body| body.pos), cell_size);
+    let mut absorbed = vec![false; bodies.len()];
+    for i in 0..bodies.len() {
+        if absorbed[i] {
+            continue;
+        }
+        // Take the overlapping bodies after `i` in index order. Merges move and grow the
+        // body, so the candidates are looked up again once it could reach past them.
+        let mut last = i;
+        let mut candidates: Vec<usize> = Vec::new();
+        let mut covered = (bodies[i].pos, -1.0);
+        loop {
+            let body = &bodies[i];
+            let search = (body.radius(law) + max_radius) * accretion_scale;
+            if body.pos.distance(covered.0) + search > covered.1 {
+                // Leave room to grow after the first lookup, which is all most bodies need
+                let radius = if covered.1 < 0.0 { search } else { 2.0 * search };
+                candidates = grid.neighbors_within(body.pos, radius);
+                candidates.sort_unstable();
+                covered = (body.pos, radius);
+            }
+            let start = candidates.partition_point(|&j| j <= last);
+            let next = candidates[start..].iter().copied().find(|&j| {
+                let reach = (body.radius(law) + bodies[j].radius(law)) * accretion_scale;
+                !absorbed[j] && body.pos.distance_squared(bodies[j].pos) < reach * reach
+            });
+            let Some(j) = next else { break };
+            let other = bodies[j].clone();
+            bodies[i].absorb(&other);
+            absorbed[j] = true;
+            last = j;
+        }
+    }
+    let mut index = 0;
+    bodies.retain(|_| {
+        index += 1;
+        !absorbed[index - 1]
+    });
+}
+
+/// Merge up to `count` of the closest pairs of bodies, each body taking part in at most one
+/// merge. The search radius starts small and doubles until enough pairs turn up. Pairs at
+/// the same separation go by their body ids, so the grid's bucket order never decides.
+fn merge_closest_pairs(bodies: &mut Vec<Body>, count: usize) {
+    let max_radius = WIDTH.max(HEIGHT) as f32 * SPACE_SCALE;
+    let positions: Vec<Vec2> = bodies.iter().map(|body| body.pos).collect();
+    let mut radius = 10.0;
+    let pairs = loop {
+        let grid = SpatialGrid::new(positions.iter().copied(), radius);
+        let mut pairs: Vec<(f32, usize, usize)> = Vec::new();
+        for (i, &pos) in positions.iter().enumerate() {
+            for j in grid.neighbors_within(pos, radius).into_iter().filter(|&j| j > i) {
+                pairs.push((pos.distance_squared(positions[j]), i, j));
+            }
+        }
+        if pairs.len() >= count || radius >= max_radius {
+            let ids = |i: usize, j: usize| (bodies[i].id.min(bodies[j].id), bodies[i].id.max(bodies[j].id));
+            pairs.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| ids(a.1, a.2).cmp(&ids(b.1, b.2))));
+            break pairs;
+        }
+        radius *= 2.0;
+    };
+
+    // Greedily take the closest pairs, with the lower index absorbing as in `merge_collisions`
+    let mut used = vec![false; bodies.len()];
+    let mut merged = 0;
+    for (_, i, j) in pairs {
+        if merged == count {
+            break;
+        }
+        if used[i] || used[j] {
+            continue;
+        }
+        used[i] = true;
+        used[j] = true;
+        let other = bodies[j].clone();
+        bodies[i].absorb(&other);
+        bodies[j].mass = 0.0;
+        merged += 1;
+    }
+    bodies.retain(|body| body.mass > 0.0);
+}
+
+/// Kick every body within `radius` of `center` straight outwards (apart from the central
+/// and static ones), with a speed falling off as 1/distance (never closer than `softening`,
+/// or one length unit with the softening off) and scaled so the kicks carry `energy` in
+/// total. Returns how many bodies were hit.
+fn explode(bodies: &mut [Body], center: Vec2, radius: f32, energy: f32, softening: f32) -> usize {
+    let softening = if softening > 0.0 { softening } else { 1.0 };
+    let in_reach = |body: &Body| !body.central && !body.is_static && body.pos.distance_squared(center) < radius * radius;
+    // With kicks of k / d each, the energy is k^2 / 2 * sum(m / d^2)
+    let weight: f32 = bodies
+        .iter()
+        .filter(|body| in_reach(body))
+        .map(|body| body.mass / body.pos.distance_squared(center).max(softening * softening))
+        .sum();
+    if weight == 0.0 {
+        return 0;
+    }
+
+    let k = (2.0 * energy / weight).sqrt();
+    let mut hit = 0;
+    for body in bodies.iter_mut().filter(|body| in_reach(body)) {
+        let offset = body.pos - center;
+        body.vel += offset.normalize_or_zero() * k / offset.length().max(softening);
+        hit += 1;
+    }
+    hit
+}
+
+/// Indices of the non-central, non-static bodies farther than `escape_radius` from the center of mass
+fn escaping_bodies(bodies: &[Body], escape_radius: f32) -> Vec<usize> {
+    let com = diagnostics::center_of_mass(bodies);
+    let radius_sq = escape_radius * escape_radius;
+    (0..bodies.len())
+        .filter(|&i| !bodies[i].central && !bodies[i].is_static && bodies[i].pos.distance_squared(com) > radius_sq)
+        .collect()
+}
+
+/// Distance between the closest two bodies, if any pair is within `radius`
+fn closest_separation(bodies: &[Body], radius: f32) -> Option<f32> {
+    let grid = SpatialGrid::new(bodies.iter().map(|body| body.pos), radius);
+    bodies
+        .iter()
+        .enumerate()
+        .flat_map(|(i, body)| {
+            grid.neighbors_within(body.pos, radius)
+                .into_iter()
+                .filter(move |&j| j > i)
+                .map(move |j| body.pos.distance(bodies[j].pos))
+        })
+        .min_by(f32::total_cmp)
+}
+
+/// Remove escaping bodies that are also unbound, returning how many were removed
+fn remove_escapers(bodies: &mut Vec<Body>, gravity: Gravity, escape_radius: f32) -> usize {
+    let unbound: Vec<u64> = escaping_bodies(bodies, escape_radius)
+        .into_iter()
+        .filter(|&i| diagnostics::body_energy(bodies, i, gravity) > 0.0)
+        .map(|i| bodies[i].id)
+        .collect();
+    bodies.retain(|body| !unbound.contains(&body.id));
+    unbound.len()
+}
+
+fn bounce(pos: &mut f32, vel: &mut f32, bounds: f32, wall_bounce: WallBounce) {
+    if pos.abs() <= bounds {
+        return;
+    }
+
+    match wall_bounce {
+        WallBounce::Damped => {
+            *vel *= -0.5;
+            *pos = pos.signum() * bounds;
+        }
+        WallBounce::Elastic => {
+            // Reflect the distance traveled past the wall back into the domain
+            *vel = -*vel;
+            let overshoot = pos.abs() - bounds;
+            *pos = pos.signum() * (bounds - overshoot).max(-bounds);
+        }
+    }
+}
+
+/// Force on every body from all the others. With a positive `theta` the forces come from
+/// a Barnes-Hut tree walk, which approximates distant groups of bodies by their center of mass,
+/// split as far as `limits` allow, and the shape of the tree comes along with them.
+fn calculate_forces(bodies: &[Body], gravity: Gravity, theta: f32, limits: TreeLimits) -> (Vec<Vec2>, Interactions, Option<TreeStats>) {
+    // Tree cells lump the species together, so uneven couplings need direct summation
+    if theta > 0.0 && gravity.uniform_coupling() {
+        // With their own softening, central bodies stay out of the tree (so no cell mixes the two
+        // softenings) and pull and get pulled directly. There are only ever a few of them.
+        let central: Vec<&Body> = if gravity.uniform_softening() { Vec::new() } else { bodies.iter().filter(|body| body.central).collect() };
+        let satellites: Cow<[Body]> = if central.is_empty() {
+            Cow::Borrowed(bodies)
+        } else {
+            Cow::Owned(bodies.iter().filter(|body| !body.central).cloned().collect())
+        };
+        let tree = QuadTree::new(&satellites, limits);
+        let (mut forces, mut interactions): (Vec<Vec2>, Vec<Interactions>) = (0..satellites.len())
+            .into_par_iter()
+            .map(|i| {
+                let body = &satellites[i];
+                let (mut force, mut interactions) = tree.force(&satellites, i, gravity, theta);
+                for other in &central {
+                    force += pair_force(body, other, gravity);
+                    interactions.direct += 1;
+                }
+                (force + gravity.tidal_force(body.pos, body.mass), interactions)
+            })
+            .unzip();
+        if !central.is_empty() {
+            // Slot the central bodies' forces back in between the satellites', in the original order
+            let mut satellite_forces = forces.into_iter();
+            forces = bodies
+                .iter()
+                .map(|body| {
+                    if !body.central {
+                        return satellite_forces.next().unwrap_or_default();
+                    }
+                    let pulls = bodies.iter().filter(|other| !std::ptr::eq(body, *other)).map(|other| pair_force(body, other, gravity));
+                    pulls.sum::<Vec2>() + gravity.tidal_force(body.pos, body.mass)
+                })
+                .collect();
+            let pairs = central.len() as u64 * bodies.len().saturating_sub(1) as u64;
+            interactions.push(Interactions { direct: pairs, approximated: 0 });
+        }
+        return (forces, interactions.into_iter().fold(Interactions::default(), Add::add), Some(tree.stats()));
+    }
+
+    let pairs = bodies.len() as u64 * bodies.len().saturating_sub(1) as u64;
+    let forces = bodies
+        .par_iter()
+        .map(|body1| {
+            let mut force = gravity.tidal_force(body1.pos, body1.mass);
+
+            for body2 in bodies {
+                if !std::ptr::eq(body1, body2) {
+                    force += pair_force(body1, body2, gravity);
+                }
+            }
+
+            force
+        })
+        .collect();
+    (forces, Interactions { direct: pairs, approximated: 0 }, None)
+}
+
+/// Force that `body2` exerts on `body1`
+fn pair_force(body1: &Body, body2: &Body, gravity: Gravity) -> Vec2 {
+    let gravity = gravity.between(body1, body2);
+    gravity.force(body1.pos, body1.mass, body2.pos, body2.mass) * gravity.coupling(body1.species, body2.species)
+}
+
+/// Future positions of `body` over `steps` timesteps, moving it as a test particle
+/// through the field of `bodies` frozen in their current positions. Only approximate,
+/// since the real field moves, but good enough to aim with.
+fn predict_path(body: &Body, bodies: &[Body], gravity: Gravity, steps: usize, config: &Config) -> Vec<Vec2> {
+    let mut probe = body.clone();
+    let sources: Vec<&Body> = bodies.iter().filter(|other| other.id != body.id).collect();
+    (0..steps)
+        .map(|_| {
+            let force = sources.iter().map(|other| pair_force(&probe, other, gravity)).sum::<Vec2>()
+                + gravity.tidal_force(probe.pos, probe.mass);
+            probe.update(force, config.dt, config);
+            probe.pos
+        })
+        .collect()
+}
+
+/// Closest approach of two bodies over the coming steps
+struct Encounter {
+    separation: f32,
+    /// Time from now until then
+    time: f32,
+    /// Whether they come close enough to touch (or merge, with `--merge`), which ends the forecast
+    collides: bool,
+}
+
+/// Forecast how close `body1` and `body2` will get over `steps` timesteps, moving them
+/// like `predict_path` does but under each other's pull alone: a two-body problem, so only
+/// a guide while the rest of the system is far away.
+fn predict_approach(body1: &Body, body2: &Body, gravity: Gravity, steps: usize, config: &Config) -> Encounter {
+    let scale = if config.merge { config.accretion_scale } else { 1.0 };
+    let contact = (body1.radius(config.radius_law) + body2.radius(config.radius_law)) * scale;
+    let (mut probe1, mut probe2) = (body1.clone(), body2.clone());
+    let mut closest = Encounter { separation: body1.pos.distance(body2.pos), time: 0.0, collides: false };
+    for step in 1..=steps {
+        let (force1, force2) = (pair_force(&probe1, &probe2, gravity), pair_force(&probe2, &probe1, gravity));
+        probe1.update(force1, config.dt, config);
+        probe2.update(force2, config.dt, config);
+        let separation = probe1.pos.distance(probe2.pos);
+        if separation < closest.separation {
+            closest = Encounter { separation, time: step as f32 * config.dt, collides: separation < contact };
+            if closest.collides {
+                break;
+            }
+        }
+    }
+    closest
+}
+
+/// Like `calculate_forces`, but also returns the time derivative of each force (jerk times mass)
+fn calculate_forces_and_jerk(bodies: &[Body], gravity: Gravity) -> Vec<(Vec2, Vec2)> {
+    bodies
+        .par_iter()
+        .map(|body1| {
+            // The tidal field is linear in position, so its jerk is the same tensor times velocity
+            let mut force = gravity.tidal_force(body1.pos, body1.mass);
+            let mut jerk = gravity.tidal_force(body1.vel, body1.mass);
+
+            for body2 in bodies {
+                if std::ptr::eq(body1, body2) {
+                    continue;
+                }
+
+                let r = body2.pos - body1.pos;
+                if r.length_squared() < MIN_SEPARATION_SQ {
+                    continue;
+                }
+
+                let v = body2.vel - body1.vel;
+                let dist = r.length();
+                // The jerk treats the softening as constant, which is exact for the round kernel
+                let dist_sq = dist * dist + gravity.between(body1, body2).softening_sq(r, (body1.pos + body2.pos) / 2.0);
+                let strength = gravity.g * gravity.coupling(body1.species, body2.species) * body1.mass * body2.mass;
+                let falloff = gravity.falloff(dist_sq);
+                force += strength * r / (dist * falloff);
+
+                // d/dt of r / (|r| * (|r|^2 + eps^2)^(p/2))
+                let r_dot_v = r.dot(v);
+                jerk += strength
+                    * (v / (dist * falloff)
+                        - r * r_dot_v * (1.0 / dist.powi(2) + gravity.exponent / dist_sq) / (dist * falloff));
+            }
+
+            (force, jerk)
+        })
+        .collect()
+}
+
+/// What the force calculations of a step cost
+struct ForceWork {
+    time: Duration,
+    /// Interactions summed in the last force calculation of the step
+    interactions: Interactions,
+    /// Shape of the Barnes-Hut tree of that calculation, if it used one
+    tree: Option<TreeStats>,
+}
+
+/// Advance all bodies by one timestep using the configured integrator. The forces line up
+/// with `bodies` by index, so nothing may add or remove bodies until this returns.
+fn step(bodies: &mut [Body], gravity: Gravity, dt: f32, config: &Config, cache: &mut Option<ForceCache>) -> ForceWork {
+    match config.integrator {
+        Integrator::Euler => {
+            let started = Instant::now();
+            let (forces, interactions, tree) = calculate_forces(bodies, gravity, config.theta, config.tree_limits);
+            let time = started.elapsed();
+            record_accelerations(bodies, forces.iter().copied());
+            for (body, force) in bodies.iter_mut().zip(forces) {
+                body.update(force, dt, config);
+            }
+            ForceWork { time, interactions, tree }
+        }
+        Integrator::Verlet => verlet_step(bodies, gravity, dt, config, cache),
+        Integrator::Hermite => hermite_step(bodies, gravity, dt, config),
+    }
+}
+
+/// Keep each body's acceleration from the latest forces, for coloring by it
+fn record_accelerations(bodies: &mut [Body], forces: impl Iterator<Item = Vec2>) {
+    for (body, force) in bodies.iter_mut().zip(forces) {
+        body.acceleration = force.length() / body.mass;
+    }
+}
+
+/// The forces a Verlet step ended with. Its final half kick only changes velocities, so
+/// they're exactly the forces the next step starts from, unless something moved, changed mass
+/// or changed the force law in between.
+struct ForceCache {
+    gravity: Gravity,
+    theta: f32,
+    /// Id, position, mass and species of every body the forces were computed for
+    state: Vec<(u64, Vec2, f32, u8)>,
+    forces: Vec<Vec2>,
+}
+
+impl ForceCache {
+    fn new(bodies: &[Body], gravity: Gravity, theta: f32, forces: Vec<Vec2>) -> Self {
+        ForceCache { gravity, theta, state: ForceCache::state_of(bodies), forces }
+    }
+
+    fn state_of(bodies: &[Body]) -> Vec<(u64, Vec2, f32, u8)> {
+        bodies.iter().map(|body| (body.id, body.pos, body.mass, body.species)).collect()
+    }
+
+    /// The cached forces, if they still apply to `bodies`
+    fn into_forces(self, bodies: &[Body], gravity: Gravity, theta: f32) -> Option<Vec<Vec2>> {
+        let valid = self.gravity == gravity && self.theta == theta && self.state == ForceCache::state_of(bodies);
+        valid.then_some(self.forces)
+    }
+}
+
+fn verlet_step(bodies: &mut [Body], gravity: Gravity, dt: f32, config: &Config, cache: &mut Option<ForceCache>) -> ForceWork {
+    // Half kick, drift with the new velocity, then another half kick with the new forces
+    let limit = config.speed_limit;
+    let started = Instant::now();
+    let start = match cache.take().and_then(|cache| cache.into_forces(bodies, gravity, config.theta)) {
+        Some(forces) => forces,
+        None => calculate_forces(bodies, gravity, config.theta, config.tree_limits).0,
+    };
+    let mut time = started.elapsed();
+    for (body, force) in bodies.iter_mut().zip(start).filter(|(body, _)| !body.is_static) {
+        body.vel += limit.acceleration(force, body.mass) * (dt / 2.0);
+        body.limit_speed(limit);
+        body.pos += body.vel * dt;
+        body.bounce_off_walls(config.wall_bounce);
+        body.vel *= config.sponge.damping(body.pos, dt);
+        body.cool(config.cooling, dt);
+    }
+
+    let started = Instant::now();
+    let (end, interactions, tree) = calculate_forces(bodies, gravity, config.theta, config.tree_limits);
+    time += started.elapsed();
+    record_accelerations(bodies, end.iter().copied());
+    for (body, &force) in bodies.iter_mut().zip(&end).filter(|(body, _)| !body.is_static) {
+        body.vel += limit.acceleration(force, body.mass) * (dt / 2.0);
+        body.limit_speed(limit);
+    }
+    if config.reuse_forces {
+        *cache = Some(ForceCache::new(bodies, gravity, config.theta, end));
+    }
+    ForceWork { time, interactions, tree }
+}
+
+fn hermite_step(bodies: &mut [Body], gravity: Gravity, dt: f32, config: &Config) -> ForceWork {
+    let dt2 = dt * dt;
+    let started = Instant::now();
+    let start = calculate_forces_and_jerk(bodies, gravity);
+    let mut time = started.elapsed();
+
+    // Predict positions and velocities from the Taylor expansion
+    let mut predicted = bodies.to_vec();
+    let limit = config.speed_limit;
+    for (body, &(force, jerk)) in predicted.iter_mut().zip(&start).filter(|(body, _)| !body.is_static) {
+        let acc = limit.acceleration(force, body.mass);
+        let jerk = jerk / body.mass;
+        body.pos += body.vel * dt + acc * (dt2 / 2.0) + jerk * (dt2 * dt / 6.0);
+        body.vel += acc * dt + jerk * (dt2 / 2.0);
+    }
+
+    // Correct using the acceleration and jerk at the predicted state
+    let started = Instant::now();
+    let end = calculate_forces_and_jerk(&predicted, gravity);
+    time += started.elapsed();
+    record_accelerations(bodies, end.iter().map(|&(force, _)| force));
+    for ((body, &(f0, j0)), &(f1, j1)) in bodies.iter_mut().zip(&start).zip(&end).filter(|((body, _), _)| !body.is_static) {
+        let (a0, j0) = (limit.acceleration(f0, body.mass), j0 / body.mass);
+        let (a1, j1) = (limit.acceleration(f1, body.mass), j1 / body.mass);
+        let vel = body.vel + (a0 + a1) * (dt / 2.0) + (j0 - j1) * (dt2 / 12.0);
+        body.pos += (body.vel + vel) * (dt / 2.0) + (a0 - a1) * (dt2 / 12.0);
+        body.vel = vel;
+        body.limit_speed(limit);
+        body.bounce_off_walls(config.wall_bounce);
+        body.vel *= config.sponge.damping(body.pos, dt);
+        body.cool(config.cooling, dt);
+    }
+    let pairs = bodies.len() as u64 * bodies.len().saturating_sub(1) as u64;
+    ForceWork { time, interactions: Interactions { direct: pairs, approximated: 0 }, tree: None }
+}
+
+/// Compare Barnes-Hut forces against direct summation at a range of opening angles. The error
+/// should shrink steadily with theta and vanish (up to rounding) at 0; anything else points
+/// to a bug in the tree walk.
+fn report_force_errors(bodies: &[Body], gravity: Gravity) {
+    let started = Instant::now();
+    let (direct, _, _) = calculate_forces(bodies, gravity, 0.0, TreeLimits::default());
+    println!("Direct summation: {:.2}ms", started.elapsed().as_secs_f64() * 1000.0);
+
+    let scale: f32 = direct.iter().map(|force| force.length_squared()).sum();
+    let mut previous_error = f32::INFINITY;
+    for theta in [1.0, 0.75, 0.5, 0.25, 0.1, 0.0] {
+        let started = Instant::now();
+        let tree = QuadTree::new(bodies, TreeLimits::default());
+        let approximate: Vec<Vec2> = (0..bodies.len())
+            .into_par_iter()
+            .map(|i| tree.force(bodies, i, gravity, theta).0)
+            .collect();
+        let elapsed = started.elapsed();
+
+        // RMS error relative to the RMS force
+        let error: f32 = direct.iter().zip(&approximate).map(|(a, b)| a.distance_squared(*b)).sum();
+        let error = (error / scale.max(f32::MIN_POSITIVE)).sqrt();
+        println!("theta {:.2}: RMS force error {:.3e}, {:.2}ms", theta, error, elapsed.as_secs_f64() * 1000.0);
+        if error > previous_error {
+            println!("Warning: the error grew as theta shrank");
+        }
+        previous_error = error;
+    }
+    if previous_error > 1e-5 {
+        println!("Warning: theta 0 should match direct summation");
+    }
+}
+
+/// Eccentricity of the test orbit in the integrator comparison; close passes are where the
+/// integrators differ most
+const SWEEP_ECCENTRICITY: f32 = 0.5;
+/// Semi-major axis of the test orbit
+const SWEEP_SEMI_MAJOR_AXIS: f32 = 300.0;
+/// Orbits the test runs for
+const SWEEP_PERIODS: f32 = 2.0;
+
+/// Run every integrator at a range of timesteps on a light body orbiting a central mass, and
+/// print the worst energy error each reaches against the time spent stepping. The higher-order
+/// integrators cost more per step but should reach a given error for less time overall.
+fn report_integrator_errors(gravity: Gravity, config: &Config) {
+    let (a, e) = (SWEEP_SEMI_MAJOR_AXIS, SWEEP_ECCENTRICITY);
+    let (central_mass, mass) = (CENTRAL_MASS, MIN_MASS);
+    let total = central_mass + mass;
+    // Start at pericenter, in the center of mass frame
+    let pericenter = a * (1.0 - e);
+    let speed = (gravity.g * total * (1.0 + e) / pericenter).sqrt();
+    let orbit = [
+        Body::new(Vec2::new(-pericenter * mass / total, 0.0), Vec2::new(0.0, -speed * mass / total), central_mass),
+        Body::new(Vec2::new(pericenter * central_mass / total, 0.0), Vec2::new(0.0, speed * central_mass / total), mass),
+    ];
+    let period = std::f32::consts::TAU * (a.powi(3) / (gravity.g * total)).sqrt();
+    let initial = diagnostics::total_energy(&orbit, gravity);
+
+    println!(
+        "Energy error over {} periods of an e = {} orbit (worst relative error, time spent stepping):",
+        SWEEP_PERIODS, e
+    );
+    for integrator in [Integrator::Euler, Integrator::Verlet, Integrator::Hermite] {
+        let config = Config { integrator, ..config.clone() };
+        for dt in [0.4, 0.2, 0.1, 0.05] {
+            let mut bodies = orbit.to_vec();
+            let steps = (SWEEP_PERIODS * period / dt).ceil() as usize;
+            let mut elapsed = Duration::ZERO;
+            let mut worst: f32 = 0.0;
+            for _ in 0..steps {
+                let started = Instant::now();
+                step(&mut bodies, gravity, dt, &config, &mut None);
+                elapsed += started.elapsed();
+                let energy = diagnostics::total_energy(&bodies, gravity);
+                worst = worst.max(((energy - initial) / initial).abs());
+            }
+            println!(
+                "  {:<8} dt {:<6} {:>6} steps: {:.3e} in {:.1}ms",
+                integrator.name(),
+                dt,
+                steps,
+                worst,
+                elapsed.as_secs_f64() * 1000.0
+            );
+        }
+    }
+}
+
+/// Body counts the tree build comparison runs at
+const TREE_BUILD_SIZES: [usize; 3] = [100_000, 200_000, 400_000];
+
+/// Time building the Barnes-Hut tree on one thread against building it in parallel, for
+/// bodies spread evenly over the simulation area, and check both give the same tree
+fn report_tree_build_times() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let (bounds_x, bounds_y) = (WIDTH as f32 / 2.0 * SPACE_SCALE, HEIGHT as f32 / 2.0 * SPACE_SCALE);
+    println!("Tree build on {} threads (best of 5):", parallel::current_num_threads());
+    for count in TREE_BUILD_SIZES {
+        let bodies: Vec<Body> = (0..count)
+            .map(|_| {
+                let pos = Vec2::new(rng.gen_range(-bounds_x..bounds_x), rng.gen_range(-bounds_y..bounds_y));
+                Body::new(pos, Vec2::ZERO, rng.gen_range(MIN_MASS..MAX_MASS))
+            })
+            .collect();
+        let time = |parallel: bool| {
+            (0..5)
+                .map(|_| {
+                    let started = Instant::now();
+                    let tree = QuadTree::build(&bodies, TreeLimits::default(), parallel);
+                    (started.elapsed(), tree.stats().nodes)
+                })
+                .min()
+                .unwrap_or_default()
+        };
+        let ((serial, serial_nodes), (parallel, parallel_nodes)) = (time(false), time(true));
+        println!(
+            "  {:>7} bodies: {:.1}ms serial, {:.1}ms parallel ({:.1}x){}",
+            count,
+            serial.as_secs_f64() * 1000.0,
+            parallel.as_secs_f64() * 1000.0,
+            serial.as_secs_f64() / parallel.as_secs_f64().max(f64::EPSILON),
+            if serial_nodes == parallel_nodes { "" } else { ", but the trees differ!" }
+        );
+    }
+}
+
+/// Wall-clock time spent in each phase of the main loop, for `--profile`
+#[derive(Default)]
+struct PhaseTimes {
+    forces: Duration,
+    /// Everything else in a physics step: integration, merging and escaper removal
+    integration: Duration,
+    rendering: Duration,
+}
+
+impl PhaseTimes {
+    /// Average time per frame (or per step) for each phase over `count` of them
+    fn report(&self, count: u64) -> String {
+        let average = |total: Duration| total.as_secs_f64() * 1000.0 / count.max(1) as f64;
+        format!(
+            "forces {:.2}ms, integration {:.2}ms, rendering {:.2}ms",
+            average(self.forces),
+            average(self.integration),
+            average(self.rendering)
+        )
+    }
+}
+
+/// The bodies together with everything needed to advance them
+struct Simulation {
+    bodies: Vec<Body>,
+    config: Config,
+    gravity_multiplier: f32,
+    /// Physics steps taken so far
+    steps: u64,
+    /// Simulated time elapsed
+    time: f32,
+    /// Escaped bodies dropped so far
+    removed: usize,
+    diagnostics_log: Option<diagnostics::DiagnosticsLog>,
+    closest_approaches: Option<diagnostics::ClosestApproaches>,
+    /// Interactions summed in the latest force calculation
+    interactions: Interactions,
+    /// Shape of the Barnes-Hut tree in the latest force calculation, if it used one
+    tree_stats: Option<TreeStats>,
+    /// Forces the latest Verlet step ended with, kept with `--reuse-forces`
+    force_cache: Option<ForceCache>,
+    /// Time spent stepping, only tracked with `--profile`
+    timings: PhaseTimes,
+}
+
+/// The bodies and progress of a run, kept in memory to go back to
+struct Snapshot {
+    bodies: Vec<Body>,
+    gravity_multiplier: f32,
+    time_multiplier: f32,
+    steps: u64,
+    time: f32,
+}
+
+impl Simulation {
+    fn new(config: Config) -> Result<Self, String> {
+        // Settle on a seed even when none was given, so the settings can be written out and rerun
+        let config = Config { seed: config.seed.or_else(|| Some(rand::random())), ..config };
+        Ok(Simulation::with_bodies(generate_bodies(&config)?, config))
+    }
+
+    /// Start from the bodies in a JSON file: an array of `{"x", "y", "vx", "vy", "mass"}` objects
+    fn from_json_bodies(config: Config, path: &Path) -> Result<Self, String> {
+        Ok(Simulation::with_bodies(particles::read_json(path)?, config))
+    }
+
+    /// Resume from a save file written by `save`
+    fn load(config: Config, path: &Path) -> Result<Self, String> {
+        let state = particles::read_save(path)?;
+        Ok(Simulation {
+            steps: state.steps,
+            time: state.time,
+            gravity_multiplier: state.gravity_multiplier,
+            ..Simulation::with_bodies(state.bodies, config)
+        })
+    }
+
+    /// Write the bodies and progress to the `--save` file, if there is one
+    fn save(&self) {
+        let Some(path) = &self.config.save else {
+            return;
+        };
+        let state = particles::SavedState {
+            steps: self.steps,
+            time: self.time,
+            gravity_multiplier: self.gravity_multiplier,
+            bodies: self.bodies.clone(),
+        };
+        match particles::write_save(path, &state) {
+            Ok(()) if self.config.data_on_stdout() => eprintln!("Saved the state to {}", path.display()),
+            Ok(()) => println!("Saved the state to {}", path.display()),
+            Err(err) => eprintln!("Error: {}", err),
+        }
+    }
+
+    fn with_bodies(mut bodies: Vec<Body>, config: Config) -> Self {
+        // Body files only know the species, not its color, unless they give one outright
+        for body in bodies.iter_mut().filter(|body| body.species == 1 && !body.custom_color) {
+            body.color = config.species.shade(body.mass);
+        }
+        let diagnostics_log = config.diagnostics_log.as_ref().map(|path| {
+            diagnostics::DiagnosticsLog::create(path, config.diagnostics_stride).unwrap_or_else(|err| {
+                eprintln!("Error: couldn't create {}: {}", path.display(), err);
+                std::process::exit(1);
+            })
+        });
+
+        let closest_approaches = (config.closest_approaches > 0)
+            .then(|| diagnostics::ClosestApproaches::new(config.closest_approaches));
+
+        Simulation {
+            bodies,
+            config,
+            gravity_multiplier: 1.0,
+            steps: 0,
+            time: 0.0,
+            removed: 0,
+            diagnostics_log,
+            closest_approaches,
+            interactions: Interactions::default(),
+            tree_stats: None,
+            force_cache: None,
+            timings: PhaseTimes::default(),
+        }
+    }
+
+    /// The current settings as a config file: the options that set up the starting bodies and
+    /// the physics, including anything tuned live, like the gravity and the integrator
+    fn settings(&self) -> toml::Table {
+        // Through the shortest decimal form of the f32, so 0.008 doesn't turn into 0.00800000037997961
+        let float = |value: f32| toml::Value::Float(value.to_string().parse().unwrap_or(value as f64));
+        let floats = |values: &[f32]| toml::Value::Array(values.iter().map(|&value| float(value)).collect());
+        let config = &self.config;
+        let mut settings = toml::Table::new();
+        let mut set = |key: &str, value: toml::Value| {
+            settings.insert(key.to_string(), value);
+        };
+
+        match (&config.load, &config.bodies_json, config.seed) {
+            (Some(path), _, _) => set("load", path.display().to_string().into()),
+            (_, Some(path), _) => set("bodies-json", path.display().to_string().into()),
+            // TOML integers stop at i64::MAX, so larger seeds go in as strings
+            (_, _, Some(seed)) => set("seed", i64::try_from(seed).map_or_else(|_| seed.to_string().into(), Into::into)),
+            _ => {}
+        }
+        match config.scenario {
+            InitialCondition::Shells => set("scenario", "shells".into()),
+            InitialCondition::Focusing => set("scenario", "focusing".into()),
+            InitialCondition::Sandbox => set("scenario", "sandbox".into()),
+            InitialCondition::Ring { inner, outer, gap_center, gap_width } => {
+                set("ring", floats(&[inner, outer, gap_center, gap_width]))
+            }
+            InitialCondition::Collision { separation, speed, impact_parameter } => {
+                set("collision", floats(&[separation, speed, impact_parameter]))
+            }
+            InitialCondition::Image => {
+                if let Some(path) = &config.image {
+                    set("image", path.display().to_string().into());
+                }
+                set("image-bodies", i64::try_from(config.image_bodies).unwrap_or(i64::MAX).into());
+                set("image-width", float(config.image_width));
+                set("image-orbits", config.image_orbits.into());
+            }
+        }
+        if let Some(separation) = config.min_separation {
+            set("min-separation", float(separation));
+        }
+        set("no-central", (!config.central_body).into());
+        set("pin-central", config.pin_central.into());
+        set("hot", config.hot.into());
+        set("velocity-dispersion", float(config.velocity_dispersion));
+        set("spin", float(config.spin));
+        match config.mass_distribution {
+            MassDistribution::Uniform => set("mass-distribution", "uniform".into()),
+            MassDistribution::LogUniform => set("mass-distribution", "log-uniform".into()),
+            MassDistribution::PowerLaw { slope } => set("mass-slope", float(slope)),
+        }
+        set("orbit-speed", floats(&[config.orbit_speed_range.0, config.orbit_speed_range.1]));
+        set("eccentricity", floats(&[config.eccentricity_range.0, config.eccentricity_range.1]));
+        set("species", config.species.fraction.into());
+        set("species-mass", floats(&[config.species.mass_range.0, config.species.mass_range.1]));
+        set("species-color", format!("{:06x}", config.species.color).into());
+        set("coupling", floats(&config.coupling));
+        set("tide", floats(&config.tide));
+        set("integrator", config.integrator.name().into());
+        set("g", float(config.g * self.gravity_multiplier));
+        set("dt", float(config.dt));
+        set("gravity-ramp", i64::try_from(config.gravity_ramp).unwrap_or(i64::MAX).into());
+        set("softening", float(config.softening));
+        if let Some(softening) = config.central_softening {
+            set("central-softening", float(softening));
+        }
+        set("force-exponent", float(config.force_exponent));
+        set("softening-anisotropy", float(config.softening_anisotropy));
+        set("theta", float(config.theta));
+        set("tree-depth", i64::try_from(config.tree_limits.max_depth).unwrap_or(i64::MAX).into());
+        set("tree-bucket", i64::try_from(config.tree_limits.bucket_size).unwrap_or(i64::MAX).into());
+        set("reuse-forces", config.reuse_forces.into());
+        set("elastic-walls", (config.wall_bounce == WallBounce::Elastic).into());
+        set("sponge-width", float(config.sponge.width));
+        set("sponge-strength", float(config.sponge.strength));
+        set("cooling", float(config.cooling));
+        match config.speed_limit {
+            SpeedLimit::Off => set("no-speed-limit", true.into()),
+            SpeedLimit::Velocity(max) => set("max-speed", float(max)),
+            SpeedLimit::Acceleration(max) => set("max-acceleration", float(max)),
+        }
+        set("merge", config.merge.into());
+        set("zero-momentum", config.zero_momentum.into());
+        set("accretion-scale", float(config.accretion_scale));
+        set("body-radius", float(config.radius_law.base));
+        set("radius-exponent", float(config.radius_law.exponent));
+        set("escape-radius", float(config.escape_radius));
+        set("remove-escapers", config.remove_escapers.into());
+        if let Some(max_bodies) = config.max_bodies {
+            set("max-bodies", i64::try_from(max_bodies).unwrap_or(i64::MAX).into());
+        }
+        set("cap-merge-rate", i64::try_from(config.cap_merge_rate).unwrap_or(i64::MAX).into());
+        let units = if config.units.physical { Units::physical() } else { Units::simulation() };
+        set("units", (if units.physical { "physical" } else { "simulation" }).into());
+        if config.units.time_label != units.time_label || config.units.time != units.time {
+            set("time-unit", format!("{},{}", config.units.time_label, config.units.time / units.time).into());
+        }
+        // Rebound keys go last, since a TOML table ends the top-level keys
+        let keys: toml::Table = config
+            .keymap
+            .changed()
+            .into_iter()
+            .map(|(action, keys)| (action.to_string(), keys.into()))
+            .collect();
+        if !keys.is_empty() {
+            set("keys", keys.into());
+        }
+        settings
+    }
+
+    /// Gravity as it acts now, weakened while `--gravity-ramp` is still winding it up
+    fn gravity(&self) -> Gravity {
+        let ramp = self.config.gravity_ramp;
+        let strength = if self.steps < ramp { (self.steps + 1) as f32 / ramp as f32 } else { 1.0 };
+        self.config.gravity(self.gravity_multiplier * strength)
+    }
+
+    /// Advance everything by one physics step of length `dt`, always in this order:
+    ///
+    /// 1. compute the forces and integrate with them (`step`), over one fixed set of bodies
+    /// 2. merge touching bodies, drop escapers and cap the body count, which changes that set
+    /// 3. with `--zero-momentum`, take out whatever net momentum the step left behind
+    /// 4. count the step, then record diagnostics of the bodies as they now are
+    ///
+    /// Anything that adds or removes bodies has to come after the integration, so a force is
+    /// never applied to a different body than the one it was computed for; the next step
+    /// computes fresh forces for the new set (a cached Verlet force is only reused when the
+    /// bodies are unchanged).
+    fn step(&mut self, dt: f32) {
+        let gravity = self.gravity();
+        let started = self.config.profile.then(Instant::now);
+        let work = step(&mut self.bodies, gravity, dt, &self.config, &mut self.force_cache);
+        self.interactions = work.interactions;
+        self.tree_stats = work.tree;
+        if self.config.merge {
+            merge_collisions(&mut self.bodies, self.config.accretion_scale, self.config.radius_law);
+        }
+        if self.config.remove_escapers {
+            self.removed += remove_escapers(&mut self.bodies, gravity, self.config.escape_radius);
+        }
+        if let Some(max_bodies) = self.config.max_bodies {
+            let excess = self.bodies.len().saturating_sub(max_bodies);
+            if excess > 0 {
+                merge_closest_pairs(&mut self.bodies, excess.min(self.config.cap_merge_rate));
+            }
+        }
+        if self.config.zero_momentum {
+            remove_net_momentum(&mut self.bodies);
+        }
+        self.steps += 1;
+        self.time += dt;
+        if let Some(started) = started {
+            self.timings.forces += work.time;
+            self.timings.integration += started.elapsed().saturating_sub(work.time);
+        }
+
+        if let Some(log) = &mut self.diagnostics_log {
+            if let Err(err) = log.record(self.steps, self.time, &self.bodies, gravity) {
+                eprintln!("Error: failed to write diagnostics, logging stopped: {}", err);
+                self.diagnostics_log = None;
+            }
+        }
+        if let Some(approaches) = &mut self.closest_approaches {
+            approaches.record(self.steps, &self.bodies);
+        }
+    }
+}
+
+/// Shift the velocities of the moving bodies by the same amount so their total momentum is
+/// zero. Escaper removal and the speed limit (and rounding) leave a small net momentum behind
+/// that would otherwise carry the whole system off-center over a long run.
+fn remove_net_momentum(bodies: &mut [Body]) {
+    let mass: f32 = bodies.iter().filter(|body| !body.is_static).map(|body| body.mass).sum();
+    if mass == 0.0 {
+        return;
+    }
+    let drift = diagnostics::momentum(bodies) / mass;
+    for body in bodies.iter_mut().filter(|body| !body.is_static) {
+        body.vel -= drift;
+    }
+}
+
+/// Parse a `MIN,MAX` pair of numbers
+fn parse_range(value: &str, flag: &str) -> Result<(f32, f32), String> {
+    let error = || format!("{} expects MIN,MAX", flag);
+    let (min, max) = value.split_once(',').ok_or_else(error)?;
+    let min: f32 = min.trim().parse().map_err(|_| error())?;
+    let max: f32 = max.trim().parse().map_err(|_| error())?;
+    if min > max {
+        return Err(format!("{}: MIN must not be greater than MAX", flag));
+    }
+    Ok((min, max))
+}
+
+/// Parse an `RRGGBB` hex color, with or without a leading `#`
+fn parse_color(value: &str) -> Option<u32> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/// Exponential moving average of frame times that never drifts far from real time
+struct FrameSmoother {
+    average: Option<f32>,
+    /// Real time that has elapsed but not yet been handed out (negative when ahead)
+    lag: f32,
+}
+
+impl FrameSmoother {
+    fn new() -> Self {
+        FrameSmoother { average: None, lag: 0.0 }
+    }
+
+    fn smooth(&mut self, elapsed: f32) -> f32 {
+        let average = match self.average {
+            Some(average) => average + (elapsed - average) * FRAME_SMOOTHING,
+            None => elapsed,
+        };
+        self.average = Some(average);
+
+        // Hand out any lag beyond the allowed window so we stay roughly real-time
+        self.lag += elapsed - average;
+        let excess = self.lag - self.lag.clamp(-MAX_FRAME_LAG, MAX_FRAME_LAG);
+        self.lag -= excess;
+        (average + excess).max(0.0)
+    }
+}
+
+/// Times the orbit of one body by its pericenter passages: the local minima of its distance
+/// to the heaviest central body (or to the center of mass without one)
+struct PeriodTimer {
+    id: u64,
+    /// The latest few samples of simulated time and distance, oldest first
+    recent: VecDeque<(f32, f32)>,
+    /// Times of the pericenter passages seen so far, oldest first
+    passages: VecDeque<f32>,
+}
+
+impl PeriodTimer {
+    fn new(id: u64) -> Self {
+        PeriodTimer { id, recent: VecDeque::with_capacity(3), passages: VecDeque::with_capacity(PERIOD_PASSAGES) }
+    }
+
+    /// Take a sample after a step. Returns false once the body is gone.
+    fn record(&mut self, bodies: &[Body], time: f32) -> bool {
+        let Some(body) = bodies.iter().find(|body| body.id == self.id) else {
+            return false;
+        };
+        let center = match bodies.iter().filter(|other| other.central && other.id != self.id).max_by(|a, b| a.mass.total_cmp(&b.mass)) {
+            Some(central) => central.pos,
+            None => diagnostics::center_of_mass(bodies),
+        };
+        // Going back in time (a restored snapshot) invalidates everything seen so far
+        if self.recent.back().is_some_and(|&(last, _)| time <= last) {
+            *self = PeriodTimer::new(self.id);
+        }
+        if self.recent.len() == 3 {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((time, body.pos.distance(center)));
+
+        if let [(t0, d0), (t1, d1), (t2, d2)] = self.recent.make_contiguous()[..] {
+            if d1 < d0 && d1 <= d2 {
+                // Place the minimum between the samples with a parabola through the three of them
+                let curvature = d0 - 2.0 * d1 + d2;
+                let offset = if curvature > 0.0 { 0.5 * (d0 - d2) / curvature } else { 0.0 };
+                let spacing = if offset < 0.0 { t1 - t0 } else { t2 - t1 };
+                if self.passages.len() == PERIOD_PASSAGES {
+                    self.passages.pop_front();
+                }
+                self.passages.push_back(t1 + offset * spacing);
+            }
+        }
+        true
+    }
+
+    /// The mean time between the recorded passages, if they've come at a steady rhythm
+    /// (within `PERIOD_TOLERANCE` of each other) and the body hasn't stopped coming back
+    fn period(&self) -> Option<f32> {
+        if self.passages.len() < 2 {
+            return None;
+        }
+        let intervals: Vec<f32> = self.passages.iter().zip(self.passages.iter().skip(1)).map(|(a, b)| b - a).collect();
+        let mean = intervals.iter().sum::<f32>() / intervals.len() as f32;
+        let steady = intervals.iter().all(|interval| (interval - mean).abs() <= PERIOD_TOLERANCE * mean);
+        let now = self.recent.back().map_or(0.0, |&(time, _)| time);
+        let overdue = now - self.passages[self.passages.len() - 1] > 2.0 * mean;
+        (steady && !overdue).then_some(mean)
+    }
+
+    /// Whether no period can be given yet, only because too few passages have been seen
+    fn measuring(&self) -> bool {
+        self.passages.len() < 2
+    }
+}
+
+/// Recent world-space positions of the tracked bodies, keyed by body id
+struct Trails {
+    length: usize,
+    points: HashMap<u64, VecDeque<Vec2>>,
+    /// The camera at each recorded position, oldest first, for drawing trails fixed to the screen
+    cameras: VecDeque<Camera>,
+}
+
+impl Trails {
+    /// Track all bodies, or only the `count` heaviest ones
+    fn new(bodies: &[Body], length: usize, count: Option<usize>) -> Self {
+        let mut tracked: Vec<&Body> = bodies.iter().collect();
+        if let Some(count) = count {
+            tracked.sort_by(|a, b| b.mass.total_cmp(&a.mass));
+            tracked.truncate(count);
+        }
+        // The trails grow as positions come in, rather than reserving `length` for every body
+        Trails {
+            length,
+            points: tracked.into_iter().map(|body| (body.id, VecDeque::new())).collect(),
+            cameras: VecDeque::new(),
+        }
+    }
+
+    /// Forget the recorded positions, keeping the same bodies tracked
+    fn clear(&mut self) {
+        self.points.values_mut().for_each(VecDeque::clear);
+        self.cameras.clear();
+    }
+
+    /// Append the current position of every tracked body, forgetting bodies that are gone
+    fn record(&mut self, bodies: &[Body], camera: Camera) {
+        if self.cameras.len() == self.length {
+            self.cameras.pop_front();
+        }
+        self.cameras.push_back(camera);
+        let positions: HashMap<u64, Vec2> = bodies.iter().map(|body| (body.id, body.pos)).collect();
+        self.points.retain(|id, _| positions.contains_key(id));
+        for (id, trail) in &mut self.points {
+            if trail.len() == self.length {
+                trail.pop_front();
+            }
+            trail.push_back(positions[id]);
+        }
+    }
+}
+
+/// How often each pixel had a body in it over the frames since the last reset, for the
+/// stroboscopic view. Unlike trails nothing fades, so orbits that keep coming back build up.
+struct Strobe {
+    counts: Vec<f32>,
+    frames: u32,
+    /// The view the counts were taken in; they're screen pixels, so they only add up while it holds
+    camera: Option<Camera>,
+}
+
+impl Strobe {
+    fn new() -> Self {
+        Strobe { counts: vec![0.0; WIDTH * HEIGHT], frames: 0, camera: None }
+    }
+
+    fn reset(&mut self) {
+        self.counts.fill(0.0);
+        self.frames = 0;
+        self.camera = None;
+    }
+
+    /// Count the pixel under every body, starting over when the camera has moved
+    fn record(&mut self, bodies: &[Body], camera: Camera) {
+        if self.camera != Some(camera) {
+            self.reset();
+            self.camera = Some(camera);
+        }
+        for body in bodies {
+            let screen = camera.world_to_screen(body.pos);
+            if (0.0..WIDTH as f32).contains(&screen.x) && (0.0..HEIGHT as f32).contains(&screen.y) {
+                self.counts[screen.y as usize * WIDTH + screen.x as usize] += 1.0;
+            }
+        }
+        self.frames += 1;
+    }
+
+    /// Draw the occupancy on a log scale on the heat ramp, full at the busiest percent of the
+    /// visited pixels (so a central mass sitting on one pixel doesn't dim everything else).
+    /// `brightness` sets how far up that scale rarely visited pixels come.
+    fn draw(&self, buffer: &mut [u32], brightness: f32) {
+        let mut visited: Vec<f32> = self.counts.par_iter().copied().filter(|&count| count > 0.0).collect();
+        if visited.is_empty() {
+            return;
+        }
+        let busy = visited.len() * 99 / 100;
+        let (_, &mut full, _) = visited.select_nth_unstable_by(busy, f32::total_cmp);
+        let norm = (1.0 + brightness).ln();
+        buffer.par_chunks_mut(WIDTH).zip(self.counts.par_chunks(WIDTH)).for_each(|(row, counts)| {
+            for (x, &count) in counts.iter().enumerate().filter(|(_, &count)| count > 0.0) {
+                let t = ((1.0 + brightness * count / full).ln() / norm).min(1.0);
+                add_pixel(row, x as i32, 0, heat_color(t), t);
+            }
+        });
+    }
+}
+
+/// Collects the characters typed into the window so the console can read them
+struct TypedChars(Rc<RefCell<String>>);
+
+impl minifb::InputCallback for TypedChars {
+    fn add_char(&mut self, uni_char: u32) {
+        if let Some(c) = char::from_u32(uni_char).filter(|c| !c.is_control()) {
+            self.0.borrow_mut().push(c);
+        }
+    }
+}
+
+/// Text console for changing parameters while the simulation runs (opened with `)
+struct Console {
+    open: bool,
+    input: String,
+    /// Result or error from the last command
+    message: String,
+}
+
+impl Console {
+    fn new() -> Self {
+        Console {
+            open: false,
+            input: String::new(),
+            message: String::new(),
+        }
+    }
+}
+
+const CONSOLE_HELP: &str = "commands: g MULT, speed MULT, dt STEP, softening EPS, spawn COUNT [MASS]";
+
+/// Parse and apply a console command, returning a message describing the result
+fn run_command(line: &str, sim: &mut Simulation, time_multiplier: &mut f32) -> Result<String, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or("");
+    let args: Vec<&str> = words.collect();
+    let number = |i: usize, usage: &str| -> Result<f32, String> {
+        args.get(i)
+            .and_then(|arg| arg.parse().ok())
+            .filter(|value: &f32| value.is_finite())
+            .ok_or(format!("usage: {}", usage))
+    };
+
+    match command {
+        "g" => {
+            let multiplier = number(0, "g MULTIPLIER")?;
+            if multiplier < 0.0 {
+                return Err("gravity can't be negative".to_string());
+            }
+            sim.gravity_multiplier = multiplier;
+            Ok(format!("gravity set to {:.2}x", sim.gravity_multiplier))
+        }
+        "speed" => {
+            let multiplier = number(0, "speed MULTIPLIER")?;
+            if multiplier <= 0.0 {
+                return Err("speed must be positive".to_string());
+            }
+            *time_multiplier = multiplier;
+            Ok(format!("speed set to {:.2}x", time_multiplier))
+        }
+        "dt" => {
+            let dt = number(0, "dt STEP")?;
+            if dt <= 0.0 {
+                return Err("dt must be positive".to_string());
+            }
+            sim.config.dt = dt;
+            Ok(format!("timestep set to {}", dt))
+        }
+        "softening" => {
+            let softening = number(0, "softening EPS")?;
+            if softening < 0.0 {
+                return Err("softening can't be negative".to_string());
+            }
+            sim.config.softening = softening;
+            Ok(format!("softening set to {}", softening))
+        }
+        "spawn" => {
+            let count: usize = args
+                .first()
+                .and_then(|arg| arg.parse().ok())
+                .ok_or("usage: spawn COUNT [MASS]")?;
+            if count > MAX_SPAWN {
+                return Err(format!("can't spawn more than {} bodies at once", MAX_SPAWN));
+            }
+            let mass = if args.len() > 1 { Some(number(1, "spawn COUNT [MASS]")?) } else { None };
+            if mass.is_some_and(|mass| mass <= 0.0) {
+                return Err("mass must be positive".to_string());
+            }
+            let config = &sim.config;
+            let mut rng = rand::thread_rng();
+            sim.bodies.extend((0..count).map(|_| {
+                let body = Body::random(&mut rng, config.g, config);
+                match mass {
+                    Some(mass) => Body { mass, color: mass_color(mass), ..body },
+                    None => body,
+                }
+            }));
+            Ok(format!("spawned {} bodies", count))
+        }
+        "help" | "" => Ok(CONSOLE_HELP.to_string()),
+        _ => Err(format!("unknown command '{}' ({})", command, CONSOLE_HELP)),
+    }
+}
+
+/// Print the heaviest bodies as a text table to stdout
+fn print_body_table(bodies: &[Body], limit: usize, units: Units) {
+    let mut sorted: Vec<&Body> = bodies.iter().collect();
+    sorted.sort_by(|a, b| b.mass.total_cmp(&a.mass));
+
+    println!(
+        "mass in {}, positions in {}, velocities in {}/{}",
+        units.mass_label, units.length_label, units.length_label, units.time_label
+    );
+    println!(
+        "{:>6} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "id", "mass", "x", "y", "vx", "vy", "speed"
+    );
+    for body in sorted.iter().take(limit) {
+        let pos = body.pos * units.length;
+        let vel = body.vel * units.velocity();
+        println!(
+            "{:>6} {:>10.4} {:>10.3} {:>10.3} {:>10.3} {:>10.3} {:>10.3}",
+            body.id,
+            body.mass * units.mass,
+            pos.x,
+            pos.y,
+            vel.x,
+            vel.y,
+            vel.length()
+        );
+    }
+    if sorted.len() > limit {
+        println!("... {} lighter bodies not shown", sorted.len() - limit);
+    }
+}
+
+/// Print the `limit` most bound and `limit` least bound bodies to stdout, by their kinetic
+/// plus potential energy against the rest of the system: the ones sinking into the core and
+/// the ones on their way out. Central and static bodies are left out.
+fn print_binding_table(bodies: &[Body], gravity: Gravity, limit: usize, units: Units) {
+    let mut energies: Vec<(&Body, f32)> = (0..bodies.len())
+        .into_par_iter()
+        .filter(|&i| !bodies[i].central && !bodies[i].is_static)
+        .map(|i| (&bodies[i], diagnostics::body_energy(bodies, i, gravity)))
+        .collect();
+    energies.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    if units.physical {
+        println!("mass in {0}, energy in {0} {1}^2/{2}^2 (per mass: {1}^2/{2}^2)", units.mass_label, units.length_label, units.time_label);
+    } else {
+        println!("mass and energy in simulation units");
+    }
+    println!("{:>14} {:>6} {:>10} {:>12} {:>10}", "", "id", "mass", "energy", "per mass");
+    // The least bound from the far end, so they don't repeat the most bound with few bodies
+    let least = energies.len().saturating_sub(limit).max(limit.min(energies.len()));
+    let most = energies[..limit.min(energies.len())].iter().map(|row| ("most bound", row));
+    let rows = most.chain(energies[least..].iter().rev().map(|row| ("least bound", row)));
+    for (label, (body, energy)) in rows {
+        println!(
+            "{:>14} {:>6} {:>10.4} {:>12.4e} {:>10.4e}{}",
+            label,
+            body.id,
+            body.mass * units.mass,
+            energy * units.energy(),
+            energy / body.mass * units.velocity() * units.velocity(),
+            if *energy > 0.0 { "  unbound" } else { "" }
+        );
+    }
+}
+
+/// The part of the world shown on screen
+#[derive(Clone, Copy, PartialEq)]
+struct Camera {
+    /// World position at the middle of the screen
+    center: Vec2,
+    /// Magnification relative to the default view of the whole simulation area
+    zoom: f32,
+}
+
+impl Camera {
+    fn new() -> Self {
+        Camera { center: Vec2::ZERO, zoom: 1.0 }
+    }
+
+    /// Screen pixels per world unit
+    fn scale(&self) -> f32 {
+        self.zoom / SPACE_SCALE
+    }
+
+    fn world_to_screen(&self, pos: Vec2) -> Vec2 {
+        (pos - self.center) * self.scale() + Vec2::new(WIDTH as f32 / 2.0, HEIGHT as f32 / 2.0)
+    }
+
+    fn screen_to_world(&self, screen: Vec2) -> Vec2 {
+        (screen - Vec2::new(WIDTH as f32 / 2.0, HEIGHT as f32 / 2.0)) / self.scale() + self.center
+    }
+
+    /// Zoom by `factor` while keeping the world position under `screen` in place
+    fn zoom_at(&mut self, screen: Vec2, factor: f32) {
+        let anchor = self.screen_to_world(screen);
+        self.zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+        self.center += anchor - self.screen_to_world(screen);
+    }
+
+    /// The view that just fits the bounding box of `points`, leaving `margin` of the screen
+    /// free on each side
+    fn framing(points: impl Iterator<Item = Vec2>, margin: f32) -> Self {
+        let (min, max) = points.fold((Vec2::splat(f32::INFINITY), Vec2::splat(f32::NEG_INFINITY)), |(min, max), pos| {
+            (min.min(pos), max.max(pos))
+        });
+        if !min.is_finite() || !max.is_finite() {
+            return Camera::new();
+        }
+
+        let size = (max - min).max(Vec2::splat(MIN_FRAME_SIZE));
+        let usable = 1.0 - 2.0 * margin;
+        let zoom = (WIDTH as f32 * usable / size.x).min(HEIGHT as f32 * usable / size.y) * SPACE_SCALE;
+        Camera { center: (min + max) / 2.0, zoom: zoom.clamp(MIN_ZOOM, MAX_ZOOM) }
+    }
+
+    /// Top-left and bottom-right corners of the visible area in world space
+    fn visible(&self) -> (Vec2, Vec2) {
+        (self.screen_to_world(Vec2::ZERO), self.screen_to_world(Vec2::new(WIDTH as f32, HEIGHT as f32)))
+    }
+}
+
+/// Positions of the `fraction` of bodies closest to the center of mass, so a few ejected
+/// bodies don't make the framing zoom out on mostly empty space
+fn framed_bodies(bodies: &[Body], fraction: f32) -> impl Iterator<Item = Vec2> {
+    let com = diagnostics::center_of_mass(bodies);
+    let mut positions: Vec<Vec2> = bodies.iter().map(|body| body.pos).collect();
+    positions.sort_by(|a, b| a.distance_squared(com).total_cmp(&b.distance_squared(com)));
+    positions.truncate((positions.len() as f32 * fraction).ceil() as usize);
+    positions.into_iter()
+}
+
+/// Mouse position in buffer pixels, accounting for the window being resized. `MouseMode::Clamp`
+/// pins a pointer outside the window to its edge, `MouseMode::Discard` gives none.
+fn mouse_screen_pos(window: &Window, mode: MouseMode) -> Option<Vec2> {
+    let (x, y) = window.get_mouse_pos(mode)?;
+    let (width, height) = window.get_size();
+    Some(Vec2::new(
+        x * WIDTH as f32 / width.max(1) as f32,
+        y * HEIGHT as f32 / height.max(1) as f32,
+    ))
+}
+
+/// Add `color` scaled by `intensity` onto the pixel, saturating each channel
+fn add_pixel(buffer: &mut [u32], x: i32, y: i32, color: u32, intensity: f32) {
+    if x < 0 || x >= WIDTH as i32 || y < 0 || y >= HEIGHT as i32 {
+        return;
+    }
+
+    let idx = y as usize * WIDTH + x as usize;
+    let existing = buffer[idx];
+    let channel = |shift: u32| {
+        let added = ((color >> shift) & 0xFF) as f32 * intensity;
+        (((existing >> shift) & 0xFF) + added as u32).min(255) << shift
+    };
+    buffer[idx] = channel(16) | channel(8) | channel(0);
+}
+
+/// Draw all bodies, the central ones last so their glow goes on top
+fn draw_bodies(buffer: &mut [u32], camera: &Camera, bodies: &[Body], colors: &[u32], law: RadiusLaw, lod_radius: f32, glow: &[GlowLayer]) {
+    for (body, &color) in bodies.iter().zip(colors).filter(|(body, _)| !body.central) {
+        draw_body(buffer, camera, body, color, law, lod_radius);
+    }
+    for body in bodies.iter().filter(|body| body.central) {
+        let screen = camera.world_to_screen(body.pos);
+        let radius = body.radius(law) * camera.scale();
+        let layers: Vec<GlowLayer> =
+            glow.iter().map(|layer| GlowLayer { radius: layer.radius * radius, intensity: layer.intensity }).collect();
+        draw_circle(buffer, screen, radius, 0xFFAA33, &layers);
+    }
+}
+
+/// The thin glow around satellites
+fn satellite_glow(radius: f32) -> [GlowLayer; 1] {
+    [GlowLayer { radius: radius + 2.0, intensity: 1.0 }]
+}
+
+/// Draw a satellite, falling back to a faint pixel when it's too small to be worth a full circle
+fn draw_body(buffer: &mut [u32], camera: &Camera, body: &Body, color: u32, law: RadiusLaw, lod_radius: f32) {
+    let screen = camera.world_to_screen(body.pos);
+    let screen_radius = body.radius(law) * camera.scale();
+    if screen_radius < lod_radius {
+        add_pixel(buffer, screen.x as i32, screen.y as i32, color, 0.5);
+    } else {
+        draw_circle(buffer, screen, screen_radius, color, &satellite_glow(screen_radius));
+    }
+}
+
+/// Additively draw a line between two screen positions
+fn draw_line(buffer: &mut [u32], from: Vec2, to: Vec2, color: u32, intensity: f32, style: LineStyle) {
+    // Walk one pixel at a time along the longer axis and fill a span across the other.
+    // Swapping coordinates lets the same loop handle steep and shallow lines.
+    let delta = to - from;
+    let steep = delta.y.abs() > delta.x.abs();
+    let (major, minor, start_major, start_minor) = if steep {
+        (delta.y, delta.x, from.y, from.x)
+    } else {
+        (delta.x, delta.y, from.x, from.y)
+    };
+    let steps = major.abs().ceil().max(1.0) as usize;
+    let direction = if major < 0.0 { -1.0 } else { 1.0 };
+    let slope = if major == 0.0 { 0.0 } else { minor / major.abs() };
+    // Span across the minor axis that gives the requested perpendicular width
+    let half_span = style.width * (1.0 + slope * slope).sqrt() / 2.0;
+
+    for i in 0..steps {
+        let along = (start_major + direction * i as f32).floor() as i32;
+        let center = start_minor + slope * i as f32;
+        let (low, high) = (center - half_span, center + half_span);
+        for across in low.floor() as i32..high.ceil() as i32 {
+            let coverage = if style.antialias {
+                (high.min(across as f32 + 1.0) - low.max(across as f32)).clamp(0.0, 1.0)
+            } else {
+                // Whole pixels whose centers fall inside the span
+                let pixel_center = across as f32 + 0.5;
+                if low < pixel_center && pixel_center <= high { 1.0 } else { 0.0 }
+            };
+            if coverage > 0.0 {
+                let (x, y) = if steep { (across, along) } else { (along, across) };
+                add_pixel(buffer, x, y, color, intensity * coverage);
+            }
+        }
+    }
+}
+
+/// Round a world position to the nearest point of a grid with spacing `increment`
+fn snap_to_grid(pos: Vec2, increment: f32) -> Vec2 {
+    (pos / increment).round() * increment
+}
+
+/// Draw faint lines through the visible points of the snap grid
+fn draw_snap_grid(buffer: &mut [u32], camera: &Camera, increment: f32, style: LineStyle) {
+    if increment * camera.scale() < MIN_GRID_SPACING {
+        return;
+    }
+    let (top_left, bottom_right) = camera.visible();
+    let first = (top_left / increment).ceil();
+    let last = (bottom_right / increment).floor();
+    for i in first.x as i64..=last.x as i64 {
+        let x = camera.world_to_screen(Vec2::new(i as f32 * increment, 0.0)).x;
+        draw_line(buffer, Vec2::new(x, 0.0), Vec2::new(x, HEIGHT as f32), GRID_COLOR, 1.0, style);
+    }
+    for i in first.y as i64..=last.y as i64 {
+        let y = camera.world_to_screen(Vec2::new(0.0, i as f32 * increment)).y;
+        draw_line(buffer, Vec2::new(0.0, y), Vec2::new(WIDTH as f32, y), GRID_COLOR, 1.0, style);
+    }
+}
+
+/// Draw a `+` of half-size `size` pixels centered on `screen`
+fn draw_cross(buffer: &mut [u32], screen: Vec2, size: f32, color: u32, style: LineStyle) {
+    draw_line(buffer, screen - Vec2::X * size, screen + Vec2::X * size, color, 1.0, style);
+    draw_line(buffer, screen - Vec2::Y * size, screen + Vec2::Y * size, color, 1.0, style);
+}
+
+/// Draw a predicted path from `start` as a faint line that fades out into the future
+fn draw_path(buffer: &mut [u32], camera: &Camera, start: Vec2, path: &[Vec2], color: u32, style: LineStyle) {
+    let mut last = camera.world_to_screen(start);
+    for (i, point) in path.iter().enumerate() {
+        let screen = camera.world_to_screen(*point);
+        let intensity = 0.6 * (1.0 - i as f32 / path.len() as f32);
+        draw_line(buffer, last, screen, color, intensity, style);
+        last = screen;
+    }
+}
+
+/// Index of the body under `pos` (in world space), preferring the closest one
+fn body_at(bodies: &[Body], camera: &Camera, pos: Vec2, law: RadiusLaw) -> Option<usize> {
+    let select_radius = SELECT_RADIUS / camera.scale();
+    bodies
+        .iter()
+        .enumerate()
+        .map(|(i, body)| (i, body.pos.distance(pos) - body.radius(law).max(select_radius)))
+        .filter(|&(_, gap)| gap <= 0.0)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(i, _)| i)
+}
+
+/// Draw each trail as a polyline that fades out towards its oldest position, in the color its
+/// body is drawn with. Trails go through the current camera, so they stay attached to the
+/// world while the camera moves, or else (`world_fixed` off) each point through the camera it
+/// was recorded with, which leaves them fixed on screen like a long exposure.
+fn draw_trails(
+    buffer: &mut [u32],
+    camera: &Camera,
+    trails: &Trails,
+    bodies: &[Body],
+    colors: &[u32],
+    style: LineStyle,
+    world_fixed: bool,
+) {
+    let colors: HashMap<u64, u32> = bodies.iter().zip(colors).map(|(body, &color)| (body.id, color)).collect();
+    for (id, trail) in &trails.points {
+        // A scrubbed frame can be missing bodies that are tracked now
+        let Some(&color) = colors.get(id) else {
+            continue;
+        };
+        // Every tracked trail is recorded together with the cameras, so they line up from the end
+        let cameras = trails.cameras.iter().skip(trails.cameras.len() - trail.len());
+        let screen: Vec<Vec2> = trail
+            .iter()
+            .zip(cameras)
+            .map(|(&pos, recorded)| if world_fixed { camera } else { recorded }.world_to_screen(pos))
+            .collect();
+        for (i, (a, b)) in screen.iter().zip(screen.iter().skip(1)).enumerate() {
+            let intensity = 0.6 * (i + 1) as f32 / trail.len() as f32;
+            draw_line(buffer, *a, *b, color, intensity, style);
+        }
+    }
+}
+
+/// Draw `text` with the built-in bitmap font, top-left corner at (`x`, `y`)
+fn draw_text(buffer: &mut [u32], x: usize, y: usize, text: &str, color: u32) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph_x = x + i * (font::GLYPH_WIDTH + 1) * TEXT_SCALE;
+        for (row, bits) in font::glyph(c).iter().enumerate() {
+            for col in 0..font::GLYPH_WIDTH {
+                if bits & (0x10 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..TEXT_SCALE {
+                    let py = y + row * TEXT_SCALE + dy;
+                    let px = glyph_x + col * TEXT_SCALE;
+                    if py < HEIGHT && px + TEXT_SCALE <= WIDTH {
+                        buffer[py * WIDTH + px..py * WIDTH + px + TEXT_SCALE].fill(color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Draw an overview of the whole simulation area with every body as one pixel, plus a
+/// rectangle marking what the camera currently shows
+fn draw_minimap(buffer: &mut [u32], camera: &Camera, bodies: &[Body], colors: &[u32], width: usize, corner: Corner) {
+    let height = width * HEIGHT / WIDTH;
+    let mut map = vec![0x101018; width * height];
+    let map_scale = width as f32 / (WIDTH as f32 * SPACE_SCALE);
+    let to_map = |pos: Vec2| pos * map_scale + Vec2::new(width as f32 / 2.0, height as f32 / 2.0);
+
+    for (body, &color) in bodies.iter().zip(colors) {
+        let point = to_map(body.pos);
+        let color = if body.central { 0xFFAA33 } else { color };
+        set_inset_pixel(&mut map, width, point.x as i32, point.y as i32, color);
+    }
+    let (view_min, view_max) = camera.visible();
+    draw_inset_rect(&mut map, width, to_map(view_min), to_map(view_max), 0xFFFFFF);
+    draw_inset_rect(&mut map, width, Vec2::ZERO, Vec2::new(width as f32 - 1.0, height as f32 - 1.0), 0x606060);
+
+    let (left, top) = corner.origin(width, height, MINIMAP_MARGIN);
+    for (row, line) in map.chunks(width).enumerate() {
+        let start = (top + row) * WIDTH + left;
+        buffer[start..start + width].copy_from_slice(line);
+    }
+}
+
+/// Plot a radial density profile on a log scale in the top-right corner, from the center of
+/// mass on the left to `escape_radius` on the right
+fn draw_profile(buffer: &mut [u32], profile: &[f32], config: &Config) {
+    let (width, height) = (1200, 600);
+    let (left, top) = Corner::TopRight.origin(width, height, 40);
+    for row in buffer[top * WIDTH..(top + height) * WIDTH].chunks_mut(WIDTH) {
+        row[left..left + width].fill(0x101018);
+    }
+    let line_height = (font::GLYPH_HEIGHT + 3) * TEXT_SCALE;
+    let units = config.units;
+    let kind = if config.profile_mass { "Mass" } else { "Number" };
+    let title = format!("{} density (log), out to {:.0} {}", kind, config.escape_radius * units.length, units.length_label);
+    draw_text(buffer, left + 20, top + 20, &title, OVERLAY_COLOR);
+
+    // Plot area below the title, spanning PROFILE_DECADES below the densest ring
+    let (plot_left, plot_top) = ((left + 20) as f32, (top + 20 + line_height) as f32);
+    let (plot_width, plot_height) = (width as f32 - 40.0, (height - 40 - line_height) as f32);
+    let frame = LineStyle { width: 2.0, antialias: false };
+    let corners = [
+        Vec2::new(plot_left, plot_top),
+        Vec2::new(plot_left + plot_width, plot_top),
+        Vec2::new(plot_left + plot_width, plot_top + plot_height),
+        Vec2::new(plot_left, plot_top + plot_height),
+    ];
+    for i in 0..4 {
+        draw_line(buffer, corners[i], corners[(i + 1) % 4], 0x606060, 1.0, frame);
+    }
+    let Some(densest) = profile.iter().copied().reduce(f32::max).filter(|&densest| densest > 0.0) else {
+        return;
+    };
+    let top_decade = densest.log10();
+    let points: Vec<Vec2> = profile
+        .iter()
+        .enumerate()
+        .map(|(i, &density)| {
+            let x = plot_left + (i as f32 + 0.5) / profile.len() as f32 * plot_width;
+            let height = ((density.max(f32::MIN_POSITIVE).log10() - top_decade) / PROFILE_DECADES + 1.0).clamp(0.0, 1.0);
+            Vec2::new(x, plot_top + (1.0 - height) * plot_height)
+        })
+        .collect();
+    let style = LineStyle { width: 3.0, antialias: true };
+    for pair in points.windows(2) {
+        draw_line(buffer, pair[0], pair[1], 0x80C0FF, 1.0, style);
+    }
+    for &point in &points {
+        draw_cross(buffer, point, 12.0, 0xFFFFFF, style);
+    }
+}
+
+/// Set a pixel of an inset image `width` pixels wide, ignoring positions outside it
+fn set_inset_pixel(image: &mut [u32], width: usize, x: i32, y: i32, color: u32) {
+    let height = image.len() / width;
+    if x >= 0 && (x as usize) < width && y >= 0 && (y as usize) < height {
+        image[y as usize * width + x as usize] = color;
+    }
+}
+
+/// Outline the rectangle between two corners in an inset image
+fn draw_inset_rect(image: &mut [u32], width: usize, min: Vec2, max: Vec2, color: u32) {
+    let height = (image.len() / width) as i32;
+    let (x0, y0, x1, y1) = (min.x as i32, min.y as i32, max.x as i32, max.y as i32);
+    // Only walk the part of each edge that's inside the image, however far out the corners are
+    for x in x0.max(0)..=x1.min(width as i32 - 1) {
+        set_inset_pixel(image, width, x, y0, color);
+        set_inset_pixel(image, width, x, y1, color);
+    }
+    for y in y0.max(0)..=y1.min(height - 1) {
+        set_inset_pixel(image, width, x0, y, color);
+        set_inset_pixel(image, width, x1, y, color);
+    }
+}
+
+/// Brighten the screen towards its edges by `strength` (0 to 1)
+fn draw_vignette(buffer: &mut [u32], strength: f32) {
+    let center = Vec2::new(WIDTH as f32 / 2.0, HEIGHT as f32 / 2.0);
+    let max_dist_sq = center.length_squared();
+    buffer.par_chunks_mut(WIDTH).enumerate().for_each(|(y, row)| {
+        for x in 0..WIDTH {
+            let dist_sq = Vec2::new(x as f32, y as f32).distance_squared(center);
+            add_pixel(row, x as i32, 0, 0xFFE8D0, strength * dist_sq / max_dist_sq);
+        }
+    });
+}
+
+/// Let pixels brighter than `threshold` bleed light into their surroundings: their excess
+/// brightness is blurred at reduced resolution and added back on top, scaled by `intensity`
+fn draw_bloom(buffer: &mut [u32], threshold: f32, intensity: f32) {
+    let (width, height) = (WIDTH / BLOOM_DOWNSCALE, HEIGHT / BLOOM_DOWNSCALE);
+    let bright_part = |pixel: u32| {
+        let rgb = Vec3::new(((pixel >> 16) & 0xFF) as f32, ((pixel >> 8) & 0xFF) as f32, (pixel & 0xFF) as f32) / 255.0;
+        let luminance = rgb.dot(Vec3::new(0.2126, 0.7152, 0.0722));
+        if luminance <= threshold {
+            Vec3::ZERO
+        } else {
+            rgb * ((luminance - threshold) / luminance)
+        }
+    };
+
+    // Threshold before downscaling, so a single bright pixel isn't averaged away
+    let bright: Vec<Vec3> = (0..width * height)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = (i % width * BLOOM_DOWNSCALE, i / width * BLOOM_DOWNSCALE);
+            let block = (0..BLOOM_DOWNSCALE * BLOOM_DOWNSCALE)
+                .map(|j| bright_part(buffer[(y + j / BLOOM_DOWNSCALE) * WIDTH + x + j % BLOOM_DOWNSCALE]))
+                .sum::<Vec3>();
+            block / (BLOOM_DOWNSCALE * BLOOM_DOWNSCALE) as f32
+        })
+        .collect();
+
+    // Two box blurs in each direction, which is close to a smooth falloff
+    let mut glow = bright;
+    for _ in 0..2 {
+        glow = transpose(&blur_rows(&glow, width), width, height);
+        glow = transpose(&blur_rows(&glow, height), height, width);
+    }
+
+    buffer.par_chunks_mut(WIDTH).enumerate().for_each(|(y, row)| {
+        let low_y = y / BLOOM_DOWNSCALE;
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let added = glow[low_y * width + x / BLOOM_DOWNSCALE] * intensity * 255.0;
+            let channel = |shift: u32, added: f32| ((((*pixel >> shift) & 0xFF) + added as u32).min(255)) << shift;
+            *pixel = channel(16, added.x) | channel(8, added.y) | channel(0, added.z);
+        }
+    });
+}
+
+/// Pass every channel through the Reinhard curve `x / (1 + x)` with `x = strength * c`: faint
+/// bodies get brighter, while bright, dense regions roll off smoothly and saturated ones end
+/// up at `strength / (1 + strength)` of full brightness instead of flat white.
+fn apply_tone_map(buffer: &mut [u32], strength: f32) {
+    let curve: Vec<u32> = (0..256)
+        .map(|value| {
+            let c = value as f32 / 255.0;
+            let x = strength * c;
+            (x / (1.0 + x) * 255.0).round() as u32
+        })
+        .collect();
+    buffer.par_iter_mut().for_each(|pixel| {
+        let channel = |shift: u32| curve[((*pixel >> shift) & 0xFF) as usize] << shift;
+        *pixel = channel(16) | channel(8) | channel(0);
+    });
+}
+
+/// Box blur each row of an image `width` pixels wide, treating pixels past the ends as black
+fn blur_rows(image: &[Vec3], width: usize) -> Vec<Vec3> {
+    let mut blurred = vec![Vec3::ZERO; image.len()];
+    blurred.par_chunks_mut(width).zip(image.par_chunks(width)).for_each(|(out, row)| {
+        let mut sum: Vec3 = row[..=BLOOM_RADIUS.min(width - 1)].iter().sum();
+        for x in 0..width {
+            out[x] = sum / (2 * BLOOM_RADIUS + 1) as f32;
+            if x + BLOOM_RADIUS + 1 < width {
+                sum += row[x + BLOOM_RADIUS + 1];
+            }
+            if x >= BLOOM_RADIUS {
+                sum -= row[x - BLOOM_RADIUS];
+            }
+        }
+    });
+    blurred
+}
+
+/// Swap rows and columns of an image `width` by `height` pixels
+fn transpose(image: &[Vec3], width: usize, height: usize) -> Vec<Vec3> {
+    (0..width * height).into_par_iter().map(|i| image[i % height * width + i / height]).collect()
+}
+
+/// Draw status lines in the top-left corner
+fn draw_overlay(buffer: &mut [u32], lines: &[String]) {
+    let line_height = (font::GLYPH_HEIGHT + 3) * TEXT_SCALE;
+    for (i, line) in lines.iter().enumerate() {
+        draw_text(buffer, 40, 40 + i * line_height, line, OVERLAY_COLOR);
+    }
+}
+
+/// Draw a filled circle centered on `screen` with a radius in pixels, with glow layers (radii
+/// in pixels too) added around it
+fn draw_circle(buffer: &mut [u32], screen: Vec2, radius: f32, color: u32, glow: &[GlowLayer]) {
+    // Skip circles whose glow can't reach the screen before any of the integer math below,
+    // which far off-screen positions could also overflow
+    let outer = glow.iter().map(|layer| layer.radius).fold(radius, f32::max);
+    let reach = outer + 1.0;
+    if screen.x + reach < 0.0 || screen.y + reach < 0.0 || screen.x - reach > WIDTH as f32 || screen.y - reach > HEIGHT as f32 {
+        return;
+    }
+
+    let x_center = screen.x as i32;
+    let y_center = screen.y as i32;
+    let r = radius as i32;
+    let glow_radius = outer as i32;
+    let r_squared = r * r;
+    let glow_squared = glow_radius * glow_radius;
+    let layers: Vec<(f32, f32)> = glow.iter().map(|layer| ((layer.radius as i32).pow(2) as f32, layer.intensity)).collect();
+
+    // Only visit rows and columns that are on screen, which matters when zoomed in
+    let rows = (-glow_radius).max(-y_center)..=glow_radius.min(HEIGHT as i32 - 1 - y_center);
+    let columns = (-glow_radius).max(-x_center)..=glow_radius.min(WIDTH as i32 - 1 - x_center);
+    for y in rows {
+        let y_offset = (y_center + y) as usize * WIDTH;
+        let y_sq = y * y;
+
+        for x in columns.clone() {
+            let dist_sq = x * x + y_sq;
+            if dist_sq > glow_squared {
+                continue;
+            }
+            let px = x_center + x;
+            if dist_sq <= r_squared {
+                buffer[y_offset + px as usize] = color;
+            } else {
+                // Each layer fades out towards its own radius, and they add up where they overlap
+                let intensity: f32 = layers
+                    .iter()
+                    .map(|&(layer_sq, intensity)| intensity * (1.0 - dist_sq as f32 / layer_sq).max(0.0))
+                    .sum();
+                add_pixel(buffer, px, y_center + y, color, intensity);
+            }
+        }
+    }
+}
+
+fn main() {
+    let (config, run): (Config, fn(Simulation)) = match Command::from_args() {
+        Ok(Command::Run(config)) => (config, run_interactive),
+        Ok(Command::Headless(config)) => (config, run_headless),
+        Ok(Command::Bench(config)) => (Config { seed: config.seed.or(Some(0)), profile: true, ..config }, run_bench),
+        Ok(Command::Convert { input, output }) => {
+            let converted = particles::read_bodies(&input).and_then(|bodies| {
+                particles::write_bodies(&output, &bodies)?;
+                Ok(bodies.len())
+            });
+            match converted {
+                Ok(count) => println!("Converted {} bodies from {} to {}", count, input.display(), output.display()),
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Ok(Command::Help(usage)) => {
+            println!("{}", usage);
+            return;
+        }
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            eprintln!("Run 'nbody-sim [COMMAND] --help' for the available options");
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(threads) = config.threads {
+        parallel::set_threads(threads);
+    }
+    // Keep stdout to the JSON output when it's asked for
+    if config.data_on_stdout() {
+        eprintln!("Using {} worker threads", parallel::current_num_threads());
+    } else {
+        println!("Using {} worker threads", parallel::current_num_threads());
+    }
+
+    if config.force_exponent != 2.0 {
+        eprintln!(
+            "Warning: initial orbital speeds assume an inverse-square force, so with --force-exponent {} orbits start out of balance",
+            config.force_exponent
+        );
+    }
+
+    if !config.compare.is_empty() {
+        // Every pane starts from the same bodies, so only the integrator differs
+        let config = Config { seed: config.seed.or_else(|| Some(rand::random())), ..config };
+        let sims = config
+            .compare
+            .iter()
+            .map(|&integrator| build_simulation(Config { integrator, ..config.clone() }))
+            .collect();
+        run_compare(sims);
+        return;
+    }
+    run(build_simulation(config));
+}
+
+/// Load or generate the starting bodies, exiting on errors
+fn build_simulation(config: Config) -> Simulation {
+    let loaded = match (config.bodies_json.clone(), config.load.clone()) {
+        (Some(path), _) => Simulation::from_json_bodies(config, &path),
+        (_, Some(path)) => Simulation::load(config, &path),
+        _ => Simulation::new(config),
+    };
+    loaded.unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    })
+}
+
+/// Simulated time in display units and the step count, plainly or as powers of ten.
+/// Zero has no logarithm, so the start of a run always reads plainly.
+fn describe_elapsed(time: f32, steps: u64, units: Units, log: bool) -> String {
+    let time = time * units.time;
+    if log && time > 0.0 && steps > 0 {
+        format!("10^{:.2} {}, step 10^{:.2}", time.log10(), units.time_label, (steps as f64).log10())
+    } else {
+        format!("{:.2} {}, step {}", time, units.time_label, steps)
+    }
+}
+
+/// Time `config.steps` headless steps and report the throughput and where the time went
+fn run_bench(mut sim: Simulation) {
+    if sim.config.theta_sweep {
+        report_force_errors(&sim.bodies, sim.gravity());
+    }
+    if sim.config.integrator_sweep {
+        report_integrator_errors(sim.gravity(), &sim.config);
+    }
+    if sim.config.tree_build {
+        report_tree_build_times();
+    }
+
+    let started = Instant::now();
+    for _ in 0..sim.config.steps {
+        sim.step(sim.config.dt);
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+    println!(
+        "{} steps with {} bodies in {:.2}s: {:.1} steps/s",
+        sim.steps,
+        sim.bodies.len(),
+        elapsed,
+        sim.steps as f64 / elapsed.max(f64::EPSILON)
+    );
+    println!("Per step: {}", sim.timings.report(sim.steps));
+}
+
+/// Step the simulation without a window. Every step advances exactly `config.dt`,
+/// independent of wall-clock time, so runs are reproducible on any machine.
+fn run_headless(mut sim: Simulation) {
+    let started = Instant::now();
+    let initial = diagnostics::Conserved::measure(&sim.bodies, sim.gravity());
+    let mut stream = sim.config.stream.map(particles::FrameStream::new);
+    for step in 0..=sim.config.steps {
+        if let Some(frames) = &mut stream {
+            // A stream nobody reads any more has no reason to keep running
+            if let Err(err) = frames.record(sim.steps, sim.time, &sim.bodies) {
+                eprintln!("Error: failed to stream frames, stopping: {}", err);
+                break;
+            }
+        }
+        if step < sim.config.steps {
+            sim.step(sim.config.dt);
+        }
+    }
+    let last = diagnostics::Conserved::measure(&sim.bodies, sim.gravity());
+    let mut report = String::new();
+    if sim.config.json {
+        print_json_report(&sim, started, &initial, &last);
+    } else {
+        report = summary(&sim, started) + &conservation_report(&sim, &initial, &last);
+    }
+    if sim.config.profile {
+        let _ = writeln!(report, "Per step: {}", sim.timings.report(sim.steps));
+    }
+    if sim.config.data_on_stdout() {
+        eprint!("{}", report);
+    } else {
+        print!("{}", report);
+    }
+    sim.save();
+}
+
+/// Size of a Barnes-Hut tree, on one line
+fn describe_tree(stats: &TreeStats) -> String {
+    format!(
+        "{} nodes, {} leaves, depth {}, up to {} per leaf",
+        stats.nodes, stats.leaves, stats.max_depth, stats.max_leaf_bodies
+    )
+}
+
+/// The steps taken, simulated time and final energy of a run
+fn summary(sim: &Simulation, started: Instant) -> String {
+    let mut text = String::new();
+    let gravity = sim.gravity();
+    let units = sim.config.units;
+    let energy = diagnostics::total_energy(&sim.bodies, gravity);
+    let _ = writeln!(
+        text,
+        "Ran {} steps ({:.3} {}) in {:.2}s: {} bodies, total energy {:.6e}, virial ratio {:.3}, G = {}",
+        sim.steps,
+        sim.time * units.time,
+        units.time_label,
+        started.elapsed().as_secs_f32(),
+        sim.bodies.len(),
+        energy * units.energy(),
+        diagnostics::virial_ratio(&sim.bodies, gravity),
+        units.describe_g(gravity.g)
+    );
+
+    if let Some(stats) = sim.tree_stats.filter(|_| sim.config.tree_stats) {
+        let Interactions { direct, approximated } = sim.interactions;
+        let _ = writeln!(
+            text,
+            "Barnes-Hut tree: {}, {:.1} interactions per body",
+            describe_tree(&stats),
+            (direct + approximated) as f32 / sim.bodies.len().max(1) as f32
+        );
+    }
+
+    if let Some(approaches) = &sim.closest_approaches {
+        text.push_str("Closest approaches:\n");
+        for approach in approaches.sorted() {
+            let _ = writeln!(
+                text,
+                "  step {}: bodies {} and {} at {:.4} {}",
+                approach.step,
+                approach.ids.0,
+                approach.ids.1,
+                approach.separation * units.length,
+                units.length_label
+            );
+        }
+    }
+    text
+}
+
+/// Initial and final value, change, and relative change of each conserved quantity, in the
+/// display units. The momentum change is the length of the change in the net momentum.
+fn conservation_drifts(sim: &Simulation, initial: &Conserved, last: &Conserved) -> [(&'static str, [f32; 4]); 3] {
+    let units = sim.config.units;
+    let momentum_unit = units.mass * units.velocity();
+    let drift = |start: f32, end: f32, change: f32, scale: f32, unit: f32| {
+        [start * unit, end * unit, change * unit, change / scale.abs().max(f32::MIN_POSITIVE)]
+    };
+    [
+        ("energy", drift(initial.energy, last.energy, last.energy - initial.energy, initial.energy, units.energy())),
+        (
+            "momentum",
+            drift(
+                initial.momentum.length(),
+                last.momentum.length(),
+                (last.momentum - initial.momentum).length(),
+                initial.momentum_scale,
+                momentum_unit,
+            ),
+        ),
+        (
+            "angular_momentum",
+            drift(
+                initial.angular_momentum,
+                last.angular_momentum,
+                last.angular_momentum - initial.angular_momentum,
+                initial.angular_momentum,
+                momentum_unit * units.length,
+            ),
+        ),
+    ]
+}
+
+/// How far energy and momenta drifted over the run, one quantity per line
+fn conservation_report(sim: &Simulation, initial: &Conserved, last: &Conserved) -> String {
+    let mut text = "Conservation (initial, final, change, relative change):\n".to_string();
+    for (name, [start, end, change, relative]) in conservation_drifts(sim, initial, last) {
+        let _ = writeln!(text, "  {:<16} {:>14.6e} {:>14.6e} {:>14.6e} {:>11.3e}", name, start, end, change, relative);
+    }
+    text
+}
+
+/// The summary and conservation report as a single JSON object
+fn print_json_report(sim: &Simulation, started: Instant, initial: &Conserved, last: &Conserved) {
+    let mut report = serde_json::json!({
+        "steps": sim.steps,
+        "time": sim.time * sim.config.units.time,
+        "seconds": started.elapsed().as_secs_f32(),
+        "bodies": sim.bodies.len(),
+    });
+    for (name, [start, end, change, relative]) in conservation_drifts(sim, initial, last) {
+        report[name] = serde_json::json!({ "initial": start, "final": end, "change": change, "relative": relative });
+    }
+    println!("{}", report);
+}
+
+/// Copies of `bodies` moved back towards their `previous` positions, `alpha` of the way from
+/// there to where they are now. Bodies without a previous position (new since) stay put.
+fn interpolate_positions(bodies: &[Body], previous: &[(u64, Vec2)], alpha: f32) -> Vec<Body> {
+    let alpha = alpha.clamp(0.0, 1.0);
+    // Steps usually keep the bodies in place, so only look positions up by id when they've moved
+    let mut by_id: Option<HashMap<u64, Vec2>> = None;
+    bodies
+        .iter()
+        .enumerate()
+        .map(|(i, body)| {
+            let before = match previous.get(i) {
+                Some(&(id, pos)) if id == body.id => Some(pos),
+                _ => by_id.get_or_insert_with(|| previous.iter().copied().collect()).get(&body.id).copied(),
+            };
+            let pos = before.map_or(body.pos, |before| before.lerp(body.pos, alpha));
+            Body { pos, ..body.clone() }
+        })
+        .collect()
+}
+
+/// Copies of the bodies that were in `frame`, at their positions then. Bodies added since
+/// are left out, and ones gone since can't be shown, since only their positions were kept.
+fn positions_at(bodies: &[Body], frame: &[(u64, Vec2)]) -> Vec<Body> {
+    // Usually the bodies are the same ones in the same order, so only look them up by id when not
+    let mut by_id: Option<HashMap<u64, Vec2>> = None;
+    bodies
+        .iter()
+        .enumerate()
+        .filter_map(|(i, body)| {
+            let pos = match frame.get(i) {
+                Some(&(id, pos)) if id == body.id => Some(pos),
+                _ => by_id.get_or_insert_with(|| frame.iter().copied().collect()).get(&body.id).copied(),
+            };
+            pos.map(|pos| Body { pos, ..body.clone() })
+        })
+        .collect()
+}
+
+/// Run with a window. The simulation keeps pace with the wall clock: each frame's
+/// elapsed real time (times the speed multiplier) is worked off in base timesteps,
+/// as far as the per-frame budget allows.
+fn run_interactive(mut sim: Simulation) {
+    let mut window = open_window(&sim.config);
+
+    let typed_chars = Rc::new(RefCell::new(String::new()));
+    window.set_input_callback(Box::new(TypedChars(typed_chars.clone())));
+
+    let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
+
+    let mut last_update = Instant::now();
+    let mut frame_smoother = FrameSmoother::new();
+    let mut time_multiplier = 1.0;  // Controls simulation speed
+    let mut color_mode = ColorMode::Mass;
+    let mut console = Console::new();
+    let mut trails = (sim.config.trail_length > 0)
+        .then(|| Trails::new(&sim.bodies, sim.config.trail_length, sim.config.trail_count));
+    // Body being thrown: placed where the drag started, with a velocity set by the drag
+    let mut throw: Option<Body> = None;
+    let mut camera = Camera::new();
+    let mut show_minimap = sim.config.minimap;
+    let mut log_time = sim.config.log_time;
+    // Latest density profile, recomputed every `profile_interval` frames while it's shown
+    let mut profile: Option<Vec<f32>> = sim.config.density_profile.then(Vec::new);
+    let mut profile_age = 0;
+    let mut show_com = sim.config.com_marker;
+    let mut snap = false;
+    let mut world_trails = true;
+    let mut tone_map = sim.config.tone_map > 0.0;
+    // Accumulated occupancy, while the stroboscopic view is on
+    let mut strobe: Option<Strobe> = None;
+    let mut show_help = false;
+    let mut paused = false;
+    // Body positions after recent frames that stepped, oldest first, and how many frames
+    // back from the latest one the paused view is
+    // Grown on demand, since --history can ask for far more frames than a run ever records
+    let mut history: VecDeque<Vec<(u64, Vec2)>> = VecDeque::new();
+    let mut scrub: usize = 0;
+    // Newest last, so restoring always goes back to the latest one
+    let mut snapshots: Vec<Snapshot> = Vec::new();
+    let keymap = sim.config.keymap.clone();
+    let help = keymap.help();
+    // Softening to go back to when it's toggled on again
+    let mut restore_softening = if sim.config.softening > 0.0 { sim.config.softening } else { SOFTENING };
+    let mut flash = 0.0;
+    // Id of the body whose predicted path is shown (picked with the right mouse button)
+    let mut selected: Option<u64> = None;
+    // Id of a second body whose approach to the selected one is forecast (Shift + right click)
+    let mut partner: Option<u64> = None;
+    let mut period_timer: Option<PeriodTimer> = None;
+    let mut right_was_down = false;
+    let mut profile_frames = 0;
+    let started = Instant::now();
+    // Simulated time owed to the physics, and time given up because the frame budget ran out
+    let mut pending_time = 0.0;
+    let mut dropped_time = 0.0;
+    // Body positions before the latest step, for drawing in between steps
+    let mut previous: Vec<(u64, Vec2)> = Vec::new();
+    let frame_budget = Duration::from_secs_f32(sim.config.frame_budget / 1000.0);
+    // Energy to measure drift against, reset whenever the integrator, gravity or body count changes
+    let mut drift_reference: Option<(f32, (Integrator, f32, usize))> = None;
+    let mut profile_since = Instant::now();
+    let mut input_clock = Instant::now();
+
+    while window.is_open() {
+        // Characters typed this frame, only kept while the console is open
+        let typed = std::mem::take(&mut *typed_chars.borrow_mut());
+
+        if window.is_key_pressed(Key::Escape, KeyRepeat::No) {
+            if !console.open {
+                break;
+            }
+            console.open = false;
+        } else if window.is_key_pressed(Key::Backquote, KeyRepeat::No) {
+            console.open = !console.open;
+        } else if console.open {
+            console.input.extend(typed.chars().filter(|&c| c != '`'));
+            if window.is_key_pressed(Key::Backspace, KeyRepeat::Yes) {
+                console.input.pop();
+            }
+            if window.is_key_pressed(Key::Enter, KeyRepeat::No) {
+                let line = std::mem::take(&mut console.input);
+                console.message = match run_command(&line, &mut sim, &mut time_multiplier) {
+                    Ok(message) => message,
+                    Err(err) => format!("error: {}", err),
+                };
+            }
+        }
+
+        // Handle controls (the console takes over the keyboard while open)
+        let input_seconds = input_clock.elapsed().as_secs_f32().min(MAX_INPUT_STEP);
+        input_clock = Instant::now();
+        let input = if console.open { InputState::none() } else { InputState::read(&window, &keymap, input_seconds) };
+        if input.triggered(Action::Help) {
+            show_help = !show_help;
+        }
+        if input.triggered(Action::Pause) {
+            paused = !paused;
+            scrub = 0;
+        }
+        if paused && input.triggered(Action::ScrubBack) {
+            scrub = (scrub + 1).min(history.len().saturating_sub(1));
+        }
+        if paused && input.triggered(Action::ScrubForward) {
+            scrub = scrub.saturating_sub(1);
+        }
+        time_multiplier *= ADJUST_RATE.powf(input.held(Action::SpeedUp) - input.held(Action::SlowDown));
+        sim.gravity_multiplier *= ADJUST_RATE.powf(input.held(Action::GravityUp) - input.held(Action::GravityDown));
+        if input.triggered(Action::CycleColors) {
+            color_mode = color_mode.next();
+        }
+        if input.triggered(Action::PrintTable) {
+            print_body_table(&sim.bodies, TABLE_LIMIT, sim.config.units);
+        }
+        if input.triggered(Action::PrintBinding) {
+            print_binding_table(&sim.bodies, sim.gravity(), BINDING_LIMIT, sim.config.units);
+        }
+        if input.triggered(Action::CycleIntegrator) {
+            sim.config.integrator = sim.config.integrator.next();
+        }
+        if input.triggered(Action::ToggleMinimap) {
+            show_minimap = !show_minimap;
+        }
+        if input.triggered(Action::ToggleProfile) {
+            profile = match profile {
+                Some(_) => None,
+                None => Some(Vec::new()),
+            };
+        }
+        if input.triggered(Action::ToggleLogTime) {
+            log_time = !log_time;
+        }
+        if input.triggered(Action::ToggleCom) {
+            show_com = !show_com;
+        }
+        if input.triggered(Action::ToggleSnap) {
+            snap = !snap;
+        }
+        if input.triggered(Action::ToggleWorldTrails) {
+            world_trails = !world_trails;
+        }
+        if input.triggered(Action::ToggleToneMap) {
+            tone_map = !tone_map;
+        }
+        if input.triggered(Action::ToggleStrobe) {
+            strobe = if strobe.is_some() { None } else { Some(Strobe::new()) };
+        }
+        if let Some(strobe) = strobe.as_mut().filter(|_| input.triggered(Action::ResetStrobe)) {
+            strobe.reset();
+        }
+        if input.triggered(Action::ToggleSoftening) {
+            if sim.config.softening > 0.0 {
+                restore_softening = sim.config.softening;
+                sim.config.softening = 0.0;
+            } else {
+                sim.config.softening = restore_softening;
+            }
+            drift_reference = None;
+        }
+        if input.triggered(Action::SaveSettings) {
+            let seconds = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+            let path = PathBuf::from(format!("nbody-{}.toml", seconds));
+            match settings::write_settings(&path, &sim.settings()) {
+                Ok(()) => println!("Saved the settings to {} (rerun them with --config)", path.display()),
+                Err(err) => eprintln!("Error: {}", err),
+            }
+        }
+        if input.triggered(Action::ThetaDown) {
+            sim.config.theta = (sim.config.theta - THETA_STEP).max(0.0);
+        }
+        if input.triggered(Action::ThetaUp) {
+            sim.config.theta = (sim.config.theta + THETA_STEP).min(MAX_THETA);
+        }
+        if input.triggered(Action::Explode) {
+            // At the mouse pointer, or at the center of mass when it's outside the window
+            let center = mouse_screen_pos(&window, MouseMode::Discard)
+                .map(|screen| camera.screen_to_world(screen))
+                .unwrap_or_else(|| diagnostics::center_of_mass(&sim.bodies));
+            let config = &sim.config;
+            explode(&mut sim.bodies, center, config.explosion_radius, config.explosion_energy, config.softening);
+            // The old paths no longer lead anywhere, and the energy jump isn't drift
+            if let Some(trails) = &mut trails {
+                trails.clear();
+            }
+            drift_reference = None;
+        }
+        if input.triggered(Action::TakeSnapshot) {
+            if snapshots.len() == MAX_SNAPSHOTS {
+                snapshots.remove(0);
+            }
+            snapshots.push(Snapshot {
+                bodies: sim.bodies.clone(),
+                gravity_multiplier: sim.gravity_multiplier,
+                time_multiplier,
+                steps: sim.steps,
+                time: sim.time,
+            });
+        }
+        if input.triggered(Action::DropSnapshot) {
+            snapshots.pop();
+        }
+        // The latest snapshot stays, to go back to it again after the next try
+        if let Some(snapshot) = snapshots.last().filter(|_| input.triggered(Action::RestoreSnapshot)) {
+            sim.bodies = snapshot.bodies.clone();
+            sim.gravity_multiplier = snapshot.gravity_multiplier;
+            sim.steps = snapshot.steps;
+            sim.time = snapshot.time;
+            sim.force_cache = None;
+            time_multiplier = snapshot.time_multiplier;
+            throw = None;
+            pending_time = 0.0;
+            previous.clear();
+            // The frames kept for scrubbing belong to the timeline being abandoned
+            history.clear();
+            scrub = 0;
+            // Track the snapshot's bodies afresh, since merged or removed ones are back
+            if trails.is_some() {
+                trails = Some(Trails::new(&sim.bodies, sim.config.trail_length, sim.config.trail_count));
+            }
+            drift_reference = None;
+        }
+
+        // The pan keys move the view, the scroll wheel zooms around the mouse
+        let pan = PAN_SPEED / camera.scale();
+        for (action, direction) in [(Action::PanLeft, -Vec2::X), (Action::PanRight, Vec2::X), (Action::PanUp, -Vec2::Y), (Action::PanDown, Vec2::Y)] {
+            camera.center += direction * pan * input.held(action);
+        }
+        if input.triggered(Action::ResetCamera) {
+            camera = Camera::new();
+        }
+        if input.triggered(Action::FitView) {
+            camera = Camera::framing(framed_bodies(&sim.bodies, sim.config.frame_fraction), FRAME_MARGIN);
+        }
+        let mass_factor = if input.triggered(Action::MassUp) {
+            Some(MASS_STEP)
+        } else if input.triggered(Action::MassDown) {
+            Some(1.0 / MASS_STEP)
+        } else {
+            None
+        };
+        let mouse_screen = mouse_screen_pos(&window, MouseMode::Clamp);
+        if let (Some((_, scroll)), Some(screen)) = (window.get_scroll_wheel(), mouse_screen) {
+            if scroll != 0.0 {
+                camera.zoom_at(screen, ZOOM_STEP.powf(scroll.signum()));
+            }
+        }
+
+        // Drag with the left mouse button to throw in a new body
+        let mouse = mouse_screen.map(|screen| camera.screen_to_world(screen));
+        if let Some(mouse) = mouse {
+            if window.get_mouse_down(MouseButton::Left) {
+                let start = if snap { snap_to_grid(mouse, sim.config.snap_increment) } else { mouse };
+                let body = throw.get_or_insert_with(|| Body::new(start, Vec2::ZERO, THROW_MASS));
+                body.vel = (mouse - body.pos) * camera.scale() * THROW_SPEED_SCALE;
+            } else if let Some(body) = throw.take() {
+                sim.bodies.push(body);
+            }
+
+            let right_down = window.get_mouse_down(MouseButton::Right);
+            if right_down && !right_was_down {
+                let picked = body_at(&sim.bodies, &camera, mouse, sim.config.radius_law).map(|i| sim.bodies[i].id);
+                if window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift) {
+                    partner = picked.filter(|&id| Some(id) != selected);
+                } else {
+                    selected = picked;
+                    if selected.is_none() || selected == partner {
+                        partner = None;
+                    }
+                }
+            }
+            right_was_down = right_down;
+        }
+
+        // Make the selected body heavier or lighter
+        if let (Some(body), Some(factor)) = (selected.and_then(|id| sim.bodies.iter_mut().find(|body| body.id == id)), mass_factor) {
+            let (min, max) = sim.config.mass_edit_range;
+            body.set_mass((body.mass * factor).clamp(min, max), &sim.config.species);
+            // The energy jump isn't drift
+            drift_reference = None;
+        }
+
+        // Clear buffer with very dark blue background
+        buffer.fill(0x000008);
+
+        // Update physics with fixed timestep and substeps for smoothness
+        let now = Instant::now();
+        let frame_time = now.duration_since(last_update);
+        let mut elapsed = frame_time.as_secs_f32();
+        if sim.config.smooth_frames {
+            elapsed = frame_smoother.smooth(elapsed);
+        }
+        
+        let dt = sim.config.dt;  // Keep base timestep constant
+        
+        // Substep until the frame's time is used up or the wall-clock budget runs out.
+        // Time we couldn't get to is dropped, so a slow machine runs slower instead of falling behind.
+        if !paused {
+            pending_time += elapsed * time_multiplier;
+        }
+        let steps_before = sim.steps;
+        while pending_time >= dt && !finished(&sim) {
+            if now.elapsed() >= frame_budget {
+                dropped_time += pending_time;
+                pending_time = 0.0;
+                break;
+            }
+            if sim.config.interpolate {
+                previous.clear();
+                previous.extend(sim.bodies.iter().map(|body| (body.id, body.pos)));
+            }
+            sim.step(dt);
+            pending_time -= dt;
+            if let Some(id) = selected {
+                let timer = period_timer.get_or_insert_with(|| PeriodTimer::new(id));
+                if timer.id != id {
+                    *timer = PeriodTimer::new(id);
+                }
+                if !timer.record(&sim.bodies, sim.time) {
+                    period_timer = None;
+                }
+            }
+        }
+        if sim.config.history > 0 && sim.steps != steps_before {
+            if history.len() == sim.config.history {
+                history.pop_front();
+            }
+            history.push_back(sim.bodies.iter().map(|body| (body.id, body.pos)).collect());
+        }
+        
+        last_update = now;
+        // Drawing a step behind the physics, at the leftover share of a step, keeps the motion
+        // smooth when frames and steps don't line up
+        let interpolated = if scrub > 0 {
+            Some(positions_at(&sim.bodies, &history[history.len() - 1 - scrub]))
+        } else {
+            sim.config.interpolate.then(|| interpolate_positions(&sim.bodies, &previous, pending_time / dt))
+        };
+        let shown = interpolated.as_deref().unwrap_or(&sim.bodies);
+        let render_started = Instant::now();
+
+        if snap {
+            draw_snap_grid(&mut buffer, &camera, sim.config.snap_increment, sim.config.line_style);
+        }
+
+        // Scrubbed frames can lack bodies, so the colors go by what's drawn
+        let colors = body_colors(shown, color_mode, &sim.config);
+        if let Some(trails) = &mut trails {
+            // A paused view isn't going anywhere, so don't pile up copies of it
+            if !paused {
+                trails.record(shown, camera);
+            }
+            if strobe.is_none() {
+                draw_trails(&mut buffer, &camera, trails, shown, &colors, sim.config.line_style, world_trails);
+            }
+        }
+
+        // The stroboscopic view stands in for the bodies themselves
+        if let Some(strobe) = &mut strobe {
+            if !paused {
+                strobe.record(shown, camera);
+            }
+            strobe.draw(&mut buffer, sim.config.strobe_brightness);
+        } else {
+            draw_bodies(&mut buffer, &camera, shown, &colors, sim.config.radius_law, sim.config.lod_radius, &sim.config.glow);
+        }
+        if input.triggered(Action::SaveSvg) {
+            let seconds = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+            let path = PathBuf::from(format!("nbody-{}.svg", seconds));
+            match write_svg(&path, &camera, shown, &colors, sim.config.radius_law, sim.config.svg_velocities) {
+                Ok(count) => println!("Saved {} bodies to {}", count, path.display()),
+                Err(err) => eprintln!("Error: couldn't save {}: {}", path.display(), err),
+            }
+        }
+
+        if let Some(body) = selected.and_then(|id| sim.bodies.iter().find(|body| body.id == id)) {
+            let path = predict_path(body, &sim.bodies, sim.gravity(), sim.config.prediction_steps, &sim.config);
+            draw_path(&mut buffer, &camera, body.pos, &path, sim.config.prediction_color, sim.config.line_style);
+        }
+        if let Some(body) = partner.and_then(|id| sim.bodies.iter().find(|body| body.id == id)) {
+            let size = body.radius(sim.config.radius_law) * camera.scale() + 10.0;
+            draw_cross(&mut buffer, camera.world_to_screen(body.pos), size, sim.config.prediction_color, sim.config.line_style);
+        }
+        if let (Some(body), Some(mouse)) = (&throw, mouse) {
+            // Drag vector, predicted orbit, and the body itself
+            let path = predict_path(body, &sim.bodies, sim.gravity(), sim.config.prediction_steps, &sim.config);
+            let (from, to) = (camera.world_to_screen(body.pos), camera.world_to_screen(mouse));
+            draw_line(&mut buffer, from, to, 0xFFFFFF, 0.8, sim.config.line_style);
+            draw_path(&mut buffer, &camera, body.pos, &path, sim.config.prediction_color, sim.config.line_style);
+            let radius = body.radius(sim.config.radius_law) * camera.scale();
+            draw_circle(&mut buffer, from, radius, body.color, &satellite_glow(radius));
+        }
+
+        if show_com {
+            let com = camera.world_to_screen(diagnostics::center_of_mass(&sim.bodies));
+            draw_cross(&mut buffer, com, 30.0, sim.config.com_color, sim.config.line_style);
+        }
+
+        if sim.config.encounter_flash > 0.0 {
+            // The potential well of the closest pair, relative to the deepest one the softening
+            // allows (bodies on top of each other), decaying over a few frames
+            // Without softening there's no deepest well to compare against, so no flash
+            let softening = sim.config.softening;
+            let closest = if softening > 0.0 { closest_separation(&sim.bodies, 4.0 * softening) } else { None };
+            if let Some(separation) = closest {
+                let depth = softening * softening / (separation * separation + softening * softening);
+                flash = f32::max(flash, depth);
+            }
+            if flash > 0.01 {
+                draw_vignette(&mut buffer, (flash * sim.config.encounter_flash).min(1.0));
+            }
+            flash *= 0.9;
+        }
+
+        if sim.config.bloom > 0.0 {
+            draw_bloom(&mut buffer, sim.config.bloom_threshold, sim.config.bloom);
+        }
+        if tone_map {
+            // Toggling on without a strength from the command line uses a moderate one
+            let strength = if sim.config.tone_map > 0.0 { sim.config.tone_map } else { 2.0 };
+            apply_tone_map(&mut buffer, strength);
+        }
+
+        let gravity = sim.gravity();
+        let kinetic = diagnostics::kinetic_energy(&sim.bodies);
+        let potential = diagnostics::potential_energy(&sim.bodies, gravity);
+        let energy = kinetic + potential;
+        let state = (sim.config.integrator, gravity.g, sim.bodies.len());
+        let reference = match drift_reference {
+            Some((reference, since)) if since == state => reference,
+            _ => {
+                drift_reference = Some((energy, state));
+                energy
+            }
+        };
+
+        let mut overlay = vec![
+            format!("Time: {}", describe_elapsed(sim.time, sim.steps, sim.config.units, log_time)),
+            format!("Bodies: {}", sim.bodies.len()),
+            format!("G: {}", sim.config.units.describe_g(gravity.g)),
+            format!("Escaping: {}", escaping_bodies(&sim.bodies, sim.config.escape_radius).len()),
+            format!("Integrator: {}", sim.config.integrator.name()),
+            if sim.config.softening > 0.0 {
+                let units = sim.config.units;
+                format!("Softening: {:.1} {} ({})", sim.config.softening * units.length, units.length_label, keymap.label(Action::ToggleSoftening))
+            } else {
+                format!("Softening: off ({})", keymap.label(Action::ToggleSoftening))
+            },
+            format!("Energy drift: {:+.4}%", (energy - reference) / reference.abs().max(f32::MIN_POSITIVE) * 100.0),
+            format!("Virial ratio: {:.3}", diagnostics::virial(kinetic, potential)),
+        ];
+        if sim.config.theta > 0.0 {
+            let Interactions { direct, approximated } = sim.interactions;
+            let total = (direct + approximated).max(1);
+            overlay.push(format!("Theta: {:.2}", sim.config.theta));
+            overlay.push(format!(
+                "Approximated: {:.1}% of {:.0} per body",
+                approximated as f32 / total as f32 * 100.0,
+                total as f32 / sim.bodies.len().max(1) as f32
+            ));
+            if let Some(stats) = sim.tree_stats.filter(|_| sim.config.tree_stats) {
+                overlay.push(format!("Tree: {}", describe_tree(&stats)));
+            }
+        } else {
+            overlay.push("Theta: 0 (direct)".to_string());
+        }
+        overlay.push(format!("Frame: {:.1}ms", frame_time.as_secs_f32() * 1000.0));
+        if sim.config.remove_escapers {
+            overlay.push(format!("Removed: {}", sim.removed));
+        }
+        if paused {
+            overlay.push(if history.is_empty() {
+                format!("Paused ({} to resume)", keymap.label(Action::Pause))
+            } else {
+                format!(
+                    "Paused at frame -{} of {} ({}, {})",
+                    scrub,
+                    history.len() - 1,
+                    keymap.label(Action::ScrubBack),
+                    keymap.label(Action::ScrubForward)
+                )
+            });
+        }
+        if let Some(strobe) = &strobe {
+            overlay.push(format!("Strobe: {} frames ({} to reset)", strobe.frames, keymap.label(Action::ResetStrobe)));
+        }
+        if let Some(snapshot) = snapshots.last() {
+            overlay.push(format!(
+                "Snapshots: {}, latest at step {} ({})",
+                snapshots.len(),
+                snapshot.steps,
+                keymap.label(Action::RestoreSnapshot)
+            ));
+        }
+        if dropped_time > 0.0 {
+            let units = sim.config.units;
+            overlay.push(format!("Dropped: {:.3} {}", dropped_time * units.time, units.time_label));
+        }
+        if color_mode == ColorMode::Acceleration {
+            let units = sim.config.units;
+            overlay.push(format!(
+                "Max acceleration: {:.3e} {}/{}^2",
+                max_acceleration(&sim.bodies) * units.length / (units.time * units.time),
+                units.length_label,
+                units.time_label
+            ));
+        }
+        if let Some(body) = selected.and_then(|id| sim.bodies.iter().find(|body| body.id == id)) {
+            let units = sim.config.units;
+            overlay.push(format!(
+                "Selected mass: {:.1} {} ({}, {})",
+                body.mass * units.mass,
+                units.mass_label,
+                keymap.label(Action::MassUp),
+                keymap.label(Action::MassDown)
+            ));
+            // An unbound body won't come around again, however regular its passages were so far
+            let index = sim.bodies.iter().position(|other| other.id == body.id).unwrap_or_default();
+            let bound = diagnostics::body_energy(&sim.bodies, index, sim.gravity()) < 0.0;
+            let timer = period_timer.as_ref().filter(|timer| timer.id == body.id);
+            let period = match timer.map(|timer| (timer.measuring(), timer.period())) {
+                None | Some((true, _)) if bound => "measuring".to_string(),
+                Some((_, Some(period))) if bound => format!("{:.2} {}", period * units.time, units.time_label),
+                _ => "N/A".to_string(),
+            };
+            overlay.push(format!("Orbital period: {}", period));
+            if let Some(other) = partner.and_then(|id| sim.bodies.iter().find(|other| other.id == id)) {
+                let approach = predict_approach(body, other, sim.gravity(), sim.config.prediction_steps, &sim.config);
+                let time = format!("{:.2} {}", approach.time * units.time, units.time_label);
+                overlay.push(if approach.collides {
+                    format!("Collision in {}", time)
+                } else {
+                    format!("Closest approach: {:.1} {} in {}, no collision", approach.separation * units.length, units.length_label, time)
+                });
+            }
+        }
+        if console.open {
+            overlay.push(format!("> {}_", console.input));
+            overlay.push(console.message.clone());
+        }
+        draw_overlay(&mut buffer, if show_help { &help } else { &overlay });
+        if show_minimap {
+            let config = &sim.config;
+            draw_minimap(&mut buffer, &camera, shown, &colors, config.minimap_width, config.minimap_corner);
+        }
+        if let Some(profile) = &mut profile {
+            let config = &sim.config;
+            if profile.is_empty() || profile_age >= config.profile_interval {
+                let center = diagnostics::center_of_mass(&sim.bodies);
+                *profile = diagnostics::radial_profile(&sim.bodies, center, config.escape_radius, config.profile_bins, config.profile_mass);
+                profile_age = 0;
+            }
+            profile_age += 1;
+            draw_profile(&mut buffer, profile, config);
+        }
+
+        // Update window title with controls and current multipliers
+        window.set_title(&format!(
+            "N-Body Simulation (4K) - Speed: {:.1}x - Gravity: {:.1}x - Colors: {} - Integrator: {} - {} for help - ESC to exit",
+            time_multiplier,
+            sim.gravity_multiplier,
+            color_mode.name(),
+            sim.config.integrator.name(),
+            keymap.label(Action::Help)
+        ));
+
+        if let Err(err) = window.update_with_buffer(&buffer, WIDTH, HEIGHT) {
+            eprintln!("Error: couldn't update the window: {}", err);
+            print!("{}", summary(&sim, started));
+            break;
+        }
+
+        if sim.config.profile {
+            sim.timings.rendering += render_started.elapsed();
+            profile_frames += 1;
+            if profile_since.elapsed() >= Duration::from_secs(1) {
+                println!(
+                    "Per frame ({} fps): {}, {:.3} time units dropped so far",
+                    profile_frames,
+                    sim.timings.report(profile_frames),
+                    dropped_time
+                );
+                sim.timings = PhaseTimes::default();
+                profile_frames = 0;
+                profile_since = Instant::now();
+            }
+        }
+
+        if finished(&sim) {
+            print!("{}", summary(&sim, started));
+            if let Some(path) = &sim.config.final_screenshot {
+                let factor = sim.config.supersample;
+                let image = if factor > 1 {
+                    let style = sim.config.line_style;
+                    let world_trails = trails.as_ref().filter(|_| world_trails);
+                    let mut image = render_supersampled(&camera, factor, |tile, tile_camera| {
+                        // Sizes in pixels grow with the resolution, so they come out the same once filtered
+                        let scale = factor as f32;
+                        if let Some(trails) = world_trails {
+                            let style = LineStyle { width: style.width * scale, ..style };
+                            draw_trails(tile, tile_camera, trails, shown, &colors, style, true);
+                        }
+                        draw_bodies(tile, tile_camera, shown, &colors, sim.config.radius_law, sim.config.lod_radius * scale, &sim.config.glow);
+                    });
+                    if sim.config.bloom > 0.0 {
+                        draw_bloom(&mut image, sim.config.bloom_threshold, sim.config.bloom);
+                    }
+                    if tone_map {
+                        let strength = if sim.config.tone_map > 0.0 { sim.config.tone_map } else { 2.0 };
+                        apply_tone_map(&mut image, strength);
+                    }
+                    image
+                } else {
+                    buffer.clone()
+                };
+                match write_ppm(path, &image) {
+                    Ok(()) => println!("Saved the final frame to {}", path.display()),
+                    Err(err) => eprintln!("Error: couldn't save {}: {}", path.display(), err),
+                }
+            }
+            break;
+        }
+    }
+    sim.save();
+}
+
+/// Open the simulation window, exiting with a hint at the headless mode if there's no
+/// display to open it on
+fn open_window(config: &Config) -> Window {
+    let (window_width, window_height, scale) = config.window_scale.window_size();
+    Window::new(
+        "N-Body Simulation (4K)",
+        window_width,
+        window_height,
+        WindowOptions {
+            resize: true,
+            scale,
+            ..WindowOptions::default()
+        },
+    )
+    .unwrap_or_else(|err| {
+        eprintln!("Error: couldn't open a window: {}", err);
+        eprintln!("Run 'nbody-sim headless' to simulate without a display");
+        std::process::exit(1);
+    })
+}
+
+/// Run two or four simulations side by side, each in its own pane at half resolution.
+/// They share the camera and advance in lockstep, so the panes always show the same
+/// simulated time, and they differ only in the integrator.
+fn run_compare(mut sims: Vec<Simulation>) {
+    let mut window = open_window(&sims[0].config);
+    let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
+    let mut pane: Vec<u32> = vec![0; WIDTH * HEIGHT];
+    let references: Vec<f32> = sims.iter().map(|sim| diagnostics::total_energy(&sim.bodies, sim.gravity())).collect();
+
+    let mut camera = Camera::new();
+    let mut time_multiplier = 1.0;
+    let mut last_update = Instant::now();
+    let mut pending_time = 0.0;
+    let frame_budget = Duration::from_secs_f32(sims[0].config.frame_budget / 1000.0);
+    let keymap = sims[0].config.keymap.clone();
+    let mut input_clock = Instant::now();
+    let started = Instant::now();
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let input_seconds = input_clock.elapsed().as_secs_f32().min(MAX_INPUT_STEP);
+        input_clock = Instant::now();
+        let input = InputState::read(&window, &keymap, input_seconds);
+        time_multiplier *= ADJUST_RATE.powf(input.held(Action::SpeedUp) - input.held(Action::SlowDown));
+        let pan = PAN_SPEED / camera.scale();
+        for (action, direction) in [(Action::PanLeft, -Vec2::X), (Action::PanRight, Vec2::X), (Action::PanUp, -Vec2::Y), (Action::PanDown, Vec2::Y)] {
+            camera.center += direction * pan * input.held(action);
+        }
+        if input.triggered(Action::ResetCamera) {
+            camera = Camera::new();
+        }
+        if let Some((_, scroll)) = window.get_scroll_wheel() {
+            if scroll != 0.0 {
+                camera.zoom = (camera.zoom * ZOOM_STEP.powf(scroll.signum())).clamp(MIN_ZOOM, MAX_ZOOM);
+            }
+        }
+
+        // Step all simulations together, so a slow frame holds all of them back equally
+        let now = Instant::now();
+        pending_time += now.duration_since(last_update).as_secs_f32() * time_multiplier;
+        last_update = now;
+        let dt = sims[0].config.dt;
+        while pending_time >= dt && !finished(&sims[0]) {
+            if now.elapsed() >= frame_budget {
+                pending_time = 0.0;
+                break;
+            }
+            for sim in &mut sims {
+                sim.step(dt);
+            }
+            pending_time -= dt;
+        }
+
+        buffer.fill(0x000008);
+        for (i, (sim, &reference)) in sims.iter().zip(&references).enumerate() {
+            pane.fill(0x000008);
+            let colors = body_colors(&sim.bodies, ColorMode::Mass, &sim.config);
+            draw_bodies(&mut pane, &camera, &sim.bodies, &colors, sim.config.radius_law, sim.config.lod_radius, &sim.config.glow);
+            let energy = diagnostics::total_energy(&sim.bodies, sim.gravity());
+            draw_overlay(&mut pane, &[
+                format!("Integrator: {}", sim.config.integrator.name()),
+                format!("Energy drift: {:+.4}%", (energy - reference) / reference.abs().max(f32::MIN_POSITIVE) * 100.0),
+                format!("Bodies: {}", sim.bodies.len()),
+            ]);
+
+            // Side by side, centered vertically for two panes, or in a 2x2 grid for four
+            let x = (i % 2) * WIDTH / 2;
+            let y = if sims.len() == 2 { HEIGHT / 4 } else { (i / 2) * HEIGHT / 2 };
+            draw_downsampled(&mut buffer, &pane, 2, x, y);
+        }
+
+        window.set_title(&format!(
+            "N-Body Simulation (4K) - comparing integrators - Speed: {:.1}x (±) - ESC to exit",
+            time_multiplier
+        ));
+        if let Err(err) = window.update_with_buffer(&buffer, WIDTH, HEIGHT) {
+            eprintln!("Error: couldn't update the window: {}", err);
+            break;
+        }
+
+        if finished(&sims[0]) {
+            break;
+        }
+    }
+
+    for sim in &sims {
+        print!("{}: ", sim.config.integrator.name());
+        print!("{}", summary(sim, started));
+    }
+}
+
+/// Shrink a full-size `source` frame by `factor` with a box filter over each `factor` x
+/// `factor` block, with its top-left corner at (`x`, `y`) in `buffer`
+fn draw_downsampled(buffer: &mut [u32], source: &[u32], factor: usize, x: usize, y: usize) {
+    let area = (factor * factor) as u32;
+    for row in 0..HEIGHT / factor {
+        for column in 0..WIDTH / factor {
+            let top = factor * row * WIDTH + factor * column;
+            let mut sums = [0; 3];
+            for i in 0..factor * factor {
+                let pixel = source[top + i / factor * WIDTH + i % factor];
+                sums[0] += (pixel >> 16) & 0xFF;
+                sums[1] += (pixel >> 8) & 0xFF;
+                sums[2] += pixel & 0xFF;
+            }
+            buffer[(y + row) * WIDTH + x + column] = ((sums[0] / area) << 16) | ((sums[1] / area) << 8) | (sums[2] / area);
+        }
+    }
+}
+
+/// Render the view at `factor` times the screen resolution and filter it back down to screen
+/// size. The large image is drawn as `factor` x `factor` screen-sized tiles, each `draw`n
+/// through a camera zoomed in on its share of the view, so the usual drawing code works as is.
+fn render_supersampled(view: &Camera, factor: usize, draw: impl Fn(&mut [u32], &Camera)) -> Vec<u32> {
+    let mut image = vec![0; WIDTH * HEIGHT];
+    let mut tile = vec![0; WIDTH * HEIGHT];
+    let tile_size = Vec2::new(WIDTH as f32, HEIGHT as f32) / factor as f32;
+    for ty in 0..factor {
+        for tx in 0..factor {
+            let middle = (Vec2::new(tx as f32, ty as f32) + 0.5) * tile_size;
+            let camera = Camera { center: view.screen_to_world(middle), zoom: view.zoom * factor as f32 };
+            tile.fill(0x000008);
+            draw(&mut tile, &camera);
+            draw_downsampled(&mut image, &tile, factor, tx * WIDTH / factor, ty * HEIGHT / factor);
+        }
+    }
+    image
+}
+
+/// Whether the run has reached `--max-steps`
+fn finished(sim: &Simulation) -> bool {
+    sim.config.max_steps.is_some_and(|max_steps| sim.steps >= max_steps)
+}
+
+/// Save the bodies on screen as SVG circles, as seen through `camera`, optionally with a line
+/// along each velocity. Returns how many bodies were written.
+fn write_svg(path: &Path, camera: &Camera, bodies: &[Body], colors: &[u32], law: RadiusLaw, velocities: bool) -> std::io::Result<usize> {
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n\
+         <rect width=\"100%\" height=\"100%\" fill=\"#000008\"/>\n",
+        WIDTH, HEIGHT
+    );
+    let mut count = 0;
+    for (body, &color) in bodies.iter().zip(colors) {
+        let screen = camera.world_to_screen(body.pos);
+        let radius = (body.radius(law) * camera.scale()).max(0.5);
+        if screen.x < -radius || screen.y < -radius || screen.x > WIDTH as f32 + radius || screen.y > HEIGHT as f32 + radius {
+            continue;
+        }
+        let color = if body.central { 0xFFAA33 } else { color };
+        if velocities && body.vel != Vec2::ZERO {
+            let end = camera.world_to_screen(body.pos + body.vel * SVG_VELOCITY_TIME);
+            let _ = writeln!(
+                svg,
+                "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"#{:06x}\" stroke-opacity=\"0.6\"/>",
+                screen.x, screen.y, end.x, end.y, color
+            );
+        }
+        let _ = writeln!(svg, "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"{:.1}\" fill=\"#{:06x}\"/>", screen.x, screen.y, radius, color);
+        count += 1;
+    }
+    svg.push_str("</svg>\n");
+    std::fs::write(path, svg)?;
+    Ok(count)
+}
+
+/// Save the frame buffer as a binary PPM image
+fn write_ppm(path: &Path, buffer: &[u32]) -> std::io::Result<()> {
+    let mut data = format!("P6\n{} {}\n255\n", WIDTH, HEIGHT).into_bytes();
+    data.reserve(buffer.len() * 3);
+    for &pixel in buffer {
+        data.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+    }
+    std::fs::write(path, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two bodies on a circular orbit about their center of mass at the origin, and the
+    /// orbit's angular velocity
+    fn circular_pair(masses: [f32; 2], separation: f32, g: f32) -> (Vec<Body>, f32) {
+        let total = masses[0] + masses[1];
+        let omega = (g * total / separation.powi(3)).sqrt();
+        let bodies = [(masses[0], -masses[1] / total), (masses[1], masses[0] / total)]
+            .into_iter()
+            .map(|(mass, share)| {
+                let pos = Vec2::new(separation * share, 0.0);
+                Body::new(pos, Vec2::new(0.0, omega * pos.x), mass)
+            })
+            .collect();
+        (bodies, omega)
+    }
+
+    #[test]
+    fn hermite_follows_the_analytic_two_body_orbit() {
+        let config = Config { integrator: Integrator::Hermite, softening: 0.0, speed_limit: SpeedLimit::Off, ..Config::default() };
+        let (mut bodies, omega) = circular_pair([1000.0, 10.0], 200.0, config.g);
+        let steps = 2000;
+        let dt = std::f32::consts::TAU / omega / steps as f32;
+        for step in 1..=steps {
+            super::step(&mut bodies, config.gravity(1.0), dt, &config, &mut None);
+            if step % 250 == 0 {
+                let angle = omega * dt * step as f32;
+                let expected = 200.0 * Vec2::new(angle.cos(), angle.sin());
+                let separation = bodies[1].pos - bodies[0].pos;
+                assert!(
+                    separation.distance(expected) < 1e-3 * 200.0,
+                    "after {} steps the separation is {} instead of {}",
+                    step,
+                    separation,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn merging_and_capping_keep_momentum_under_every_integrator() {
+        for integrator in [Integrator::Euler, Integrator::Verlet, Integrator::Hermite] {
+            let config = Config {
+                seed: Some(7),
+                integrator,
+                merge: true,
+                max_bodies: Some(150),
+                reuse_forces: true,
+                speed_limit: SpeedLimit::Off,
+                ..Config::default()
+            };
+            let mut sim = Simulation::new(config).unwrap();
+            let (initial, mass) = (Conserved::measure(&sim.bodies, sim.gravity()), sim.bodies.iter().map(|body| body.mass).sum::<f32>());
+            let count = sim.bodies.len();
+            for _ in 0..60 {
+                sim.step(sim.config.dt);
+            }
+            // Touching bodies merge right away, and the cap merges the closest pairs after that
+            assert!(sim.bodies.len() <= 150, "{}: {} of {} bodies left over the cap", integrator.name(), sim.bodies.len(), count);
+            let drift = (diagnostics::momentum(&sim.bodies) - initial.momentum).length() / initial.momentum_scale;
+            assert!(drift < 1e-4, "{}: momentum drifted by {:e}", integrator.name(), drift);
+            let total: f32 = sim.bodies.iter().map(|body| body.mass).sum();
+            assert!((total - mass).abs() < 1e-3 * mass, "{}: mass went from {} to {}", integrator.name(), mass, total);
+        }
+    }
+
+    #[test]
+    fn written_settings_reload_to_the_same_state() {
+        let config = Config {
+            seed: Some(11),
+            sponge: Sponge { width: 120.0, strength: 0.5 },
+            cooling: 0.02,
+            species: Species { fraction: 0.25, mass_range: (2.0, 8.0), color: 0x40a0ff },
+            coupling: [1.0, 0.5, 2.0],
+            tide: [0.001, -0.0005, 0.002],
+            mass_distribution: MassDistribution::PowerLaw { slope: SALPETER_SLOPE },
+            orbit_speed_range: (0.9, 1.1),
+            eccentricity_range: (-0.2, 0.3),
+            min_separation: Some(4.0),
+            escape_radius: 3000.0,
+            remove_escapers: true,
+            max_bodies: Some(400),
+            cap_merge_rate: 3,
+            units: Units::physical().with_time_unit("days", 25.0),
+            ..Config::default()
+        };
+        let sim = Simulation::new(config).unwrap();
+        let path = std::env::temp_dir().join(format!("nbody-settings-{}.toml", std::process::id()));
+        settings::write_settings(&path, &sim.settings()).unwrap();
+        let args = settings::read_settings(&path);
+        std::fs::remove_file(&path).unwrap();
+        let reloaded = Simulation::new(Config::from_args("headless", args.unwrap().into_iter()).unwrap()).unwrap();
+
+        assert_eq!(reloaded.settings(), sim.settings());
+        assert_eq!(reloaded.bodies.len(), sim.bodies.len());
+        for (body, original) in reloaded.bodies.iter().zip(&sim.bodies) {
+            assert_eq!((body.pos, body.vel, body.mass), (original.pos, original.vel, original.mass));
+        }
+    }
+
+    #[test]
+    fn a_body_absorbing_a_central_body_becomes_central() {
+        let mut body = Body::new(Vec2::new(1.0, 0.0), Vec2::ZERO, MIN_MASS);
+        body.absorb(&Body::central());
+        assert!(body.central);
+        assert_eq!(body.mass, MIN_MASS + CENTRAL_MASS);
+    }
+
+    #[test]
+    fn elastic_walls_conserve_energy() {
+        // A loose swarm fast enough that most of it reaches a wall several times
+        let mut rng = StdRng::seed_from_u64(3);
+        let bounds = Vec2::new(WIDTH as f32, HEIGHT as f32) / 2.0 * SPACE_SCALE;
+        let swarm: Vec<Body> = (0..100)
+            .map(|_| {
+                let pos = Vec2::new(rng.gen_range(-bounds.x..bounds.x), rng.gen_range(-bounds.y..bounds.y)) * 0.9;
+                let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+                Body::new(pos, 1500.0 * Vec2::new(angle.cos(), angle.sin()), rng.gen_range(1.0..5.0))
+            })
+            .collect();
+
+        let energy_error = |wall_bounce| {
+            let config = Config { wall_bounce, integrator: Integrator::Verlet, speed_limit: SpeedLimit::Off, ..Config::default() };
+            let gravity = config.gravity(1.0);
+            let mut bodies = swarm.clone();
+            let initial = diagnostics::total_energy(&bodies, gravity);
+            for _ in 0..500 {
+                super::step(&mut bodies, gravity, config.dt, &config, &mut None);
+            }
+            (diagnostics::total_energy(&bodies, gravity) - initial) / initial
+        };
+        let elastic = energy_error(WallBounce::Elastic);
+        assert!(elastic.abs() < 1e-4, "elastic walls changed the energy by {:e}", elastic);
+        // The same run with the default walls shows the bounces happened
+        let damped = energy_error(WallBounce::Damped);
+        assert!(damped < -0.5, "damped walls only changed the energy by {:e}", damped);
+    }
+
+    #[test]
+    fn a_fast_flyby_is_deflected_by_the_analytic_angle() {
+        let (impact_parameter, speed, reach) = (100.0, 400.0, 1800.0);
+        for integrator in [Integrator::Euler, Integrator::Verlet, Integrator::Hermite] {
+            let config = Config { integrator, softening: 0.0, speed_limit: SpeedLimit::Off, ..Config::default() };
+            let mut bodies = vec![
+                Body { is_static: true, ..Body::central() },
+                Body::new(Vec2::new(-reach, impact_parameter), Vec2::new(speed, 0.0), MIN_MASS),
+            ];
+            while bodies[1].pos.x < reach {
+                super::step(&mut bodies, config.gravity(1.0), config.dt, &config, &mut None);
+            }
+            // Rutherford's angle for a path from infinity, cut down to the stretch within the
+            // reach the way the small-angle impulse along a straight line is
+            let focus = config.g * CENTRAL_MASS / (impact_parameter * speed * speed);
+            let expected = 2.0 * focus.atan() * reach / reach.hypot(impact_parameter);
+            let deflection = -bodies[1].vel.y.atan2(bodies[1].vel.x);
+            assert!(
+                (deflection - expected).abs() < 0.01 * expected,
+                "{}: deflected by {} instead of {}",
+                integrator.name(),
+                deflection,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn coincident_bodies_give_finite_forces() {
+        let mut bodies: Vec<Body> = (0..3).map(|_| Body::new(Vec2::new(50.0, -20.0), Vec2::new(1.0, 2.0), 3.0)).collect();
+        bodies.push(Body::new(Vec2::new(-300.0, 40.0), Vec2::ZERO, 7.0));
+        for softening in [0.0, SOFTENING] {
+            let config = Config { softening, ..Config::default() };
+            let gravity = config.gravity(1.0);
+            for theta in [0.0, 0.5] {
+                let (forces, _, _) = calculate_forces(&bodies, gravity, theta, config.tree_limits);
+                assert!(forces.iter().all(|force| force.is_finite()), "theta {}, softening {}: {:?}", theta, softening, forces);
+            }
+            let jerks = calculate_forces_and_jerk(&bodies, gravity);
+            assert!(jerks.iter().all(|(force, jerk)| force.is_finite() && jerk.is_finite()), "softening {}: {:?}", softening, jerks);
+            assert!(diagnostics::total_energy(&bodies, gravity).is_finite());
+        }
+    }
+
+    #[test]
+    fn verlet_conserves_the_energy_of_a_softened_binary() {
+        // An eccentric binary whose closest approach is well inside the softening length, where
+        // an energy that doesn't match the softened force law would drift the most
+        let config = Config { integrator: Integrator::Verlet, theta: 0.0, speed_limit: SpeedLimit::Off, ..Config::default() };
+        let gravity = config.gravity(1.0);
+        let (mass, separation) = (5.0, 6.0);
+        let circular = (config.g * 2.0 * mass * separation / (separation * separation + config.softening * config.softening)).sqrt();
+        let mut bodies: Vec<Body> = [-0.5, 0.5]
+            .into_iter()
+            .map(|share| Body::new(Vec2::new(separation * share, 0.0), Vec2::new(0.0, 0.4 * circular * share), mass))
+            .collect();
+        let initial = diagnostics::total_energy(&bodies, gravity);
+        let mut closest = separation;
+        for _ in 0..2000 {
+            super::step(&mut bodies, gravity, 0.01, &config, &mut None);
+            closest = closest.min(bodies[0].pos.distance(bodies[1].pos));
+            let error = (diagnostics::total_energy(&bodies, gravity) - initial) / initial;
+            assert!(error.abs() < 1e-3, "the energy drifted by {:e}", error);
+        }
+        assert!(closest < config.softening / 2.0, "the closest approach was only {}", closest);
+    }
+
+    #[test]
+    fn the_same_seed_merges_the_same_way() {
+        let run = || {
+            // Touching bodies merge, and then the cap merges the closest pairs
+            let config = Config { seed: Some(21), merge: true, max_bodies: Some(150), ..Config::default() };
+            let mut sim = Simulation::new(config).unwrap();
+            for _ in 0..60 {
+                sim.step(sim.config.dt);
+            }
+            sim.bodies.iter().map(|body| (body.pos, body.vel, body.mass)).collect::<Vec<_>>()
+        };
+        let first = run();
+        assert!(first.len() <= 150, "only merged down to {} bodies", first.len());
+        assert!(first == run(), "two runs with the same seed merged differently");
+    }
+
+    #[test]
+    fn off_screen_bodies_draw_no_pixels() {
+        let mut camera = Camera::new();
+        camera.zoom_at(Vec2::new(WIDTH as f32, HEIGHT as f32) / 2.0, 4.0);
+        let law = Config::default().radius_law;
+        let edge = camera.screen_to_world(Vec2::ZERO).abs();
+        let outside = [
+            Vec2::new(edge.x * 1.1, 0.0),
+            Vec2::new(-edge.x * 1.1, 0.0),
+            Vec2::new(0.0, edge.y * 1.1),
+            Vec2::new(0.0, -edge.y * 1.1),
+            Vec2::new(-edge.x * 1.1, edge.y * 1.1),
+            Vec2::splat(1e30),
+        ];
+        let bodies: Vec<Body> = outside.iter().map(|&pos| Body::new(pos, Vec2::ZERO, 5.0)).collect();
+        let colors = vec![0xFFFFFF; bodies.len()];
+        let mut buffer = vec![0; WIDTH * HEIGHT];
+        for lod_radius in [0.0, 1000.0] {
+            draw_bodies(&mut buffer, &camera, &bodies, &colors, law, lod_radius, &[]);
+        }
+        let central = Body { pos: Vec2::splat(-1e30), ..Body::central() };
+        draw_bodies(&mut buffer, &camera, &[central], &[0], law, 0.0, &[GlowLayer { radius: 3.0, intensity: 1.0 }]);
+        assert!(buffer.iter().all(|&pixel| pixel == 0), "{} pixels were drawn", buffer.iter().filter(|&&pixel| pixel != 0).count());
+
+        // A body straddling the edge is still drawn
+        let straddling = Body::new(Vec2::new(edge.x, 0.0), Vec2::ZERO, 5.0);
+        draw_bodies(&mut buffer, &camera, &[straddling], &colors, law, 0.0, &[]);
+        assert!(buffer.iter().any(|&pixel| pixel != 0));
+    }
+
+    #[test]
+    fn reused_verlet_forces_match_recomputed_ones() {
+        let run = |reuse_forces| {
+            // Merges invalidate the cache now and then, so both paths get taken
+            let config = Config { seed: Some(5), integrator: Integrator::Verlet, merge: true, reuse_forces, ..Config::default() };
+            let mut sim = Simulation::new(config).unwrap();
+            let mut reused = 0;
+            for _ in 0..50 {
+                reused += sim.force_cache.as_ref().is_some_and(|cache| cache.state == ForceCache::state_of(&sim.bodies)) as usize;
+                sim.step(sim.config.dt);
+            }
+            assert_eq!(reused > 0, reuse_forces, "the cached forces were used {} times", reused);
+            sim.bodies.iter().map(|body| (body.pos, body.vel, body.mass)).collect::<Vec<_>>()
+        };
+        assert!(run(true) == run(false), "reusing the forces changed the result");
+    }
+
+    #[test]
+    fn grid_merges_match_a_brute_force_pass() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let crowd: Vec<Body> = (0..2000)
+            .map(|_| Body::new(Vec2::new(rng.gen_range(-200.0..200.0), rng.gen_range(-200.0..200.0)), Vec2::ZERO, rng.gen_range(1.0..20.0)))
+            .collect();
+        let law = Config::default().radius_law;
+        for accretion_scale in [0.5, 1.0, 2.0] {
+            let mut merged = crowd.clone();
+            merge_collisions(&mut merged, accretion_scale, law);
+
+            // Every body takes in each later one it touches by then, in index order
+            let mut expected = crowd.clone();
+            let mut absorbed = vec![false; expected.len()];
+            for i in 0..expected.len() {
+                for j in i + 1..expected.len() {
+                    let reach = (expected[i].radius(law) + expected[j].radius(law)) * accretion_scale;
+                    if !absorbed[i] && !absorbed[j] && expected[i].pos.distance_squared(expected[j].pos) < reach * reach {
+                        let other = expected[j].clone();
+                        expected[i].absorb(&other);
+                        absorbed[j] = true;
+                    }
+                }
+            }
+            let expected: Vec<_> = expected.iter().zip(&absorbed).filter(|(_, &gone)| !gone).map(|(body, _)| (body.id, body.pos, body.mass)).collect();
+
+            assert!(expected.len() < crowd.len() / 2, "too sparse to test: {} bodies left", expected.len());
+            let merged: Vec<_> = merged.iter().map(|body| (body.id, body.pos, body.mass)).collect();
+            assert!(merged == expected, "scale {}: the grid left {} bodies, brute force {}", accretion_scale, merged.len(), expected.len());
+        }
+    }
+
+    #[test]
+    #[ignore = "100k steps; run with cargo test --release -- --ignored"]
+    fn zero_momentum_keeps_the_drift_near_zero_over_long_runs() {
+        let config = Config { seed: Some(13), zero_momentum: true, ..Config::default() };
+        let bodies = generate_bodies(&config).unwrap().into_iter().take(40).collect();
+        let mut sim = Simulation::with_bodies(bodies, config);
+        remove_net_momentum(&mut sim.bodies);
+        let (scale, center) = (Conserved::measure(&sim.bodies, sim.gravity()).momentum_scale, diagnostics::center_of_mass(&sim.bodies));
+        for step in 1..=100_000 {
+            sim.step(sim.config.dt);
+            if step % 1000 == 0 {
+                let drift = diagnostics::momentum(&sim.bodies).length() / scale;
+                assert!(drift < 1e-5, "after {} steps the net momentum is {:e} of the total", step, drift);
+                let moved = diagnostics::center_of_mass(&sim.bodies).distance(center);
+                assert!(moved < 1.0, "after {} steps the center of mass moved by {}", step, moved);
+            }
+        }
+    }
+
+    #[test]
+    fn sampled_masses_follow_their_distribution() {
+        let (min, max) = (MIN_MASS, MAX_MASS);
+        let cdf = |distribution, mass: f32| match distribution {
+            MassDistribution::Uniform => (mass - min) / (max - min),
+            MassDistribution::LogUniform | MassDistribution::PowerLaw { slope: 1.0 } => (mass / min).ln() / (max / min).ln(),
+            MassDistribution::PowerLaw { slope } => {
+                let exponent = 1.0 - slope;
+                (mass.powf(exponent) - min.powf(exponent)) / (max.powf(exponent) - min.powf(exponent))
+            }
+        };
+        // Kolmogorov-Smirnov distance between the samples and a cumulative distribution
+        let distance = |samples: &[f32], distribution| {
+            samples
+                .iter()
+                .enumerate()
+                .map(|(i, &mass)| {
+                    let expected = cdf(distribution, mass);
+                    (expected - i as f32 / samples.len() as f32).abs().max((expected - (i + 1) as f32 / samples.len() as f32).abs())
+                })
+                .fold(0.0, f32::max)
+        };
+
+        let count = 20_000;
+        // The distance a correct sampler stays under 99.9% of the time
+        let critical = 1.95 / (count as f32).sqrt();
+        let distributions = [
+            MassDistribution::Uniform,
+            MassDistribution::LogUniform,
+            MassDistribution::PowerLaw { slope: SALPETER_SLOPE },
+            MassDistribution::PowerLaw { slope: 1.0 },
+            MassDistribution::PowerLaw { slope: -1.0 },
+        ];
+        let mut rng = StdRng::seed_from_u64(17);
+        for (i, &distribution) in distributions.iter().enumerate() {
+            let mut samples: Vec<f32> = (0..count).map(|_| distribution.sample(&mut rng, min, max)).collect();
+            samples.sort_by(f32::total_cmp);
+            assert!(samples.iter().all(|mass| (min..=max).contains(mass)));
+            let fit = distance(&samples, distribution);
+            assert!(fit < critical, "distribution {}: KS distance {} over {}", i, fit, critical);
+            // And the test tells the shapes apart
+            let other = if i == 0 { MassDistribution::LogUniform } else { MassDistribution::Uniform };
+            assert!(distance(&samples, other) > 10.0 * critical, "distribution {} passes for another one", i);
+        }
+    }
+
+    #[test]
+    fn console_commands_reject_unusable_values() {
+        let mut sim = Simulation::with_bodies(vec![Body::central()], Config { seed: Some(1), ..Config::default() });
+        let mut time_multiplier = 1.0;
+        for line in ["g -1", "g NaN", "g inf", "speed -1", "speed 0", "dt inf", "spawn 1e12", "spawn 20000", "spawn -3", "spawn 2 NaN"] {
+            assert!(run_command(line, &mut sim, &mut time_multiplier).is_err(), "'{}' was accepted", line);
+        }
+        assert_eq!((sim.gravity_multiplier, sim.bodies.len(), time_multiplier), (1.0, 1, 1.0));
+
+        run_command("g 0", &mut sim, &mut time_multiplier).unwrap();
+        run_command("spawn 25 3", &mut sim, &mut time_multiplier).unwrap();
+        assert_eq!((sim.gravity_multiplier, sim.bodies.len()), (0.0, 26));
+    }
+
+    #[test]
+    fn out_of_range_option_values_are_rejected() {
+        let parse = |command: &str, option: &str, value: &str| Config::from_args(command, [option.to_string(), value.to_string()].into_iter());
+        for (option, bad) in [
+            ("--accretion-scale", &["0", "-1", "inf", "NaN"][..]),
+            ("--escape-radius", &["0", "-5", "inf", "NaN"]),
+            ("--velocity-dispersion", &["-1", "inf", "NaN"]),
+            ("--max-bodies", &["0", "-2"]),
+            ("--g", &["-1", "inf", "NaN"]),
+            ("--force-exponent", &["0", "-2", "inf", "NaN"]),
+            ("--spin", &["inf", "-inf", "NaN"]),
+        ] {
+            for value in bad {
+                assert!(parse("headless", option, value).is_err(), "{} {} was accepted", option, value);
+            }
+        }
+        for value in ["-0.5", "inf", "NaN"] {
+            assert!(parse("run", "--encounter-flash", value).is_err(), "--encounter-flash {} was accepted", value);
+        }
+        assert!(parse("headless", "--velocity-dispersion", "0").is_ok());
+        assert!(parse("run", "--encounter-flash", "0").is_ok());
+    }
+
+    #[test]
+    fn long_trails_only_store_what_they_record() {
+        let bodies: Vec<Body> = (0..500).map(|i| Body::new(Vec2::new(i as f32, 0.0), Vec2::ZERO, MIN_MASS)).collect();
+        let mut trails = Trails::new(&bodies, 10_000_000, None);
+        for _ in 0..3 {
+            trails.record(&bodies, Camera::new());
+        }
+        assert!(trails.points.values().all(|trail| trail.len() == 3 && trail.capacity() < 1000));
+
+        // Short trails still drop their oldest positions
+        let mut trails = Trails::new(&bodies, 2, Some(10));
+        for _ in 0..3 {
+            trails.record(&bodies, Camera::new());
+        }
+        assert_eq!(trails.points.len(), 10);
+        assert!(trails.points.values().all(|trail| trail.len() == 2) && trails.cameras.len() == 2);
+    }
+
+    #[test]
+    fn static_bodies_never_move() {
+        // One in the sponge layer by a wall, one in the disk and one with a satellite on top of it
+        let statics = [Vec2::new(1900.0, 0.0), Vec2::new(-500.0, 300.0), Vec2::new(200.0, -100.0)];
+        for integrator in [Integrator::Euler, Integrator::Verlet, Integrator::Hermite] {
+            let config = Config {
+                seed: Some(4),
+                integrator,
+                merge: true,
+                zero_momentum: true,
+                wall_bounce: WallBounce::Elastic,
+                sponge: Sponge { width: 120.0, strength: 2.0 },
+                cooling: 0.5,
+                reuse_forces: true,
+                ..Config::default()
+            };
+            let mut bodies: Vec<Body> = statics.iter().map(|&pos| Body { is_static: true, ..Body::new(pos, Vec2::ZERO, 50.0) }).collect();
+            bodies.push(Body::new(statics[2] + Vec2::new(1.0, 0.0), Vec2::new(0.0, 30.0), 5.0));
+            bodies.extend(generate_bodies(&config).unwrap().into_iter().take(60));
+            let mut sim = Simulation::with_bodies(bodies, config);
+            for _ in 0..2000 {
+                sim.step(sim.config.dt);
+            }
+
+            let after: Vec<(Vec2, Vec2)> = sim.bodies.iter().filter(|body| body.is_static).map(|body| (body.pos, body.vel)).collect();
+            let before: Vec<(Vec2, Vec2)> = statics.iter().map(|&pos| (pos, Vec2::ZERO)).collect();
+            assert!(after == before, "{}: the static bodies went from {:?} to {:?}", integrator.name(), before, after);
+            assert!(sim.bodies.len() < 64, "{}: nothing merged", integrator.name());
+        }
+    }
+
+    #[test]
+    fn spin_sets_the_sign_of_the_angular_momentum() {
+        // Without a central body the bodies start with random velocities and next to no net spin
+        for central_body in [false, true] {
+            let angular_momentum = |spin| {
+                let config = Config { seed: Some(8), central_body, spin, ..Config::default() };
+                diagnostics::angular_momentum(&generate_bodies(&config).unwrap())
+            };
+            let (still, clockwise, counterclockwise) = (angular_momentum(0.0), angular_momentum(-0.1), angular_momentum(0.1));
+            assert!(clockwise < still && still < counterclockwise, "{} < {} < {}", clockwise, still, counterclockwise);
+            if !central_body {
+                assert!(clockwise < 0.0 && counterclockwise > 0.0, "spins of -0.1 and 0.1 gave {} and {}", clockwise, counterclockwise);
+            }
+        }
     }
 }
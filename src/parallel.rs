@@ -0,0 +1,75 @@
+//! Data-parallel iteration. With the `parallel` feature (on by default) this is just rayon;
+//! without it the same method names hand back the plain serial iterators, so the number
+//! crunching compiles unchanged and runs on one thread in a fixed order.
+
+#[cfg(feature = "parallel")]
+pub use rayon::prelude;
+
+#[cfg(not(feature = "parallel"))]
+pub mod prelude {
+    /// `into_par_iter` for anything iterable
+    pub trait IntoParallelIterator: IntoIterator + Sized {
+        fn into_par_iter(self) -> Self::IntoIter {
+            self.into_iter()
+        }
+    }
+
+    impl<T: IntoIterator> IntoParallelIterator for T {}
+
+    pub trait ParallelSlice<T> {
+        fn par_iter(&self) -> std::slice::Iter<'_, T>;
+        fn par_chunks(&self, size: usize) -> std::slice::Chunks<'_, T>;
+    }
+
+    impl<T> ParallelSlice<T> for [T] {
+        fn par_iter(&self) -> std::slice::Iter<'_, T> {
+            self.iter()
+        }
+
+        fn par_chunks(&self, size: usize) -> std::slice::Chunks<'_, T> {
+            self.chunks(size)
+        }
+    }
+
+    pub trait ParallelSliceMut<T> {
+        fn par_iter_mut(&mut self) -> std::slice::IterMut<'_, T>;
+        fn par_chunks_mut(&mut self, size: usize) -> std::slice::ChunksMut<'_, T>;
+        fn par_sort_unstable_by_key<K: Ord>(&mut self, key: impl FnMut(&T) -> K);
+    }
+
+    impl<T> ParallelSliceMut<T> for [T] {
+        fn par_iter_mut(&mut self) -> std::slice::IterMut<'_, T> {
+            self.iter_mut()
+        }
+
+        fn par_chunks_mut(&mut self, size: usize) -> std::slice::ChunksMut<'_, T> {
+            self.chunks_mut(size)
+        }
+
+        fn par_sort_unstable_by_key<K: Ord>(&mut self, key: impl FnMut(&T) -> K) {
+            self.sort_unstable_by_key(key)
+        }
+    }
+}
+
+/// Threads the parallel work is spread over
+pub fn current_num_threads() -> usize {
+    #[cfg(feature = "parallel")]
+    return rayon::current_num_threads();
+    #[cfg(not(feature = "parallel"))]
+    return 1;
+}
+
+/// Size the global thread pool for `--threads`. A serial build only ever uses one, so
+/// asking for more is a warning rather than an error.
+pub fn set_threads(threads: usize) {
+    #[cfg(feature = "parallel")]
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .expect("failed to configure the rayon thread pool");
+    #[cfg(not(feature = "parallel"))]
+    if threads > 1 {
+        eprintln!("Warning: built without the parallel feature, so --threads {} has no effect", threads);
+    }
+}
@@ -0,0 +1,389 @@
+use crate::{parse_color, Body};
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::io::{self, StdoutLock, Write as _};
+use std::path::Path;
+
+/// One body as stored in a body file
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct BodyRecord {
+    x: f32,
+    y: f32,
+    vx: f32,
+    vy: f32,
+    mass: f32,
+    /// Static bodies pull on the others but never move
+    #[serde(default, rename = "static", skip_serializing_if = "std::ops::Not::not")]
+    is_static: bool,
+    /// Drawn as the central star
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    central: bool,
+    /// 1 for the second species
+    #[serde(default, skip_serializing_if = "is_first_species")]
+    species: u8,
+    /// Fixed color instead of the one that follows from the mass
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color: Option<ColorValue>,
+}
+
+/// A color as given in a body file: an `RRGGBB` hex string (with or without `#`), or the
+/// packed `0xRRGGBB` integer
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum ColorValue {
+    Packed(u32),
+    Hex(String),
+}
+
+impl ColorValue {
+    fn rgb(&self) -> Result<u32, String> {
+        match self {
+            ColorValue::Packed(color) if *color <= 0xFFFFFF => Ok(*color),
+            ColorValue::Packed(color) => Err(format!("color {} is more than 0xFFFFFF", color)),
+            ColorValue::Hex(hex) => parse_color(hex).ok_or(format!("color '{}' isn't an RRGGBB hex color", hex)),
+        }
+    }
+
+    /// Parse a CSV cell: `#RRGGBB` or `0xRRGGBB` in hex, otherwise a decimal packed integer
+    fn from_cell(cell: &str) -> Result<Self, String> {
+        let color = if cell.starts_with('#') {
+            ColorValue::Hex(cell.to_string())
+        } else if let Some(hex) = cell.strip_prefix("0x") {
+            ColorValue::Packed(u32::from_str_radix(hex, 16).map_err(|_| format!("color '{}' isn't a hex number", cell))?)
+        } else {
+            ColorValue::Packed(cell.parse().map_err(|_| format!("color '{}' isn't #RRGGBB or a packed integer", cell))?)
+        };
+        color.rgb().map(|_| color)
+    }
+}
+
+fn is_first_species(species: &u8) -> bool {
+    *species == 0
+}
+
+impl BodyRecord {
+    fn into_body(self) -> Result<Body, String> {
+        if self.mass <= 0.0 {
+            return Err("mass must be positive".to_string());
+        }
+        if self.species > 1 {
+            return Err("species must be 0 or 1".to_string());
+        }
+        let vel = if self.is_static { Vec2::ZERO } else { Vec2::new(self.vx, self.vy) };
+        let body = Body::new(Vec2::new(self.x, self.y), vel, self.mass);
+        let color = self.color.as_ref().map(ColorValue::rgb).transpose()?;
+        Ok(Body {
+            is_static: self.is_static,
+            central: self.central,
+            species: self.species,
+            color: color.unwrap_or(body.color),
+            custom_color: color.is_some(),
+            ..body
+        })
+    }
+
+    fn from_body(body: &Body) -> Self {
+        BodyRecord {
+            x: body.pos.x,
+            y: body.pos.y,
+            vx: body.vel.x,
+            vy: body.vel.y,
+            mass: body.mass,
+            is_static: body.is_static,
+            central: body.central,
+            species: body.species,
+            color: body.custom_color.then(|| ColorValue::Hex(format!("#{:06x}", body.color))),
+        }
+    }
+}
+
+const CSV_HEADER: &str = "x,y,vx,vy,mass";
+/// Columns that may follow the required ones, in any order: a 0/1 flag for static bodies,
+/// and a color (empty for the mass-based one)
+const CSV_OPTIONAL_COLUMNS: [&str; 2] = ["static", "color"];
+
+/// Read bodies from a `.json` or `.csv` file, depending on its extension
+pub fn read_bodies(path: &Path) -> Result<Vec<Body>, String> {
+    match extension(path) {
+        Some("json") => read_json(path),
+        Some("csv") => read_csv(path),
+        _ => Err(format!("{}: expected a .json or .csv file", path.display())),
+    }
+}
+
+/// Write bodies to a `.json` or `.csv` file, depending on its extension
+pub fn write_bodies(path: &Path, bodies: &[Body]) -> Result<(), String> {
+    let text = match extension(path) {
+        Some("json") => {
+            let records: Vec<BodyRecord> = bodies.iter().map(BodyRecord::from_body).collect();
+            serde_json::to_string_pretty(&records).map_err(|err| err.to_string())?
+        }
+        Some("csv") => {
+            let with_static = bodies.iter().any(|body| body.is_static);
+            let with_color = bodies.iter().any(|body| body.custom_color);
+            let mut text = CSV_HEADER.to_string();
+            for (column, used) in CSV_OPTIONAL_COLUMNS.iter().zip([with_static, with_color]) {
+                if used {
+                    let _ = write!(text, ",{}", column);
+                }
+            }
+            text.push('\n');
+            for body in bodies {
+                let _ = write!(text, "{},{},{},{},{}", body.pos.x, body.pos.y, body.vel.x, body.vel.y, body.mass);
+                if with_static {
+                    let _ = write!(text, ",{}", body.is_static as u8);
+                }
+                if with_color {
+                    text.push(',');
+                    if body.custom_color {
+                        let _ = write!(text, "#{:06x}", body.color);
+                    }
+                }
+                text.push('\n');
+            }
+            text
+        }
+        _ => return Err(format!("{}: expected a .json or .csv file", path.display())),
+    };
+    std::fs::write(path, text).map_err(|err| format!("couldn't write {}: {}", path.display(), err))
+}
+
+/// Read an array of `{"x", "y", "vx", "vy", "mass"}` objects, with an optional `"static"` flag
+/// and `"color"`. Errors name the offending element.
+pub fn read_json(path: &Path) -> Result<Vec<Body>, String> {
+    let text = read(path)?;
+    let elements: Vec<serde_json::Value> =
+        serde_json::from_str(&text).map_err(|err| format!("{}: expected an array of bodies: {}", path.display(), err))?;
+
+    elements
+        .into_iter()
+        .enumerate()
+        .map(|(i, element)| {
+            BodyRecord::deserialize(element)
+                .map_err(|err| err.to_string())
+                .and_then(BodyRecord::into_body)
+                .map_err(|err| format!("{}: body {}: {}", path.display(), i, err))
+        })
+        .collect()
+}
+
+/// Read rows of `x,y,vx,vy,mass` below a header line, optionally followed by `static` and
+/// `color` columns. Errors name the offending line.
+pub fn read_csv(path: &Path) -> Result<Vec<Body>, String> {
+    let text = read(path)?;
+    let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    let header = lines.next().map(|(_, header)| header.replace(' ', "")).unwrap_or_default();
+    let extra: Vec<&str> = match header.strip_prefix(CSV_HEADER) {
+        Some("") => Vec::new(),
+        Some(rest) if rest.starts_with(',') => rest[1..].split(',').collect(),
+        _ => return Err(format!("{}: expected a '{}' header", path.display(), CSV_HEADER)),
+    };
+    for (i, column) in extra.iter().enumerate() {
+        if !CSV_OPTIONAL_COLUMNS.contains(column) || extra[..i].contains(column) {
+            return Err(format!(
+                "{}: unexpected column '{}' (after '{}' only {} may follow, once each)",
+                path.display(),
+                column,
+                CSV_HEADER,
+                CSV_OPTIONAL_COLUMNS.join(" and ")
+            ));
+        }
+    }
+
+    lines
+        .map(|(i, line)| {
+            let cells: Vec<&str> = line.split(',').map(str::trim).collect();
+            let record = if cells.len() != 5 + extra.len() {
+                Err(format!("expected {} values", 5 + extra.len()))
+            } else {
+                parse_row(&cells, &extra)
+            };
+            record
+                .and_then(BodyRecord::into_body)
+                .map_err(|err| format!("{}: line {}: {}", path.display(), i + 1, err))
+        })
+        .collect()
+}
+
+/// One CSV row of the five required values followed by the `extra` columns
+fn parse_row(cells: &[&str], extra: &[&str]) -> Result<BodyRecord, String> {
+    let numbers = cells[..5].iter().map(|cell| cell.parse::<f32>()).collect::<Result<Vec<_>, _>>().map_err(|err| err.to_string())?;
+    let [x, y, vx, vy, mass] = numbers[..] else { unreachable!() };
+    let mut record = BodyRecord { x, y, vx, vy, mass, is_static: false, central: false, species: 0, color: None };
+    for (&column, &cell) in extra.iter().zip(&cells[5..]) {
+        match column {
+            "static" => {
+                record.is_static = match cell.parse::<f32>() {
+                    Ok(flag) if flag == 0.0 || flag == 1.0 => flag == 1.0,
+                    _ => return Err("expected a 0 or 1 static flag".to_string()),
+                }
+            }
+            _ if cell.is_empty() => {}
+            _ => record.color = Some(ColorValue::from_cell(cell)?),
+        }
+    }
+    Ok(record)
+}
+
+/// Version of the save file format. Bump it whenever the format changes, and teach
+/// `read_save` to migrate (or reject) the older version.
+const SAVE_VERSION: u32 = 2;
+
+/// Progress of a simulation to resume from
+pub struct SavedState {
+    pub steps: u64,
+    pub time: f32,
+    pub gravity_multiplier: f32,
+    pub bodies: Vec<Body>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct SaveFile {
+    version: u32,
+    steps: u64,
+    time: f32,
+    gravity_multiplier: f32,
+    bodies: Vec<BodyRecord>,
+}
+
+pub fn write_save(path: &Path, state: &SavedState) -> Result<(), String> {
+    let save = SaveFile {
+        version: SAVE_VERSION,
+        steps: state.steps,
+        time: state.time,
+        gravity_multiplier: state.gravity_multiplier,
+        bodies: state.bodies.iter().map(BodyRecord::from_body).collect(),
+    };
+    let text = serde_json::to_string_pretty(&save).map_err(|err| err.to_string())?;
+    std::fs::write(path, text).map_err(|err| format!("couldn't write {}: {}", path.display(), err))
+}
+
+/// Read a save file. A bare array of bodies (a body file, the unversioned format saves
+/// started out as) loads as a fresh start; newer versions than this build knows are rejected.
+pub fn read_save(path: &Path) -> Result<SavedState, String> {
+    let text = read(path)?;
+    let value: serde_json::Value =
+        serde_json::from_str(&text).map_err(|err| format!("{}: not a save file: {}", path.display(), err))?;
+    if value.is_array() {
+        let bodies = read_json(path)?;
+        return Ok(SavedState { steps: 0, time: 0.0, gravity_multiplier: 1.0, bodies });
+    }
+
+    match value.get("version").and_then(|version| version.as_u64()) {
+        None => return Err(format!("{}: not a save file (no version)", path.display())),
+        Some(version) if version > SAVE_VERSION as u64 => {
+            return Err(format!(
+                "{}: saved in format version {}, but this build only reads up to version {}",
+                path.display(),
+                version,
+                SAVE_VERSION
+            ))
+        }
+        Some(_) => {}
+    }
+    let save = SaveFile::deserialize(value).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let bodies = save
+        .bodies
+        .into_iter()
+        .enumerate()
+        .map(|(i, record)| record.into_body().map_err(|err| format!("{}: body {}: {}", path.display(), i, err)))
+        .collect::<Result<_, _>>()?;
+    Ok(SavedState { steps: save.steps, time: save.time, gravity_multiplier: save.gravity_multiplier, bodies })
+}
+
+/// Writes the bodies to stdout every `stride` steps, one JSON object per line, for another
+/// process to follow the run live
+pub struct FrameStream {
+    out: StdoutLock<'static>,
+    stride: u64,
+}
+
+#[derive(Serialize)]
+struct Frame<'a> {
+    step: u64,
+    time: f32,
+    bodies: &'a [StreamedBody],
+}
+
+/// A body file record with the body's id, so consumers can follow bodies across frames
+#[derive(Serialize)]
+struct StreamedBody {
+    id: u64,
+    #[serde(flatten)]
+    record: BodyRecord,
+}
+
+impl FrameStream {
+    pub fn new(stride: u64) -> Self {
+        FrameStream { out: io::stdout().lock(), stride }
+    }
+
+    /// Write a frame if `step` falls on the stride, flushing it right away. Fails once the
+    /// consumer has gone away.
+    pub fn record(&mut self, step: u64, time: f32, bodies: &[Body]) -> io::Result<()> {
+        if !step.is_multiple_of(self.stride) {
+            return Ok(());
+        }
+        let bodies: Vec<StreamedBody> =
+            bodies.iter().map(|body| StreamedBody { id: body.id, record: BodyRecord::from_body(body) }).collect();
+        serde_json::to_writer(&mut self.out, &Frame { step, time, bodies: &bodies })?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()
+    }
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|err| format!("couldn't read {}: {}", path.display(), err))
+}
+
+fn extension(path: &Path) -> Option<&str> {
+    path.extension().and_then(|extension| extension.to_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("nbody-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn saves_read_back_unchanged() {
+        let bodies = vec![
+            Body::central(),
+            Body::new(Vec2::new(120.5, -33.25), Vec2::new(-4.0, 17.5), 2.5),
+            Body { species: 1, color: 0x40a0ff, custom_color: true, ..Body::new(Vec2::new(-900.0, 410.0), Vec2::new(0.125, 0.0), 7.0) },
+            Body { is_static: true, ..Body::new(Vec2::new(5.0, 5.0), Vec2::ZERO, 40.0) },
+        ];
+        let state = SavedState { steps: 12345, time: 98.765, gravity_multiplier: 1.5, bodies };
+        let path = temp_path("save");
+        write_save(&path, &state).unwrap();
+        let loaded = read_save(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!((loaded.steps, loaded.time, loaded.gravity_multiplier), (state.steps, state.time, state.gravity_multiplier));
+        assert_eq!(loaded.bodies.len(), state.bodies.len());
+        for (body, saved) in loaded.bodies.iter().zip(&state.bodies) {
+            assert_eq!((body.pos, body.vel, body.mass), (saved.pos, saved.vel, saved.mass));
+            assert_eq!((body.is_static, body.central, body.species), (saved.is_static, saved.central, saved.species));
+            assert_eq!((body.color, body.custom_color), (saved.color, saved.custom_color));
+        }
+    }
+
+    #[test]
+    fn saves_from_a_newer_version_are_rejected() {
+        let path = temp_path("future-save");
+        let text = format!(r#"{{"version": {}, "steps": 1, "time": 0.5, "gravity_multiplier": 1.0, "bodies": [], "spin": 3}}"#, SAVE_VERSION + 1);
+        std::fs::write(&path, text).unwrap();
+        let loaded = read_save(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let err = loaded.err().expect("a save from a newer version loaded");
+        assert!(err.contains(&format!("version {}", SAVE_VERSION + 1)), "unexpected error: {}", err);
+    }
+}
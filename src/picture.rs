@@ -0,0 +1,131 @@
+use std::path::Path;
+
+/// A grayscale image, brightness from 0 (black) to 1 (white) row by row from the top
+pub struct Picture {
+    pub width: usize,
+    pub height: usize,
+    pub brightness: Vec<f32>,
+}
+
+impl Picture {
+    /// Brightness at `(x, y)`, with both coordinates from 0 to 1 across the image
+    pub fn sample(&self, x: f32, y: f32) -> f32 {
+        let column = ((x * self.width as f32) as usize).min(self.width - 1);
+        let row = ((y * self.height as f32) as usize).min(self.height - 1);
+        self.brightness[row * self.width + column]
+    }
+}
+
+/// Read a Netpbm image: PGM (`P2`/`P5`) or PPM (`P3`/`P6`), plain or binary, which most
+/// image tools can export (e.g. `convert photo.png photo.pgm`). Colors are reduced to
+/// their luma.
+pub fn read_picture(path: &Path) -> Result<Picture, String> {
+    let data = std::fs::read(path).map_err(|err| format!("couldn't read {}: {}", path.display(), err))?;
+    parse(&data).map_err(|err| format!("couldn't read {}: {}", path.display(), err))
+}
+
+fn parse(data: &[u8]) -> Result<Picture, String> {
+    let (channels, binary) = match data.get(..2) {
+        Some(b"P2") => (1, false),
+        Some(b"P5") => (1, true),
+        Some(b"P3") => (3, false),
+        Some(b"P6") => (3, true),
+        _ => return Err("not a PGM or PPM image (only the P2, P3, P5 and P6 formats are supported)".to_string()),
+    };
+    let mut header = Tokens { data, pos: 2 };
+    let width = header.number()?;
+    let height = header.number()?;
+    let max = header.number()?;
+    if width == 0 || height == 0 {
+        return Err("the image is empty".to_string());
+    }
+    if max == 0 || max > u16::MAX as usize {
+        return Err(format!("unsupported maximum value {}", max));
+    }
+
+    // The header can claim any size, so don't let it overflow
+    let count = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(channels))
+        .ok_or("the image is too large")?;
+    let values: Vec<usize> = if binary {
+        // Exactly one whitespace byte separates the header from the raster
+        let raster = data.get(header.pos + 1..).unwrap_or_default();
+        let bytes = if max > u8::MAX as usize { 2 } else { 1 };
+        if raster.len() / bytes < count {
+            return Err("the pixel data is cut short".to_string());
+        }
+        raster
+            .chunks_exact(bytes)
+            .take(count)
+            .map(|value| value.iter().fold(0, |total, &byte| total << 8 | byte as usize))
+            .collect()
+    } else {
+        (0..count).map(|_| header.number()).collect::<Result<_, _>>()?
+    };
+
+    let brightness = values
+        .chunks_exact(channels)
+        .map(|pixel| {
+            let luma = match *pixel {
+                [r, g, b] => 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32,
+                _ => pixel[0] as f32,
+            };
+            (luma / max as f32).min(1.0)
+        })
+        .collect();
+    Ok(Picture { width, height, brightness })
+}
+
+/// Whitespace-separated numbers in a Netpbm header, skipping `#` comments
+struct Tokens<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Tokens<'_> {
+    fn number(&mut self) -> Result<usize, String> {
+        loop {
+            match self.data.get(self.pos) {
+                Some(byte) if byte.is_ascii_whitespace() => self.pos += 1,
+                Some(b'#') => {
+                    while self.data.get(self.pos).is_some_and(|&byte| byte != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+        let start = self.pos;
+        while self.data.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.data[start..self.pos])
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or("the header or pixel values are malformed".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_headers_are_rejected() {
+        let huge = usize::MAX / 2;
+        for header in [format!("P6 {} 4 255\n", huge), format!("P5 {} {} 255\n", huge, huge), format!("P3 {} 1 255\n", usize::MAX)] {
+            assert!(parse(header.as_bytes()).is_err(), "{}", header.trim());
+        }
+        // A header that fits but promises more pixels than there are
+        assert_eq!(parse(format!("P5 {} 1 255\n\0\0", huge).as_bytes()).err().as_deref(), Some("the pixel data is cut short"));
+    }
+
+    #[test]
+    fn small_images_read_as_luma() {
+        let picture = parse(b"P6 2 1 255\n\xff\xff\xff\x00\x00\xff").unwrap();
+        assert_eq!((picture.width, picture.height), (2, 1));
+        assert!((picture.brightness[0] - 1.0).abs() < 1e-6);
+        assert!((picture.brightness[1] - 0.114).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,354 @@
+use crate::{Body, Gravity};
+use crate::parallel::prelude::*;
+use glam::Vec2;
+use std::ops::Add;
+
+/// Deepest `TreeLimits::max_depth` allowed. The build recurses once per level, so this also
+/// bounds its stack use, and f32 positions can't be told apart in cells this small anyway.
+pub const DEPTH_LIMIT: usize = 64;
+/// Cells with at least this many bodies are split up on several threads
+pub const PARALLEL_BUILD_MIN: usize = 20_000;
+/// Levels of cells whose children are built in parallel, for up to 4^depth subtrees at once
+const PARALLEL_DEPTH: usize = 3;
+
+/// How far the cells are split
+#[derive(Clone, Copy)]
+pub struct TreeLimits {
+    /// Cells this deep are split no further, so coincident bodies can't recurse forever.
+    /// Their bodies share a leaf and are summed directly.
+    pub max_depth: usize,
+    /// Cells with at most this many bodies stay leaves. Bigger buckets make for smaller trees
+    /// with more direct summation; a bucket far enough away still pulls as a single body.
+    pub bucket_size: usize,
+}
+
+impl Default for TreeLimits {
+    fn default() -> Self {
+        TreeLimits { max_depth: 32, bucket_size: 1 }
+    }
+}
+
+/// Barnes-Hut quadtree: every cell knows the total mass and center of mass of the bodies
+/// inside it, so a distant cell can pull as a single body instead of body by body
+pub struct QuadTree {
+    nodes: Vec<Node>,
+    /// Body indices, ordered so that the bodies of every cell are contiguous
+    order: Vec<usize>,
+    /// Depth of the deepest cell, the root being 0
+    max_depth: usize,
+}
+
+/// Shape of a built tree. Deep trees and crowded leaves point at tight clusters, which make
+/// the tree walk expensive.
+#[derive(Clone, Copy, Default)]
+pub struct TreeStats {
+    pub nodes: usize,
+    /// Cells holding bodies that aren't split any further
+    pub leaves: usize,
+    pub max_depth: usize,
+    /// Most bodies sharing a leaf, more than the bucket size only at the depth limit
+    pub max_leaf_bodies: usize,
+}
+
+/// Number of pulls summed up in a force calculation
+#[derive(Clone, Copy, Default)]
+pub struct Interactions {
+    /// Body-body pairs
+    pub direct: u64,
+    /// Whole cells standing in for the bodies inside them
+    pub approximated: u64,
+}
+
+impl Add for Interactions {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Interactions {
+            direct: self.direct + other.direct,
+            approximated: self.approximated + other.approximated,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    center: Vec2,
+    /// Half the side length of the square cell
+    half_size: f32,
+    mass: f32,
+    com: Vec2,
+    /// The cell's bodies, as a range of `order`
+    start: usize,
+    end: usize,
+    /// Index of the first of the four children in `nodes`, if the cell is split
+    children: Option<usize>,
+}
+
+impl QuadTree {
+    /// Build the tree for `bodies`, on several threads once there are enough of them to pay off
+    pub fn new(bodies: &[Body], limits: TreeLimits) -> Self {
+        QuadTree::build(bodies, limits, bodies.len() >= PARALLEL_BUILD_MIN && crate::parallel::current_num_threads() > 1)
+    }
+
+    /// Build the tree on the calling thread only, or with the first few levels of cells split
+    /// up and their subtrees built in parallel. Both give the same tree.
+    pub fn build(bodies: &[Body], limits: TreeLimits, parallel: bool) -> Self {
+        let (min, max) = bodies.iter().fold((Vec2::splat(f32::INFINITY), Vec2::splat(f32::NEG_INFINITY)), |(min, max), body| {
+            (min.min(body.pos), max.max(body.pos))
+        });
+        let (center, half_size) = if bodies.is_empty() {
+            (Vec2::ZERO, 1.0)
+        } else {
+            // Pad the root a little so bodies on the far edge still fall inside it
+            ((min + max) / 2.0, ((max - min).max_element() / 2.0).max(1.0) * 1.001)
+        };
+
+        let mut nodes = vec![Node { center, half_size, mass: 0.0, com: Vec2::ZERO, start: 0, end: bodies.len(), children: None }];
+        let mut order: Vec<usize> = (0..bodies.len()).collect();
+        let max_depth = if parallel {
+            build_parallel(&mut nodes, 0, &mut order, bodies, limits, 0)
+        } else {
+            build(&mut nodes, 0, &mut order, bodies, limits, 0)
+        };
+        QuadTree { nodes, order, max_depth }
+    }
+
+    pub fn stats(&self) -> TreeStats {
+        let leaves = self.nodes.iter().filter(|node| node.children.is_none() && node.end > node.start);
+        TreeStats {
+            nodes: self.nodes.len(),
+            leaves: leaves.clone().count(),
+            max_depth: self.max_depth,
+            max_leaf_bodies: leaves.map(|node| node.end - node.start).max().unwrap_or(0),
+        }
+    }
+
+    /// Force on `bodies[index]`, treating every cell smaller than `theta` times its distance
+    /// as a single body, including leaves with several bodies, unless `bodies[index]` is inside
+    /// it. Bodies must be the ones the tree was built from. A `theta` of 0 opens every cell,
+    /// which reduces to direct summation.
+    pub fn force(&self, bodies: &[Body], index: usize, gravity: Gravity, theta: f32) -> (Vec2, Interactions) {
+        let body = &bodies[index];
+        let mut force = Vec2::ZERO;
+        let mut interactions = Interactions::default();
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if node.mass == 0.0 {
+                continue;
+            }
+            // A body's own cell is always opened, even when large theta would accept it, so the
+            // body never pulls on itself through the cell's center of mass
+            let far = 2.0 * node.half_size < theta * body.pos.distance(node.com)
+                && (body.pos - node.center).abs().max_element() > node.half_size;
+            match node.children {
+                Some(first) if !far => {
+                    stack.extend(first..first + 4);
+                }
+                Some(_) => {
+                    force += gravity.force(body.pos, body.mass, node.com, node.mass);
+                    interactions.approximated += 1;
+                }
+                None if far && node.end - node.start > 1 => {
+                    force += gravity.force(body.pos, body.mass, node.com, node.mass);
+                    interactions.approximated += 1;
+                }
+                None => {
+                    for &other in self.order[node.start..node.end].iter().filter(|&&other| other != index) {
+                        force += gravity.force(body.pos, body.mass, bodies[other].pos, bodies[other].mass);
+                        interactions.direct += 1;
+                    }
+                }
+            }
+        }
+        (force, interactions)
+    }
+}
+
+/// Sum up the mass of the cell `nodes[index]`, whose bodies are `members` (its range of the
+/// tree's `order`), and split it into four children unless it's a leaf. Returns whether it split.
+fn split(nodes: &mut Vec<Node>, index: usize, members: &mut [usize], bodies: &[Body], limits: TreeLimits, depth: usize) -> bool {
+    let Node { center, half_size, start, .. } = nodes[index];
+    let mass: f32 = members.iter().map(|&i| bodies[i].mass).sum();
+    if mass > 0.0 {
+        nodes[index].mass = mass;
+        nodes[index].com = members.iter().map(|&i| bodies[i].pos * bodies[i].mass).sum::<Vec2>() / mass;
+    }
+    if members.len() <= limits.bucket_size || depth >= limits.max_depth {
+        return false;
+    }
+
+    // Children in the order bottom-left, bottom-right, top-left, top-right (in world y)
+    let quadrant = |pos: Vec2| (pos.x >= center.x) as usize + 2 * (pos.y >= center.y) as usize;
+    if members.len() >= PARALLEL_BUILD_MIN {
+        members.par_sort_unstable_by_key(|&i| quadrant(bodies[i].pos));
+    } else {
+        members.sort_unstable_by_key(|&i| quadrant(bodies[i].pos));
+    }
+
+    let first = nodes.len();
+    let mut child_start = 0;
+    for q in 0..4 {
+        let count = members[child_start..].iter().take_while(|&&i| quadrant(bodies[i].pos) == q).count();
+        let offset = Vec2::new(if q & 1 == 1 { 0.5 } else { -0.5 }, if q & 2 == 2 { 0.5 } else { -0.5 });
+        nodes.push(Node {
+            center: center + offset * half_size,
+            half_size: half_size / 2.0,
+            mass: 0.0,
+            com: Vec2::ZERO,
+            start: start + child_start,
+            end: start + child_start + count,
+            children: None,
+        });
+        child_start += count;
+    }
+    nodes[index].children = Some(first);
+    true
+}
+
+/// The bodies of each of the four children of a split cell, in order
+fn child_members<'a>(nodes: &[Node], first: usize, members: &'a mut [usize]) -> Vec<&'a mut [usize]> {
+    let mut rest = members;
+    (first..first + 4)
+        .map(|child| {
+            let (head, tail) = std::mem::take(&mut rest).split_at_mut(nodes[child].end - nodes[child].start);
+            rest = tail;
+            head
+        })
+        .collect()
+}
+
+/// Split a cell and its children until each cell fits in a bucket. Returns the depth of the
+/// deepest cell.
+fn build(nodes: &mut Vec<Node>, index: usize, members: &mut [usize], bodies: &[Body], limits: TreeLimits, depth: usize) -> usize {
+    if !split(nodes, index, members, bodies, limits, depth) {
+        return depth;
+    }
+    let first = nodes[index].children.unwrap_or_default();
+    let mut deepest = depth;
+    for (child, members) in (first..first + 4).zip(child_members(nodes, first, members)) {
+        deepest = deepest.max(build(nodes, child, members, bodies, limits, depth + 1));
+    }
+    deepest
+}
+
+/// Like `build`, but down to `PARALLEL_DEPTH` the four children are built at the same time,
+/// each into a list of nodes of its own that is then appended to `nodes`
+fn build_parallel(nodes: &mut Vec<Node>, index: usize, members: &mut [usize], bodies: &[Body], limits: TreeLimits, depth: usize) -> usize {
+    if depth == PARALLEL_DEPTH || members.len() < PARALLEL_BUILD_MIN {
+        return build(nodes, index, members, bodies, limits, depth);
+    }
+    if !split(nodes, index, members, bodies, limits, depth) {
+        return depth;
+    }
+    let first = nodes[index].children.unwrap_or_default();
+    let subtrees: Vec<(Vec<Node>, usize)> = child_members(nodes, first, members)
+        .into_par_iter()
+        .zip(nodes[first..first + 4].par_iter())
+        .map(|(members, &child)| {
+            let mut subtree = vec![child];
+            let deepest = build_parallel(&mut subtree, 0, members, bodies, limits, depth + 1);
+            (subtree, deepest)
+        })
+        .collect();
+
+    // Each subtree has its root (the child) first and the rest after it, with indices local to
+    // the subtree: the root takes its place among the children and the rest joins the end
+    let mut deepest = depth;
+    for (q, (subtree, subtree_depth)) in subtrees.into_iter().enumerate() {
+        let base = nodes.len();
+        let mut relocated = subtree.into_iter().map(|node| Node { children: node.children.map(|child| base + child - 1), ..node });
+        if let Some(root) = relocated.next() {
+            nodes[first + q] = root;
+        }
+        nodes.extend(relocated);
+        deepest = deepest.max(subtree_depth);
+    }
+    deepest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// `count` bodies of assorted masses scattered over a square
+    fn cloud(count: usize, seed: u64) -> Vec<Body> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..count)
+            .map(|_| Body::new(Vec2::new(rng.gen_range(-1000.0..1000.0), rng.gen_range(-1000.0..1000.0)), Vec2::ZERO, rng.gen_range(1.0..80.0)))
+            .collect()
+    }
+
+    fn direct_force(bodies: &[Body], index: usize, gravity: Gravity) -> Vec2 {
+        let body = &bodies[index];
+        (0..bodies.len())
+            .filter(|&other| other != index)
+            .map(|other| gravity.force(body.pos, body.mass, bodies[other].pos, bodies[other].mass))
+            .sum()
+    }
+
+    /// Root mean square error of the tree forces relative to the size of the direct ones
+    fn force_error(tree: &QuadTree, bodies: &[Body], direct: &[Vec2], gravity: Gravity, theta: f32) -> f32 {
+        let (error, scale) = direct.iter().enumerate().fold((0.0, 0.0), |(error, scale), (i, &exact)| {
+            let approximate = tree.force(bodies, i, gravity, theta).0;
+            (error + (approximate - exact).length_squared(), scale + exact.length_squared())
+        });
+        (error / scale).sqrt()
+    }
+
+    #[test]
+    fn force_error_shrinks_with_theta() {
+        let bodies = cloud(2000, 1);
+        let gravity = Config::default().gravity(1.0);
+        let direct: Vec<Vec2> = (0..bodies.len()).map(|i| direct_force(&bodies, i, gravity)).collect();
+        let tree = QuadTree::new(&bodies, TreeLimits::default());
+
+        let errors: Vec<f32> = [1.0, 0.75, 0.5, 0.25, 0.1].iter().map(|&theta| force_error(&tree, &bodies, &direct, gravity, theta)).collect();
+        for pair in errors.windows(2) {
+            assert!(pair[1] < pair[0], "error grew as theta shrank: {:?}", errors);
+        }
+        assert!(errors[0] < 0.05, "theta = 1 is far off: {:?}", errors);
+        // Opening every cell sums the same pairs, only in another order
+        let exact = force_error(&tree, &bodies, &direct, gravity, 0.0);
+        assert!(exact < 1e-5, "theta = 0 differs from direct summation by {}", exact);
+    }
+
+    #[test]
+    fn coincident_bodies_stop_at_the_depth_limit() {
+        // Far more bodies than distinct f32 positions in so small a patch, so most coincide
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut bodies: Vec<Body> =
+            (0..50_000).map(|_| Body::new(Vec2::new(100.0 + rng.gen_range(0.0..1e-3), -50.0), Vec2::ZERO, 1.0)).collect();
+        bodies.extend(cloud(500, 3));
+        let gravity = Config::default().gravity(1.0);
+        for bucket_size in [1, 16] {
+            let limits = TreeLimits { max_depth: DEPTH_LIMIT, bucket_size };
+            for parallel in [false, true] {
+                let tree = QuadTree::build(&bodies, limits, parallel);
+                let stats = tree.stats();
+                assert_eq!(stats.max_depth, DEPTH_LIMIT);
+                assert!(stats.max_leaf_bodies > bucket_size, "the coincident bodies should share a leaf");
+                for i in [0, 49_999, 50_000, bodies.len() - 1] {
+                    assert!(tree.force(&bodies, i, gravity, 0.5).0.is_finite());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_build_matches_serial() {
+        // Enough bodies for the big cells to be sorted and split up in parallel
+        let bodies = cloud(2 * PARALLEL_BUILD_MIN, 4);
+        let gravity = Config::default().gravity(1.0);
+        let (serial, parallel) = (QuadTree::build(&bodies, TreeLimits::default(), false), QuadTree::build(&bodies, TreeLimits::default(), true));
+        let (a, b) = (serial.stats(), parallel.stats());
+        assert_eq!((a.nodes, a.leaves, a.max_depth, a.max_leaf_bodies), (b.nodes, b.leaves, b.max_depth, b.max_leaf_bodies));
+        for i in (0..bodies.len()).step_by(97) {
+            assert_eq!(serial.force(&bodies, i, gravity, 0.5).0, parallel.force(&bodies, i, gravity, 0.5).0);
+        }
+    }
+}
+
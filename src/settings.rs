@@ -0,0 +1,56 @@
+use std::path::Path;
+use toml::{Table, Value};
+
+/// Read a TOML config file as the command-line options it stands for, in the order they
+/// appear: `key = value` becomes `--key value`, `key = true` the bare flag `--key` (false
+/// leaves it out), and an array the comma-separated list, like `ring = [300, 900]`.
+/// A `[keys]` table rebinds the controls, one `--bind action=keys` per entry.
+pub fn read_settings(path: &Path) -> Result<Vec<String>, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("couldn't read {}: {}", path.display(), err))?;
+    let table: Table = toml::from_str(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let flag = format!("--{}", key);
+        let value = match value {
+            Value::Table(bindings) if key == "keys" => {
+                for (action, keys) in bindings {
+                    let keys = match keys {
+                        Value::Array(items) => items.iter().map(scalar).collect::<Option<Vec<_>>>().map(|items| items.join(",")),
+                        keys => scalar(&keys),
+                    };
+                    let keys = keys.ok_or(format!("{}: keys.{} expects a key name or a list of them", path.display(), action))?;
+                    args.push("--bind".to_string());
+                    args.push(format!("{}={}", action, keys));
+                }
+                continue;
+            }
+            Value::Boolean(true) => {
+                args.push(flag);
+                continue;
+            }
+            Value::Boolean(false) => continue,
+            Value::Array(items) => items.iter().map(scalar).collect::<Option<Vec<_>>>().map(|items| items.join(",")),
+            value => scalar(&value),
+        };
+        let value = value.ok_or(format!("{}: {} expects a number, a string or a list of them", path.display(), key))?;
+        args.push(flag);
+        args.push(value);
+    }
+    Ok(args)
+}
+
+/// Write settings in the form `read_settings` reads back
+pub fn write_settings(path: &Path, settings: &Table) -> Result<(), String> {
+    let text = toml::to_string(settings).map_err(|err| err.to_string())?;
+    std::fs::write(path, text).map_err(|err| format!("couldn't write {}: {}", path.display(), err))
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Integer(number) => Some(number.to_string()),
+        Value::Float(number) => Some(number.to_string()),
+        _ => None,
+    }
+}